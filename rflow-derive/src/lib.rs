@@ -0,0 +1,281 @@
+//! Derive macros for [RFlow](https://crates.io/crates/rflow). Use the `derive` feature of the
+//! main crate instead of depending on this crate directly.
+#![deny(missing_docs)]
+
+use std::fmt::Write as _;
+
+use proc_macro::TokenStream;
+use proc_macro2::TokenStream as TokenStream2;
+use quote::quote;
+use syn::{parse_macro_input, Data, DeriveInput, Expr, Fields, Lit, LitStr, Meta};
+
+/// Derives `rflow::router::RflowCommand` for an enum
+///
+/// Variant attributes:
+///
+/// * `#[rflow(name = "...")]` - override the command name (default: the variant name in
+///   kebab-case)
+///
+/// * `#[rflow(help = "...")]` - override the command help (default: the variant doc comment)
+#[proc_macro_derive(RflowCommand, attributes(rflow))]
+pub fn derive_rflow_command(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    expand(&input)
+        .unwrap_or_else(syn::Error::into_compile_error)
+        .into()
+}
+
+fn expand(input: &DeriveInput) -> syn::Result<TokenStream2> {
+    let Data::Enum(data) = &input.data else {
+        return Err(syn::Error::new_spanned(
+            input,
+            "RflowCommand can be derived for enums only",
+        ));
+    };
+    let ident = &input.ident;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+    let mut names: Vec<String> = Vec::with_capacity(data.variants.len());
+    let mut arms = Vec::with_capacity(data.variants.len());
+    let mut infos = Vec::with_capacity(data.variants.len());
+    for variant in &data.variants {
+        let attrs = VariantAttrs::parse(&variant.attrs)?;
+        let name = attrs
+            .name
+            .unwrap_or_else(|| to_kebab_case(&variant.ident.to_string()));
+        if names.contains(&name) {
+            return Err(syn::Error::new_spanned(
+                variant,
+                format!("duplicate command name: {}", name),
+            ));
+        }
+        let help = attrs.help.unwrap_or_default();
+        let mut usage = name.clone();
+        let mut specs = Vec::with_capacity(variant.fields.len());
+        let mut values = Vec::with_capacity(variant.fields.len());
+        let mut optional_seen = false;
+        for (pos, field) in variant.fields.iter().enumerate() {
+            let arg_name = field.ident.as_ref().map_or_else(
+                || format!("arg{}", pos + 1),
+                |i| i.to_string().trim_start_matches("r#").to_owned(),
+            );
+            let optional = is_option(&field.ty);
+            if optional {
+                optional_seen = true;
+                write!(usage, " [{}]", arg_name).ok();
+            } else if optional_seen {
+                return Err(syn::Error::new_spanned(
+                    field,
+                    "required arguments must be placed before optional ones",
+                ));
+            } else {
+                write!(usage, " <{}>", arg_name).ok();
+            }
+            let required = !optional;
+            specs.push(quote! { (#arg_name, #required) });
+            let parse_fn = if optional {
+                quote! { ::rflow::router::__private::parse_optional_arg }
+            } else {
+                quote! { ::rflow::router::__private::parse_arg }
+            };
+            let value = quote! { #parse_fn(&__args, #pos, #arg_name)? };
+            values.push(if let Some(field_ident) = &field.ident {
+                quote! { #field_ident: #value }
+            } else {
+                value
+            });
+        }
+        let variant_ident = &variant.ident;
+        let construct = match &variant.fields {
+            Fields::Named(_) => quote! { Self::#variant_ident { #(#values),* } },
+            Fields::Unnamed(_) => quote! { Self::#variant_ident(#(#values),*) },
+            Fields::Unit => quote! { Self::#variant_ident },
+        };
+        arms.push(quote! {
+            #name => {
+                ::rflow::router::__private::check_args(&__args, &[#(#specs),*])?;
+                ::std::result::Result::Ok(#construct)
+            }
+        });
+        infos.push(quote! {
+            ::rflow::router::CommandInfo {
+                name: #name,
+                usage: #usage,
+                help: #help,
+            }
+        });
+        names.push(name);
+    }
+    Ok(quote! {
+        impl #impl_generics ::rflow::router::RflowCommand for #ident #ty_generics #where_clause {
+            fn parse(line: &str) -> ::std::result::Result<Self, ::rflow::router::CommandError> {
+                let mut __sp = line.split_whitespace();
+                let __name = __sp.next().unwrap_or_default();
+                let __args: ::std::vec::Vec<&str> = __sp.collect();
+                match __name {
+                    #(#arms)*
+                    _ => ::std::result::Result::Err(
+                        ::rflow::router::CommandError::Unknown(__name.to_owned())
+                    ),
+                }
+            }
+            fn commands() -> &'static [::rflow::router::CommandInfo] {
+                const COMMANDS: &[::rflow::router::CommandInfo] = &[#(#infos),*];
+                COMMANDS
+            }
+        }
+    })
+}
+
+#[derive(Default)]
+struct VariantAttrs {
+    name: Option<String>,
+    help: Option<String>,
+}
+
+impl VariantAttrs {
+    fn parse(attrs: &[syn::Attribute]) -> syn::Result<Self> {
+        let mut result = Self::default();
+        let mut doc: Vec<String> = Vec::new();
+        for attr in attrs {
+            if attr.path().is_ident("rflow") {
+                attr.parse_nested_meta(|meta| {
+                    if meta.path.is_ident("name") {
+                        result.name = Some(meta.value()?.parse::<LitStr>()?.value());
+                    } else if meta.path.is_ident("help") {
+                        result.help = Some(meta.value()?.parse::<LitStr>()?.value());
+                    } else {
+                        return Err(meta.error("unsupported rflow attribute"));
+                    }
+                    Ok(())
+                })?;
+            } else if attr.path().is_ident("doc") {
+                if let Meta::NameValue(nv) = &attr.meta {
+                    if let Expr::Lit(expr) = &nv.value {
+                        if let Lit::Str(s) = &expr.lit {
+                            let line = s.value();
+                            let line = line.trim();
+                            if !line.is_empty() {
+                                doc.push(line.to_owned());
+                            }
+                        }
+                    }
+                }
+            }
+        }
+        if result.help.is_none() && !doc.is_empty() {
+            result.help = Some(doc.join(" "));
+        }
+        Ok(result)
+    }
+}
+
+fn is_option(ty: &syn::Type) -> bool {
+    if let syn::Type::Path(p) = ty {
+        if p.qself.is_none() {
+            if let Some(segment) = p.path.segments.last() {
+                return segment.ident == "Option";
+            }
+        }
+    }
+    false
+}
+
+fn to_kebab_case(s: &str) -> String {
+    let chars: Vec<char> = s.chars().collect();
+    let mut result = String::with_capacity(s.len() + 4);
+    for (i, c) in chars.iter().enumerate() {
+        if c.is_uppercase() && i > 0 {
+            let prev = chars[i - 1];
+            let next_lower = chars.get(i + 1).map_or(false, |n| n.is_lowercase());
+            if prev.is_lowercase() || prev.is_ascii_digit() || (prev.is_uppercase() && next_lower) {
+                result.push('-');
+            }
+        }
+        result.extend(c.to_lowercase());
+    }
+    result
+}
+
+#[cfg(test)]
+mod test {
+    use syn::parse_quote;
+
+    use super::{expand, to_kebab_case};
+
+    fn expand_err(input: &syn::DeriveInput) -> String {
+        expand(input).unwrap_err().to_string()
+    }
+
+    #[test]
+    fn kebab_case() {
+        assert_eq!(to_kebab_case("Start"), "start");
+        assert_eq!(to_kebab_case("SetSpeed"), "set-speed");
+        assert_eq!(to_kebab_case("ReadIOPort"), "read-io-port");
+        assert_eq!(to_kebab_case("Motor2Start"), "motor2-start");
+    }
+
+    #[test]
+    fn expansion() {
+        let input: syn::DeriveInput = parse_quote! {
+            enum Cmd {
+                /// start the motor
+                Start,
+                #[rflow(name = "speed", help = "set the speed")]
+                SetSpeed { rpm: u32, ramp: Option<u32> },
+                Move(i32, Option<i32>),
+            }
+        };
+        let expanded = expand(&input).unwrap().to_string();
+        for expected in [
+            r#"name : "start" , usage : "start" , help : "start the motor""#,
+            r#"name : "speed" , usage : "speed <rpm> [ramp]" , help : "set the speed""#,
+            r#"name : "move" , usage : "move <arg1> [arg2]" , help : """#,
+            r#"check_args (& __args , & [("rpm" , true) , ("ramp" , false)])"#,
+        ] {
+            assert!(expanded.contains(expected), "{} not found", expected);
+        }
+    }
+
+    #[test]
+    fn required_after_optional() {
+        let input = parse_quote! {
+            enum Cmd {
+                SetSpeed { ramp: Option<u32>, rpm: u32 },
+            }
+        };
+        assert_eq!(
+            expand_err(&input),
+            "required arguments must be placed before optional ones"
+        );
+    }
+
+    #[test]
+    fn duplicate_name() {
+        let input = parse_quote! {
+            enum Cmd {
+                Start,
+                #[rflow(name = "start")]
+                Run,
+            }
+        };
+        assert_eq!(expand_err(&input), "duplicate command name: start");
+    }
+
+    #[test]
+    fn invalid_input() {
+        let input = parse_quote! {
+            struct Cmd;
+        };
+        assert_eq!(
+            expand_err(&input),
+            "RflowCommand can be derived for enums only"
+        );
+        let input = parse_quote! {
+            enum Cmd {
+                #[rflow(alias = "go")]
+                Start,
+            }
+        };
+        assert_eq!(expand_err(&input), "unsupported rflow attribute");
+    }
+}
@@ -0,0 +1,212 @@
+use std::{fmt, str::FromStr, sync::Arc};
+
+use crate::Error;
+
+pub(crate) const AUTH_OK: &str = "+OK";
+pub(crate) const AUTH_ERR: &str = "-ERR";
+pub(crate) const AUTH_FAILED_MESSAGE: &str = "authentication failed";
+pub(crate) const PERMISSION_DENIED_MESSAGE: &str = "permission denied";
+
+/// Client role. Roles are ordered by their privileges
+#[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug)]
+pub enum Role {
+    /// Receives broadcasts but can not send messages
+    Observer,
+    /// Can send messages
+    Operator,
+    /// Can send messages, has access to administrative commands
+    Admin,
+}
+
+impl Role {
+    /// Get role as string
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Observer => "observer",
+            Self::Operator => "operator",
+            Self::Admin => "admin",
+        }
+    }
+}
+
+impl fmt::Display for Role {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for Role {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "observer" => Ok(Self::Observer),
+            "operator" => Ok(Self::Operator),
+            "admin" => Ok(Self::Admin),
+            _ => Err(Error::InvalidData),
+        }
+    }
+}
+
+/// Authenticated client identity
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Identity {
+    user: String,
+    role: Role,
+}
+
+impl Identity {
+    /// Create a new identity
+    pub fn new(user: impl ToString, role: Role) -> Self {
+        Self {
+            user: user.to_string(),
+            role,
+        }
+    }
+    /// User name
+    #[inline]
+    pub fn user(&self) -> &str {
+        &self.user
+    }
+    /// User role
+    #[inline]
+    pub fn role(&self) -> Role {
+        self.role
+    }
+}
+
+/// Authentication method, advertised by the server in the `Auth` greeting header
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum AuthMethod {
+    /// The client sends `USER PASSWORD`
+    Password,
+    /// The client sends `TOKEN`
+    Token,
+}
+
+impl AuthMethod {
+    /// Get method as string
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Password => "password",
+            Self::Token => "token",
+        }
+    }
+}
+
+impl fmt::Display for AuthMethod {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for AuthMethod {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "password" => Ok(Self::Password),
+            "token" => Ok(Self::Token),
+            _ => Err(Error::InvalidData),
+        }
+    }
+}
+
+/// Client credentials
+#[derive(Clone)]
+pub enum Credentials {
+    /// User name and password
+    Password {
+        /// User name
+        user: String,
+        /// Password
+        password: String,
+    },
+    /// Access token
+    Token(String),
+}
+
+impl fmt::Debug for Credentials {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Password { user, .. } => f
+                .debug_struct("Password")
+                .field("user", user)
+                .finish_non_exhaustive(),
+            Self::Token(_) => f.write_str("Token(..)"),
+        }
+    }
+}
+
+impl Credentials {
+    /// Authentication method the credentials are sent with
+    pub fn method(&self) -> AuthMethod {
+        match self {
+            Self::Password { .. } => AuthMethod::Password,
+            Self::Token(_) => AuthMethod::Token,
+        }
+    }
+    /// Parses the credentials line, sent by a client
+    pub(crate) fn parse(method: AuthMethod, line: &str) -> Option<Self> {
+        match method {
+            AuthMethod::Password => {
+                let (user, password) = line.split_once(' ')?;
+                if user.is_empty() {
+                    return None;
+                }
+                Some(Self::Password {
+                    user: user.to_owned(),
+                    password: password.to_owned(),
+                })
+            }
+            AuthMethod::Token => {
+                if line.is_empty() {
+                    return None;
+                }
+                Some(Self::Token(line.to_owned()))
+            }
+        }
+    }
+    /// Formats the credentials line
+    pub(crate) fn to_line(&self) -> String {
+        match self {
+            Self::Password { user, password } => format!("{} {}\n", user, password),
+            Self::Token(token) => format!("{}\n", token),
+        }
+    }
+}
+
+type AuthFn = dyn Fn(&Credentials) -> Option<Identity> + Send + Sync;
+
+/// Server-side authenticator
+#[derive(Clone)]
+pub(crate) struct Authenticator {
+    pub(crate) method: AuthMethod,
+    f: Arc<AuthFn>,
+}
+
+impl Authenticator {
+    pub(crate) fn new<F>(method: AuthMethod, f: F) -> Self
+    where
+        F: Fn(&Credentials) -> Option<Identity> + Send + Sync + 'static,
+    {
+        Self {
+            method,
+            f: Arc::new(f),
+        }
+    }
+    /// Authenticates a credentials line
+    pub(crate) fn authenticate(&self, line: &str) -> Option<Identity> {
+        let credentials = Credentials::parse(self.method, line)?;
+        (self.f)(&credentials)
+    }
+}
+
+/// Parses the server authentication reply (`+OK ROLE` or `-ERR MESSAGE`)
+pub(crate) fn parse_auth_reply(line: &str) -> Result<Role, Error> {
+    if let Some(role) = line.strip_prefix(AUTH_OK) {
+        role.trim().parse()
+    } else if let Some(message) = line.strip_prefix(AUTH_ERR) {
+        Err(Error::AuthFailed(message.trim().to_owned()))
+    } else {
+        Err(Error::InvalidData)
+    }
+}
@@ -0,0 +1,105 @@
+use std::{
+    collections::BTreeMap,
+    sync::{atomic, mpsc},
+};
+
+use crate::{Direction, Mutex};
+
+const CALL_ID_PREFIX: char = '@';
+const CALL_ID_SEPARATOR: char = ':';
+
+/// Splits `@ID:DATA` into the call id and the data
+pub(crate) fn split_call_id(s: &str) -> Option<(u64, &str)> {
+    let (id, data) = s
+        .strip_prefix(CALL_ID_PREFIX)?
+        .split_once(CALL_ID_SEPARATOR)?;
+    if id.is_empty() || !id.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    Some((id.parse().ok()?, data))
+}
+
+pub(crate) fn format_call(id: u64, data: &str) -> String {
+    format!("{}{}{}{}", CALL_ID_PREFIX, id, CALL_ID_SEPARATOR, data)
+}
+
+pub(crate) trait ReplySender {
+    fn deliver(self, data: String);
+}
+
+impl ReplySender for mpsc::Sender<String> {
+    fn deliver(self, data: String) {
+        self.send(data).ok();
+    }
+}
+
+#[cfg(feature = "async")]
+impl ReplySender for tokio::sync::oneshot::Sender<String> {
+    fn deliver(self, data: String) {
+        self.send(data).ok();
+    }
+}
+
+/// Pending client calls
+pub(crate) struct Calls<T> {
+    id: atomic::AtomicU64,
+    // call ids have been negotiated, so replies are tagged
+    call_ids: bool,
+    pending: Mutex<BTreeMap<u64, T>>,
+    next_reply: Mutex<Option<T>>,
+}
+
+impl<T: ReplySender> Calls<T> {
+    pub(crate) fn new(call_ids: bool) -> Self {
+        Self {
+            id: atomic::AtomicU64::new(1),
+            call_ids,
+            pending: <_>::default(),
+            next_reply: Mutex::new(None),
+        }
+    }
+    /// Registers a call with an id, the reply must be tagged by the server
+    pub(crate) fn register(&self, reply_tx: T) -> u64 {
+        let id = self.id.fetch_add(1, atomic::Ordering::Relaxed);
+        self.pending.lock().insert(id, reply_tx);
+        id
+    }
+    pub(crate) fn unregister(&self, id: u64) {
+        self.pending.lock().remove(&id);
+    }
+    /// Registers a call which is replied with the next server message (if call ids have not been
+    /// negotiated). Calls of this kind must be serialized by the caller.
+    pub(crate) fn register_next(&self, reply_tx: T) {
+        *self.next_reply.lock() = Some(reply_tx);
+    }
+    pub(crate) fn unregister_next(&self) {
+        self.next_reply.lock().take();
+    }
+    /// Delivers the message to a pending call if matched, otherwise returns the message back
+    /// untouched. Tagged replies are matched only if call ids have been negotiated, so server
+    /// messages which look like tagged are not altered.
+    pub(crate) fn route(&self, direction: Direction, data: String) -> Option<String> {
+        if direction != Direction::ServerToClient {
+            return Some(data);
+        }
+        if self.call_ids {
+            if let Some((id, reply)) = split_call_id(&data) {
+                if let Some(reply_tx) = self.pending.lock().remove(&id) {
+                    reply_tx.deliver(reply.to_owned());
+                    return None;
+                }
+            }
+            return Some(data);
+        }
+        if let Some(reply_tx) = self.next_reply.lock().take() {
+            reply_tx.deliver(data);
+            return None;
+        }
+        Some(data)
+    }
+    /// Drops all pending calls, so the callers get disconnect errors
+    pub(crate) fn clear(&self) {
+        self.pending.lock().clear();
+        self.next_reply.lock().take();
+    }
+}
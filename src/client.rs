@@ -1,11 +1,15 @@
 use std::{
-    io::{BufRead, Write},
-    net::{Shutdown, TcpStream, ToSocketAddrs},
-    sync::{atomic, Arc},
+    collections::{BTreeMap, BTreeSet},
+    io::{self, BufRead, BufReader, Write},
+    net::{TcpStream, ToSocketAddrs},
+    sync::{atomic, mpsc, Arc},
     thread,
     time::Duration,
 };
 
+#[cfg(unix)]
+use std::{os::unix::net::UnixStream, path::Path};
+
 use crate::{Condvar, Mutex, RawMutex};
 use rtsc::{
     channel::{Receiver, Sender},
@@ -14,10 +18,21 @@ use rtsc::{
 use tracing::trace;
 
 use crate::{
-    Direction, Error, API_VERSION, DEFAULT_INCOMING_QUEUE_SIZE, DEFAULT_TIMEOUT, GREETING,
-    HEADERS_TRANSMISSION_END,
+    auth::parse_auth_reply,
+    call::{format_call, Calls},
+    protocol::{
+        format_hello, parse_capabilities_ack, Capability, Greeting, GreetingParser, MessageDecoder,
+        KEEPALIVE_MISSES, PONG,
+    },
+    stream::Stream,
+    AuthMethod, Credentials, Direction, Error, Role, Transport, API_VERSION,
+    DEFAULT_INCOMING_QUEUE_SIZE, DEFAULT_TIMEOUT, HEADER_AUTH, HEADER_HISTORY,
 };
 
+/// Capabilities, handled by the clients internally and always requested
+const CLIENT_CAPABILITIES: &[Capability] =
+    &[Capability::Block, Capability::Keepalive, Capability::CallId];
+
 /// Client instance
 #[derive(Clone)]
 pub struct Client {
@@ -25,8 +40,15 @@ pub struct Client {
 }
 
 struct Inner {
-    stream: Mutex<TcpStream>,
+    stream: Arc<Mutex<Stream>>,
     connected: Arc<atomic::AtomicBool>,
+    headers: BTreeMap<String, String>,
+    calls: Arc<Calls<mpsc::Sender<String>>>,
+    call_ids: bool,
+    call_lock: Mutex<()>,
+    role: Option<Role>,
+    capabilities: BTreeSet<Capability>,
+    history_len: usize,
 }
 
 /// Connection options
@@ -34,6 +56,10 @@ struct Inner {
 pub struct ConnectionOptions {
     pub(crate) timeout: Duration,
     pub(crate) incoming_queue_size: usize,
+    pub(crate) credentials: Option<Credentials>,
+    pub(crate) capabilities: BTreeSet<Capability>,
+    #[cfg(feature = "tls")]
+    pub(crate) tls: Option<(Arc<rustls::ClientConfig>, String)>,
 }
 
 impl Default for ConnectionOptions {
@@ -41,6 +67,10 @@ impl Default for ConnectionOptions {
         Self {
             timeout: DEFAULT_TIMEOUT,
             incoming_queue_size: DEFAULT_INCOMING_QUEUE_SIZE,
+            credentials: None,
+            capabilities: BTreeSet::new(),
+            #[cfg(feature = "tls")]
+            tls: None,
         }
     }
 }
@@ -63,6 +93,29 @@ impl ConnectionOptions {
         self.incoming_queue_size = size;
         self
     }
+    /// Set the credentials, sent if the server requires authentication
+    pub fn credentials(mut self, credentials: Credentials) -> Self {
+        self.credentials = Some(credentials);
+        self
+    }
+    /// Set additional capabilities, requested from servers which announce them. The
+    /// capabilities, handled by the client itself (multi-line blocks), are always requested. The
+    /// capabilities, which are not supported by the server, are not requested.
+    pub fn capabilities(mut self, capabilities: impl IntoIterator<Item = Capability>) -> Self {
+        self.capabilities = capabilities.into_iter().collect();
+        self
+    }
+    /// Connect with TLS (requires `tls` feature). The server name is used to verify the server
+    /// certificate. See [`crate::tls`] for the configuration helpers.
+    #[cfg(feature = "tls")]
+    pub fn tls(
+        mut self,
+        config: Arc<rustls::ClientConfig>,
+        server_name: impl Into<String>,
+    ) -> Self {
+        self.tls = Some((config, server_name.into()));
+        self
+    }
 }
 
 impl Client {
@@ -77,66 +130,158 @@ impl Client {
     ) -> Result<(Self, FrameReceiver), Error> {
         let timeout = options.timeout;
         let op = Operation::new(timeout);
-        let stream = TcpStream::connect_timeout(
+        let socket = TcpStream::connect_timeout(
             &addr
                 .to_socket_addrs()?
                 .next()
                 .ok_or(Error::InvalidAddress)?,
             timeout,
         )?;
-        stream.set_read_timeout(Some(timeout))?;
-        stream.set_write_timeout(Some(timeout))?;
-        stream.set_nodelay(true)?;
-        let reader = &mut std::io::BufReader::new(&stream);
-        let mut lines = reader.lines();
+        socket.set_read_timeout(Some(timeout))?;
+        socket.set_write_timeout(Some(timeout))?;
+        socket.set_nodelay(true)?;
+        #[cfg(feature = "tls")]
+        let stream: Stream = if let Some((config, server_name)) = &options.tls {
+            Box::new(crate::tls::TlsStream::connect(
+                socket,
+                config.clone(),
+                crate::tls::server_name(server_name)?,
+                op.remaining().map_err(|_| Error::Timeout)?,
+            )?)
+        } else {
+            Box::new(socket)
+        };
+        #[cfg(not(feature = "tls"))]
+        let stream: Stream = Box::new(socket);
+        Self::connect_stream(stream, options, &op)
+    }
+    /// Connect to a server via a Unix domain socket and create a client instance
+    #[cfg(unix)]
+    pub fn connect_unix(path: impl AsRef<Path>) -> Result<(Self, FrameReceiver), Error> {
+        Self::connect_unix_with_options(path, &ConnectionOptions::default())
+    }
+    /// Connect to a server via a Unix domain socket and create a client instance with the
+    /// defined options (TLS options are ignored)
+    #[cfg(unix)]
+    pub fn connect_unix_with_options(
+        path: impl AsRef<Path>,
+        options: &ConnectionOptions,
+    ) -> Result<(Self, FrameReceiver), Error> {
+        let op = Operation::new(options.timeout);
+        let socket = UnixStream::connect(path)?;
+        socket.set_read_timeout(Some(options.timeout))?;
+        socket.set_write_timeout(Some(options.timeout))?;
+        Self::connect_stream(Box::new(socket), options, &op)
+    }
+    /// Connect to a server over a custom transport and create a client instance with the defined
+    /// options (TLS options are ignored)
+    pub fn connect_transport(
+        transport: impl Transport,
+        options: &ConnectionOptions,
+    ) -> Result<(Self, FrameReceiver), Error> {
+        let op = Operation::new(options.timeout);
+        transport.set_read_timeout(Some(options.timeout))?;
+        transport.set_write_timeout(Some(options.timeout))?;
+        Self::connect_stream(Box::new(transport), options, &op)
+    }
+    fn connect_stream(
+        mut stream: Stream,
+        options: &ConnectionOptions,
+        op: &Operation,
+    ) -> Result<(Self, FrameReceiver), Error> {
+        // the reader is passed to the connection thread as-is, as it may already contain the
+        // data which follows the greeting
+        let mut reader = BufReader::new(stream.try_clone()?);
+        let mut lines = (&mut reader).lines();
         trace!("reading greeting");
-        let line = lines.next().ok_or(Error::InvalidData)??;
-        let mut sp = line.split('/');
-        if sp.next() != Some(GREETING) {
-            return Err(Error::InvalidData);
-        }
-        let api_version: u8 = sp
-            .next()
-            .ok_or_else(|| {
-                trace!("Unable to parse greetings header value");
-                Error::InvalidData
-            })?
-            .trim()
-            .parse()
-            .map_err(|error| {
-                trace!(%error, "Unable to parse greetings header value");
+        let mut parser = GreetingParser::new();
+        let greeting = loop {
+            let line = lines.next().ok_or_else(|| {
+                trace!("Invalid headers transmission end");
                 Error::InvalidData
-            })?;
+            })??;
+            if let Some(greeting) = parser.process_line(&line)? {
+                break greeting;
+            }
+            stream.set_read_timeout(Some(op.remaining().map_err(|_| Error::Timeout)?))?;
+        };
+        let api_version = greeting.api_version();
         if api_version != API_VERSION {
             return Err(Error::ApiVersion(api_version));
         }
-        trace!("reading headers");
-        let mut headers_end = false;
-        stream.set_read_timeout(Some(op.remaining().map_err(|_| Error::Timeout)?))?;
-        // headers are reserved for future use
-        for line in lines.by_ref() {
-            if line? == HEADERS_TRANSMISSION_END {
-                headers_end = true;
-                break;
-            }
-            stream.set_read_timeout(Some(op.remaining().map_err(|_| Error::Timeout)?))?;
+        let requested = requested_capabilities(options, &greeting);
+        let keepalive_interval = greeting.keepalive_interval();
+        let headers = greeting.into_headers();
+        if !requested.is_empty() {
+            trace!("sending hello");
+            stream.write_all(format_hello(&requested).as_bytes())?;
         }
-        if !headers_end {
-            trace!("Invalid headers transmission end");
-            return Err(Error::InvalidData);
+        let auth_method = headers.get(HEADER_AUTH);
+        if let Some(method) = auth_method {
+            let credentials = auth_credentials(method, options.credentials.as_ref())?;
+            trace!(method, "authenticating");
+            stream.write_all(credentials.to_line().as_bytes())?;
         }
+        let mut pending = Vec::new();
+        let capabilities = if requested.is_empty() {
+            BTreeSet::new()
+        } else {
+            read_capabilities_ack(&stream, &mut lines, op, &mut pending)?
+        };
+        let role = if auth_method.is_some() {
+            stream.set_read_timeout(Some(op.remaining().map_err(|_| Error::Timeout)?))?;
+            let line = lines.next().ok_or(Error::InvalidData)??;
+            Some(parse_auth_reply(&line)?)
+        } else {
+            None
+        };
+        let mut pending = pending.into_iter();
+        let mut decoder = MessageDecoder::new();
+        let history = read_history(
+            &stream,
+            &mut lines,
+            op,
+            &mut pending,
+            &mut decoder,
+            &capabilities,
+            history_lines(&headers),
+        )?;
+        let history_len = history.len();
         trace!(api_version, "connection estabilished");
-        stream.set_read_timeout(None)?;
+        stream.set_read_timeout(keepalive_timeout(keepalive_interval, &capabilities))?;
+        let stream = Arc::new(Mutex::new(stream));
         let (tx, rx) = rtsc::channel::bounded(options.incoming_queue_size);
-        let stream_c = stream.try_clone()?;
         let connected = Arc::new(atomic::AtomicBool::new(true));
         let connected_c = connected.clone();
-        thread::spawn(move || handle_connection(tx, stream_c, connected_c));
+        let calls = Arc::new(Calls::new(capabilities.contains(&Capability::CallId)));
+        let calls_c = calls.clone();
+        let capabilities_c = capabilities.clone();
+        let stream_c = stream.clone();
+        thread::spawn(move || {
+            handle_connection(
+                tx,
+                reader,
+                connected_c,
+                &calls_c,
+                history,
+                pending,
+                decoder,
+                &capabilities_c,
+                &stream_c,
+            );
+        });
         Ok((
             Self {
                 inner: Inner {
-                    stream: Mutex::new(stream),
+                    stream,
                     connected,
+                    headers,
+                    calls,
+                    call_ids: capabilities.contains(&Capability::CallId),
+                    call_lock: <_>::default(),
+                    role,
+                    capabilities,
+                    history_len,
                 }
                 .into(),
             },
@@ -150,51 +295,233 @@ impl Client {
             .write_all(format!("{}\n", data.to_string()).as_bytes())
             .map_err(Into::into)
     }
+    /// Send a request to the server and wait for the reply
+    ///
+    /// If the call-id capability has been negotiated, the reply is matched by the request id.
+    /// Otherwise the next server (`<<<`) message is considered as the reply and calls are
+    /// serialized. Replies are not sent to the client frame channel.
+    pub fn call(&self, data: impl ToString, timeout: Duration) -> Result<String, Error> {
+        let (tx, rx) = mpsc::channel();
+        let calls = &self.inner.calls;
+        if self.inner.call_ids {
+            let id = calls.register(tx);
+            let result = self
+                .try_send(format_call(id, &data.to_string()))
+                .and_then(|()| wait_reply(&rx, timeout));
+            calls.unregister(id);
+            result
+        } else {
+            let _lock = self.inner.call_lock.lock();
+            calls.register_next(tx);
+            let result = self.try_send(data).and_then(|()| wait_reply(&rx, timeout));
+            calls.unregister_next();
+            result
+        }
+    }
     /// Check if the client is connected
     pub fn is_connected(&self) -> bool {
         self.inner.connected.load(atomic::Ordering::Relaxed)
     }
+    /// Greeting headers, sent by the server
+    pub fn headers(&self) -> &BTreeMap<String, String> {
+        &self.inner.headers
+    }
+    /// The client role, assigned by the server (`None` if the server does not require
+    /// authentication)
+    pub fn role(&self) -> Option<Role> {
+        self.inner.role
+    }
+    /// Capabilities, negotiated with the server
+    pub fn capabilities(&self) -> &BTreeSet<Capability> {
+        &self.inner.capabilities
+    }
+    /// The number of history messages, replayed by the server on connect. The history messages
+    /// are the first ones in the frame channel.
+    pub fn history_len(&self) -> usize {
+        self.inner.history_len
+    }
+}
+
+/// Checks the credentials against the authentication method, requested by the server
+pub(crate) fn auth_credentials<'a>(
+    method: &str,
+    credentials: Option<&'a Credentials>,
+) -> Result<&'a Credentials, Error> {
+    let method: AuthMethod = method
+        .parse()
+        .map_err(|_| Error::AuthFailed(format!("unsupported authentication method: {}", method)))?;
+    let credentials = credentials.ok_or(Error::AuthRequired)?;
+    if credentials.method() != method {
+        return Err(Error::AuthFailed(format!(
+            "the server requires {} authentication",
+            method
+        )));
+    }
+    Ok(credentials)
+}
+
+/// The capabilities, requested by the client and supported by the server
+pub(crate) fn requested_capabilities(
+    options: &ConnectionOptions,
+    greeting: &Greeting,
+) -> BTreeSet<Capability> {
+    let supported = greeting.capabilities();
+    options
+        .capabilities
+        .iter()
+        .chain(CLIENT_CAPABILITIES)
+        .filter(|c| supported.contains(c))
+        .copied()
+        .collect()
+}
+
+/// The server is considered dead if it sends nothing (including pings) within the returned
+/// timeout. `None` if keepalive has not been negotiated.
+pub(crate) fn keepalive_timeout(
+    interval: Option<Duration>,
+    capabilities: &BTreeSet<Capability>,
+) -> Option<Duration> {
+    interval
+        .filter(|_| capabilities.contains(&Capability::Keepalive))
+        .map(|interval| interval * KEEPALIVE_MISSES)
+}
+
+/// Reads the server confirmation of the negotiated capabilities. Messages, received before the
+/// confirmation, are encoded without the capabilities and are put to the pending list.
+fn read_capabilities_ack(
+    stream: &Stream,
+    lines: &mut impl Iterator<Item = io::Result<String>>,
+    op: &Operation,
+    pending: &mut Vec<String>,
+) -> Result<BTreeSet<Capability>, Error> {
+    loop {
+        stream.set_read_timeout(Some(op.remaining().map_err(|_| Error::Timeout)?))?;
+        let line = lines.next().ok_or(Error::InvalidData)??;
+        if let Some(capabilities) = parse_capabilities_ack(&line) {
+            return Ok(capabilities);
+        }
+        pending.push(line);
+    }
+}
+
+/// Reads the history, which is replayed by the server either before the capabilities are
+/// confirmed (the lines are pending) or after the authentication reply
+fn read_history(
+    stream: &Stream,
+    lines: &mut impl Iterator<Item = io::Result<String>>,
+    op: &Operation,
+    pending: &mut impl Iterator<Item = String>,
+    decoder: &mut MessageDecoder,
+    capabilities: &BTreeSet<Capability>,
+    count: usize,
+) -> Result<Vec<(Direction, String)>, Error> {
+    let mut history = Vec::new();
+    for _ in 0..count {
+        let line = if let Some(line) = pending.next() {
+            line
+        } else {
+            decoder.set_capabilities(capabilities);
+            stream.set_read_timeout(Some(op.remaining().map_err(|_| Error::Timeout)?))?;
+            lines.next().ok_or(Error::InvalidData)??
+        };
+        if let Some(message) = decoder.decode_message(&line)? {
+            history.push((message.direction, message.data.into_owned()));
+        }
+    }
+    Ok(history)
 }
 
-fn handle_connection(tx: FrameSender, stream: TcpStream, connected: Arc<atomic::AtomicBool>) {
+/// The number of history lines, announced by the server
+pub(crate) fn history_lines(headers: &BTreeMap<String, String>) -> usize {
+    headers
+        .get(HEADER_HISTORY)
+        .and_then(|v| v.parse().ok())
+        .unwrap_or_default()
+}
+
+fn wait_reply(rx: &mpsc::Receiver<String>, timeout: Duration) -> Result<String, Error> {
+    rx.recv_timeout(timeout).map_err(|e| match e {
+        mpsc::RecvTimeoutError::Timeout => Error::Timeout,
+        mpsc::RecvTimeoutError::Disconnected => Error::NotConnected,
+    })
+}
+
+/// The history messages are sent to the channel first. The pending lines have been received
+/// before the capabilities are confirmed. Keepalive pings are replied via the shared stream.
+#[allow(clippy::too_many_arguments)]
+fn handle_connection(
+    tx: FrameSender,
+    mut reader: BufReader<Stream>,
+    connected: Arc<atomic::AtomicBool>,
+    calls: &Calls<mpsc::Sender<String>>,
+    history: Vec<(Direction, String)>,
+    mut pending: std::vec::IntoIter<String>,
+    mut decoder: MessageDecoder,
+    capabilities: &BTreeSet<Capability>,
+    stream: &Mutex<Stream>,
+) {
     macro_rules! quit {
         () => {{
-            stream.shutdown(Shutdown::Both).ok();
+            reader.get_ref().shutdown().ok();
             break;
         }};
     }
     macro_rules! report_msg {
         ($dir: expr, $msg: expr) => {
-            if tx.send(($dir, $msg)).is_err() {
-                quit!();
+            if let Some(msg) = calls.route($dir, $msg) {
+                if tx.send(($dir, msg)).is_err() {
+                    quit!();
+                }
             }
         };
     }
-    let reader = &mut std::io::BufReader::new(&stream);
-    let mut last_direction: Option<Direction> = None;
-    for line in reader.lines() {
-        let Ok(line) = line else {
-            quit!();
-        };
-        if let Some(msg) = line.strip_prefix(Direction::ClientToServer.as_str()) {
-            last_direction = Some(Direction::ClientToServer);
-            report_msg!(Direction::ClientToServer, msg.to_string());
-        } else if let Some(msg) = line.strip_prefix(Direction::ServerToClient.as_str()) {
-            last_direction = Some(Direction::ServerToClient);
-            report_msg!(Direction::ServerToClient, msg.to_string());
+    let keepalive = capabilities.contains(&Capability::Keepalive);
+    let mut capabilities = Some(capabilities);
+    let mut history = history.into_iter();
+    let mut buf = String::new();
+    loop {
+        if let Some(frame) = history.next() {
+            if tx.send(frame).is_err() {
+                quit!();
+            }
+            continue;
+        }
+        buf.clear();
+        if let Some(line) = pending.next() {
+            buf.push_str(&line);
         } else {
-            let Some(last_direction) = last_direction else {
+            if let Some(capabilities) = capabilities.take() {
+                decoder.set_capabilities(capabilities);
+            }
+            if !matches!(reader.read_line(&mut buf), Ok(n) if n > 0) {
+                trace!("server disconnected or timed out");
+                quit!();
+            }
+        }
+        if keepalive && decoder.is_ping(&buf) {
+            if stream
+                .lock()
+                .write_all(format!("{}\n", PONG).as_bytes())
+                .is_err()
+            {
                 quit!();
-            };
-            report_msg!(last_direction, line);
+            }
+            continue;
+        }
+        let Ok(message) = decoder.decode_message(&buf) else {
+            quit!();
+        };
+        if let Some(message) = message {
+            report_msg!(message.direction, message.data.into_owned());
         }
     }
     connected.store(false, atomic::Ordering::Relaxed);
+    calls.clear();
 }
 
 impl Drop for Client {
     fn drop(&mut self) {
-        self.inner.stream.lock().shutdown(Shutdown::Both).ok();
+        self.inner.stream.lock().shutdown().ok();
         self.inner.connected.store(false, atomic::Ordering::Relaxed);
     }
 }
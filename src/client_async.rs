@@ -1,4 +1,5 @@
 use std::{
+    collections::{BTreeMap, BTreeSet},
     net::ToSocketAddrs,
     sync::{atomic, Arc},
     time::Duration,
@@ -10,18 +11,30 @@ use rtsc::{
     ops::Operation,
 };
 use tokio::{
-    io::{AsyncBufReadExt, AsyncWriteExt},
-    net::{
-        tcp::{OwnedReadHalf, OwnedWriteHalf},
-        TcpStream,
-    },
-    sync::Mutex,
+    io::{AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader, Lines},
+    net::TcpStream,
+    sync::{oneshot, Mutex},
     task::JoinHandle,
 };
 use tracing::trace;
 
+#[cfg(unix)]
+use std::path::Path;
+#[cfg(unix)]
+use tokio::net::UnixStream;
+
 use crate::{
-    client::ConnectionOptions, Direction, Error, API_VERSION, GREETING, HEADERS_TRANSMISSION_END,
+    auth::parse_auth_reply,
+    call::{format_call, Calls},
+    client::{
+        auth_credentials, history_lines, keepalive_timeout, requested_capabilities,
+        ConnectionOptions,
+    },
+    protocol::{
+        format_hello, parse_capabilities_ack, Capability, Greeting, GreetingParser, MessageDecoder,
+        PONG,
+    },
+    Direction, Error, Role, TransportAsync, API_VERSION, HEADER_AUTH,
 };
 
 /// Client instance
@@ -30,11 +43,21 @@ pub struct ClientAsync {
     inner: Arc<Inner>,
 }
 
+type Reader = Box<dyn AsyncRead + Send + Unpin>;
+type Writer = Box<dyn AsyncWrite + Send + Unpin>;
+
 struct Inner {
-    writer: Mutex<OwnedWriteHalf>,
+    writer: Arc<Mutex<Writer>>,
     connected: Arc<atomic::AtomicBool>,
     timeout: Duration,
     reader_fut: SyncMutex<JoinHandle<()>>,
+    headers: BTreeMap<String, String>,
+    calls: Arc<Calls<oneshot::Sender<String>>>,
+    call_ids: bool,
+    call_lock: Mutex<()>,
+    role: Option<Role>,
+    capabilities: BTreeSet<Capability>,
+    history_len: usize,
 }
 
 impl ClientAsync {
@@ -51,7 +74,7 @@ impl ClientAsync {
     ) -> Result<(Self, Receiver<(Direction, String)>), Error> {
         let timeout = options.timeout;
         let op = Operation::new(timeout);
-        let mut stream = tokio::time::timeout(
+        let socket = tokio::time::timeout(
             timeout,
             TcpStream::connect(
                 &addr
@@ -61,66 +84,137 @@ impl ClientAsync {
             ),
         )
         .await??;
-        stream.set_nodelay(true)?;
-        let reader = tokio::io::BufReader::new(&mut stream);
-        let mut lines = reader.lines();
+        socket.set_nodelay(true)?;
+        let (reader, writer) =
+            split_stream(socket, options, op.remaining().map_err(|_| Error::Timeout)?).await?;
+        Self::connect_stream(reader, writer, options, &op).await
+    }
+    /// Connect to a server via a Unix domain socket and create a client instance
+    #[cfg(unix)]
+    pub async fn connect_unix(
+        path: impl AsRef<Path>,
+    ) -> Result<(Self, Receiver<(Direction, String)>), Error> {
+        Self::connect_unix_with_options(path, &ConnectionOptions::default()).await
+    }
+    /// Connect to a server via a Unix domain socket and create a client instance with the
+    /// defined options (TLS options are ignored)
+    #[cfg(unix)]
+    pub async fn connect_unix_with_options(
+        path: impl AsRef<Path>,
+        options: &ConnectionOptions,
+    ) -> Result<(Self, Receiver<(Direction, String)>), Error> {
+        let op = Operation::new(options.timeout);
+        let socket = tokio::time::timeout(options.timeout, UnixStream::connect(path)).await??;
+        let (reader, writer) = socket.into_split();
+        Self::connect_stream(Box::new(reader), Box::new(writer), options, &op).await
+    }
+    /// Connect to a server over a custom transport and create a client instance with the defined
+    /// options (TLS options are ignored)
+    pub async fn connect_transport(
+        transport: impl TransportAsync,
+        options: &ConnectionOptions,
+    ) -> Result<(Self, Receiver<(Direction, String)>), Error> {
+        let op = Operation::new(options.timeout);
+        let (reader, writer) = tokio::io::split(transport);
+        Self::connect_stream(Box::new(reader), Box::new(writer), options, &op).await
+    }
+    async fn connect_stream(
+        reader: Reader,
+        mut writer: Writer,
+        options: &ConnectionOptions,
+        op: &Operation,
+    ) -> Result<(Self, Receiver<(Direction, String)>), Error> {
+        // the reader is passed to the connection task as-is, as it may already contain the data
+        // which follows the greeting
+        let mut reader = BufReader::new(reader);
+        let mut lines = (&mut reader).lines();
         trace!("reading greeting");
-        let line = tokio::time::timeout(
+        let greeting = tokio::time::timeout(
             op.remaining().map_err(|_| Error::Timeout)?,
-            lines.next_line(),
+            read_greeting(&mut lines),
         )
-        .await??
-        .ok_or(Error::InvalidData)?;
-        let mut sp = line.split('/');
-        if sp.next() != Some(GREETING) {
-            return Err(Error::InvalidData);
-        }
-        let api_version: u8 = sp
-            .next()
-            .ok_or_else(|| {
-                trace!("Unable to parse greetings header value");
-                Error::InvalidData
-            })?
-            .trim()
-            .parse()
-            .map_err(|error| {
-                trace!(%error, "Unable to parse greetings header value");
-                Error::InvalidData
-            })?;
+        .await??;
+        let api_version = greeting.api_version();
         if api_version != API_VERSION {
             return Err(Error::ApiVersion(api_version));
         }
-        trace!("reading headers");
-        let mut headers_end = false;
-        // headers are reserved for future use
-        while let Ok(Some(line)) = tokio::time::timeout(
-            op.remaining().map_err(|_| Error::Timeout)?,
-            lines.next_line(),
-        )
-        .await?
-        {
-            if line == HEADERS_TRANSMISSION_END {
-                headers_end = true;
-                break;
-            }
+        let requested = requested_capabilities(options, &greeting);
+        let keepalive_interval = greeting.keepalive_interval();
+        let headers = greeting.into_headers();
+        if !requested.is_empty() {
+            trace!("sending hello");
+            tokio::time::timeout(
+                op.remaining().map_err(|_| Error::Timeout)?,
+                writer.write_all(format_hello(&requested).as_bytes()),
+            )
+            .await??;
         }
-        if !headers_end {
-            trace!("Invalid headers transmission end");
-            return Err(Error::InvalidData);
+        let auth_method = headers.get(HEADER_AUTH);
+        if let Some(method) = auth_method {
+            let credentials = auth_credentials(method, options.credentials.as_ref())?;
+            trace!(method, "authenticating");
+            tokio::time::timeout(
+                op.remaining().map_err(|_| Error::Timeout)?,
+                writer.write_all(credentials.to_line().as_bytes()),
+            )
+            .await??;
         }
+        let mut pending = Vec::new();
+        let capabilities = if requested.is_empty() {
+            BTreeSet::new()
+        } else {
+            read_capabilities_ack(&mut lines, op, &mut pending).await?
+        };
+        let role = if auth_method.is_some() {
+            Some(parse_auth_reply(&next_line(&mut lines, op).await?)?)
+        } else {
+            None
+        };
+        let mut pending = pending.into_iter();
+        let mut decoder = MessageDecoder::new();
+        let history = read_history(
+            &mut lines,
+            op,
+            &mut pending,
+            &mut decoder,
+            &capabilities,
+            history_lines(&headers),
+        )
+        .await?;
+        let history_len = history.len();
         trace!(api_version, "connection estabilished");
+        let writer = Arc::new(Mutex::new(writer));
         let (tx, rx) = rtsc::channel_async::bounded(options.incoming_queue_size);
-        let (reader, writer) = stream.into_split();
         let connected = Arc::new(atomic::AtomicBool::new(true));
         let connected_c = connected.clone();
-        let reader_fut = tokio::spawn(handle_connection(tx, reader, connected_c));
+        let calls = Arc::new(Calls::new(capabilities.contains(&Capability::CallId)));
+        let reader_fut = tokio::spawn(handle_connection(
+            tx,
+            reader,
+            connected_c,
+            calls.clone(),
+            history,
+            pending,
+            decoder,
+            capabilities.clone(),
+            writer.clone(),
+            keepalive_timeout(keepalive_interval, &capabilities),
+            options.timeout,
+        ));
         Ok((
             Self {
                 inner: Inner {
-                    writer: Mutex::new(writer),
+                    writer,
                     connected,
-                    timeout,
+                    timeout: options.timeout,
                     reader_fut: SyncMutex::new(reader_fut),
+                    headers,
+                    calls,
+                    call_ids: capabilities.contains(&Capability::CallId),
+                    call_lock: <_>::default(),
+                    role,
+                    capabilities,
+                    history_len,
                 }
                 .into(),
             },
@@ -137,16 +231,175 @@ impl ClientAsync {
         .await?
         .map_err(Into::into)
     }
+    /// Send a request to the server and wait for the reply
+    ///
+    /// If the call-id capability has been negotiated, the reply is matched by the request id.
+    /// Otherwise the next server (`<<<`) message is considered as the reply and calls are
+    /// serialized. Replies are not sent to the client frame channel.
+    pub async fn call(&self, data: impl ToString, timeout: Duration) -> Result<String, Error> {
+        let (tx, rx) = oneshot::channel();
+        let calls = &self.inner.calls;
+        if self.inner.call_ids {
+            let id = calls.register(tx);
+            let result = match self.try_send(format_call(id, &data.to_string())).await {
+                Ok(()) => wait_reply(rx, timeout).await,
+                Err(e) => Err(e),
+            };
+            calls.unregister(id);
+            result
+        } else {
+            let _lock = self.inner.call_lock.lock().await;
+            calls.register_next(tx);
+            let result = match self.try_send(data).await {
+                Ok(()) => wait_reply(rx, timeout).await,
+                Err(e) => Err(e),
+            };
+            calls.unregister_next();
+            result
+        }
+    }
     /// Check if the client is connected
     pub fn is_connected(&self) -> bool {
         self.inner.connected.load(atomic::Ordering::Relaxed)
     }
+    /// Greeting headers, sent by the server
+    pub fn headers(&self) -> &BTreeMap<String, String> {
+        &self.inner.headers
+    }
+    /// The client role, assigned by the server (`None` if the server does not require
+    /// authentication)
+    pub fn role(&self) -> Option<Role> {
+        self.inner.role
+    }
+    /// Capabilities, negotiated with the server
+    pub fn capabilities(&self) -> &BTreeSet<Capability> {
+        &self.inner.capabilities
+    }
+    /// The number of history messages, replayed by the server on connect. The history messages
+    /// are the first ones in the frame channel.
+    pub fn history_len(&self) -> usize {
+        self.inner.history_len
+    }
 }
 
+#[cfg_attr(not(feature = "tls"), allow(unused_variables, clippy::unused_async))]
+async fn split_stream(
+    socket: TcpStream,
+    options: &ConnectionOptions,
+    timeout: Duration,
+) -> Result<(Reader, Writer), Error> {
+    #[cfg(feature = "tls")]
+    if let Some((config, server_name)) = &options.tls {
+        let connector = tokio_rustls::TlsConnector::from(config.clone());
+        let stream = tokio::time::timeout(
+            timeout,
+            connector.connect(crate::tls::server_name(server_name)?, socket),
+        )
+        .await??;
+        let (reader, writer) = tokio::io::split(stream);
+        return Ok((Box::new(reader), Box::new(writer)));
+    }
+    let (reader, writer) = socket.into_split();
+    Ok((Box::new(reader), Box::new(writer)))
+}
+
+async fn read_greeting<R>(lines: &mut Lines<R>) -> Result<Greeting, Error>
+where
+    R: AsyncBufRead + Unpin,
+{
+    let mut parser = GreetingParser::new();
+    loop {
+        let line = lines.next_line().await?.ok_or_else(|| {
+            trace!("Invalid headers transmission end");
+            Error::InvalidData
+        })?;
+        if let Some(greeting) = parser.process_line(&line)? {
+            return Ok(greeting);
+        }
+    }
+}
+
+/// Reads the server confirmation of the negotiated capabilities. Messages, received before the
+/// confirmation, are encoded without the capabilities and are put to the pending list.
+async fn read_capabilities_ack<R>(
+    lines: &mut Lines<R>,
+    op: &Operation,
+    pending: &mut Vec<String>,
+) -> Result<BTreeSet<Capability>, Error>
+where
+    R: AsyncBufRead + Unpin,
+{
+    loop {
+        let line = next_line(lines, op).await?;
+        if let Some(capabilities) = parse_capabilities_ack(&line) {
+            return Ok(capabilities);
+        }
+        pending.push(line);
+    }
+}
+
+/// Reads the next line within the operation timeout
+async fn next_line<R>(lines: &mut Lines<R>, op: &Operation) -> Result<String, Error>
+where
+    R: AsyncBufRead + Unpin,
+{
+    tokio::time::timeout(
+        op.remaining().map_err(|_| Error::Timeout)?,
+        lines.next_line(),
+    )
+    .await??
+    .ok_or(Error::InvalidData)
+}
+
+/// Reads the history, which is replayed by the server either before the capabilities are
+/// confirmed (the lines are pending) or after the authentication reply
+async fn read_history<R>(
+    lines: &mut Lines<R>,
+    op: &Operation,
+    pending: &mut impl Iterator<Item = String>,
+    decoder: &mut MessageDecoder,
+    capabilities: &BTreeSet<Capability>,
+    count: usize,
+) -> Result<Vec<(Direction, String)>, Error>
+where
+    R: AsyncBufRead + Unpin,
+{
+    let mut history = Vec::new();
+    for _ in 0..count {
+        let line = if let Some(line) = pending.next() {
+            line
+        } else {
+            decoder.set_capabilities(capabilities);
+            next_line(lines, op).await?
+        };
+        if let Some(message) = decoder.decode_message(&line)? {
+            history.push((message.direction, message.data.into_owned()));
+        }
+    }
+    Ok(history)
+}
+
+async fn wait_reply(rx: oneshot::Receiver<String>, timeout: Duration) -> Result<String, Error> {
+    tokio::time::timeout(timeout, rx)
+        .await?
+        .map_err(|_| Error::NotConnected)
+}
+
+/// The history messages are sent to the channel first. The pending lines have been received
+/// before the capabilities are confirmed. Keepalive pings are replied via the shared writer.
+#[allow(clippy::too_many_arguments)]
 async fn handle_connection(
     tx: Sender<(Direction, String)>,
-    mut reader: OwnedReadHalf,
+    reader: BufReader<Reader>,
     connected: Arc<atomic::AtomicBool>,
+    calls: Arc<Calls<oneshot::Sender<String>>>,
+    history: Vec<(Direction, String)>,
+    mut pending: std::vec::IntoIter<String>,
+    mut decoder: MessageDecoder,
+    capabilities: BTreeSet<Capability>,
+    writer: Arc<Mutex<Writer>>,
+    read_timeout: Option<Duration>,
+    timeout: Duration,
 ) {
     macro_rules! quit {
         () => {{
@@ -155,34 +408,69 @@ async fn handle_connection(
     }
     macro_rules! report_msg {
         ($dir: expr, $msg: expr) => {
-            if tx.send(($dir, $msg)).await.is_err() {
-                quit!();
+            if let Some(msg) = calls.route($dir, $msg) {
+                if tx.send(($dir, msg)).await.is_err() {
+                    quit!();
+                }
             }
         };
     }
-    let reader = tokio::io::BufReader::new(&mut reader);
-    let mut last_direction: Option<Direction> = None;
+    let keepalive = capabilities.contains(&Capability::Keepalive);
+    let mut capabilities = Some(capabilities);
+    let mut history = history.into_iter();
     let mut lines = reader.lines();
-    while let Ok(Some(line)) = lines.next_line().await {
-        if let Some(msg) = line.strip_prefix(Direction::ClientToServer.as_str()) {
-            last_direction = Some(Direction::ClientToServer);
-            report_msg!(Direction::ClientToServer, msg.to_string());
-        } else if let Some(msg) = line.strip_prefix(Direction::ServerToClient.as_str()) {
-            last_direction = Some(Direction::ServerToClient);
-            report_msg!(Direction::ServerToClient, msg.to_string());
+    loop {
+        if let Some(frame) = history.next() {
+            if tx.send(frame).await.is_err() {
+                quit!();
+            }
+            continue;
+        }
+        let line = if let Some(line) = pending.next() {
+            line
         } else {
-            let Some(last_direction) = last_direction else {
+            if let Some(capabilities) = capabilities.take() {
+                decoder.set_capabilities(&capabilities);
+            }
+            let line = if let Some(read_timeout) = read_timeout {
+                tokio::time::timeout(read_timeout, lines.next_line())
+                    .await
+                    .unwrap_or_else(|_| Err(std::io::ErrorKind::TimedOut.into()))
+            } else {
+                lines.next_line().await
+            };
+            let Ok(Some(line)) = line else {
+                trace!("server disconnected or timed out");
                 quit!();
             };
-            report_msg!(last_direction, line);
+            line
+        };
+        if keepalive && decoder.is_ping(&line) {
+            let mut writer = writer.lock().await;
+            if !matches!(
+                tokio::time::timeout(timeout, writer.write_all(format!("{}\n", PONG).as_bytes()))
+                    .await,
+                Ok(Ok(()))
+            ) {
+                quit!();
+            }
+            continue;
+        }
+        let Ok(message) = decoder.decode_message(&line) else {
+            quit!();
+        };
+        if let Some(message) = message {
+            report_msg!(message.direction, message.data.into_owned());
         }
     }
     connected.store(false, atomic::Ordering::Relaxed);
+    calls.clear();
 }
 
 impl Drop for ClientAsync {
     fn drop(&mut self) {
         self.inner.reader_fut.lock().abort();
         self.inner.connected.store(false, atomic::Ordering::Relaxed);
+        self.inner.calls.clear();
     }
 }
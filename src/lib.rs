@@ -6,17 +6,64 @@ use std::{net::ToSocketAddrs, time::Duration};
 
 use once_cell::sync::Lazy;
 
+mod auth;
+pub use auth::{AuthMethod, Credentials, Identity, Role};
+
+mod call;
+mod limit;
+pub use limit::{LongLinePolicy, RateLimit};
+mod pipe;
+mod queue;
+pub use queue::SlowClientPolicy;
+mod stream;
+pub use stream::ClientAddr;
+mod transport;
+pub use transport::Transport;
+#[cfg(feature = "async")]
+pub use transport::TransportAsync;
+#[cfg(unix)]
+mod unix;
+#[cfg(feature = "websocket")]
+mod websocket;
+
 mod server;
-pub use server::Server;
+pub use server::{
+    ClientInfo, DisconnectReason, IncomingFrame, IncomingPolicy, Server, ServerEvent,
+};
+
+#[cfg(feature = "async")]
+mod server_async;
+#[cfg(feature = "async")]
+pub use server_async::ServerAsync;
 
 mod client;
 pub use client::{Client, ConnectionOptions};
 
+pub mod protocol;
+pub use protocol::{Capability, Severity};
+use protocol::{API_VERSION, GREETING, HEADERS_TRANSMISSION_END, HEADER_AUTH, HEADER_HISTORY};
+
+pub mod router;
+#[cfg(feature = "derive")]
+pub use rflow_derive::RflowCommand;
+pub use router::RflowCommand;
+
 #[cfg(feature = "async")]
 mod client_async;
 #[cfg(feature = "async")]
 pub use client_async::ClientAsync;
 
+mod reconnect;
+pub use reconnect::{ClientEvent, ReconnectOptions, ReconnectingClient};
+
+#[cfg(feature = "async")]
+mod reconnect_async;
+#[cfg(feature = "async")]
+pub use reconnect_async::ReconnectingClientAsync;
+
+#[cfg(feature = "tls")]
+pub mod tls;
+
 #[cfg(feature = "locking-default")]
 use parking_lot::{Condvar, Mutex, RawMutex};
 
@@ -26,11 +73,6 @@ use parking_lot_rt::{Condvar, Mutex, RawMutex};
 #[cfg(feature = "locking-rt-safe")]
 use rtsc::pi::{Condvar, Mutex, RawMutex};
 
-const GREETING: &str = "RFLOW";
-const HEADERS_TRANSMISSION_END: &str = "---";
-
-const API_VERSION: u8 = 1;
-
 const DEFAULT_INCOMING_QUEUE_SIZE: usize = 128;
 const DEFAULT_OUTGOING_QUEUE_SIZE: usize = 128;
 
@@ -38,11 +80,33 @@ const DEFAULT_TIMEOUT: Duration = Duration::from_secs(5);
 
 static DEFAULT_SERVER: Lazy<Server> = Lazy::new(|| Server::new(DEFAULT_TIMEOUT));
 
+/// Get the default server instance
+pub fn default_server() -> Server {
+    DEFAULT_SERVER.clone()
+}
+
 /// Serve the default server
 pub fn serve(addr: impl ToSocketAddrs + std::fmt::Debug) -> Result<(), Error> {
     DEFAULT_SERVER.serve(addr)
 }
 
+/// Serve the default server on a Unix domain socket
+#[cfg(unix)]
+pub fn serve_unix(path: impl AsRef<std::path::Path>) -> Result<(), Error> {
+    DEFAULT_SERVER.serve_unix(path)
+}
+
+/// Serve the default server over WebSocket
+#[cfg(feature = "websocket")]
+pub fn serve_websocket(addr: impl ToSocketAddrs + std::fmt::Debug) -> Result<(), Error> {
+    DEFAULT_SERVER.serve_websocket(addr)
+}
+
+/// Serve a single client of the default server over a serial device
+pub fn serve_serial(path: impl AsRef<std::path::Path>) -> Result<(), Error> {
+    DEFAULT_SERVER.serve_serial(path)
+}
+
 /// Spawn the default server as a separate thread and return the data channel
 pub fn spawn(addr: impl ToSocketAddrs + std::fmt::Debug) -> Result<server::FrameReceiver, Error> {
     let listener = std::net::TcpListener::bind(addr)?;
@@ -54,16 +118,59 @@ pub fn spawn(addr: impl ToSocketAddrs + std::fmt::Debug) -> Result<server::Frame
     DEFAULT_SERVER.take_data_channel()
 }
 
+/// Gracefully shut down the default server
+pub fn shutdown() {
+    DEFAULT_SERVER.shutdown();
+}
+
+/// Set a greeting header of the default server
+pub fn set_header(name: &str, value: impl ToString) -> Result<(), Error> {
+    DEFAULT_SERVER.set_header(name, value)
+}
+
+/// Require authentication for the default server's clients
+pub fn set_authenticator<F>(method: AuthMethod, f: F) -> Result<(), Error>
+where
+    F: Fn(&Credentials) -> Option<Identity> + Send + Sync + 'static,
+{
+    DEFAULT_SERVER.set_authenticator(method, f)
+}
+
 /// Send a message to the default server's clients
 pub fn send(data: impl ToString) {
     DEFAULT_SERVER.send(data);
 }
 
+/// Send a message with the severity to the default server's clients
+pub fn send_with_severity(severity: Severity, data: impl ToString) {
+    DEFAULT_SERVER.send_with_severity(severity, data);
+}
+
+/// Send a message to a single client of the default server
+pub fn send_to(client_id: usize, data: impl ToString) -> Result<(), Error> {
+    DEFAULT_SERVER.send_to(client_id, data)
+}
+
+/// Send a message to all clients of the default server except the specified one
+pub fn send_except(client_id: usize, data: impl ToString) {
+    DEFAULT_SERVER.send_except(client_id, data);
+}
+
+/// Reply to the default server's client the frame has been received from
+pub fn reply(frame: &IncomingFrame, data: impl ToString) -> Result<(), Error> {
+    DEFAULT_SERVER.reply(frame, data)
+}
+
 /// Take the default server data channel
 pub fn take_data_channel() -> Result<server::FrameReceiver, Error> {
     DEFAULT_SERVER.take_data_channel()
 }
 
+/// Take the default server event channel
+pub fn take_event_channel() -> Result<server::EventReceiver, Error> {
+    DEFAULT_SERVER.take_event_channel()
+}
+
 /// Direction of the message (client)
 #[derive(Copy, Clone, Eq, PartialEq, Debug)]
 pub enum Direction {
@@ -111,6 +218,9 @@ pub enum Error {
     /// Data channel is already taken
     #[error("Data channel is already taken")]
     DataChannelTaken,
+    /// Event channel is already taken
+    #[error("Event channel is already taken")]
+    EventChannelTaken,
     /// All I/O errors
     #[error("IO error: {0}")]
     Io(#[from] std::io::Error),
@@ -126,6 +236,28 @@ pub enum Error {
     /// Timed out
     #[error("Timed out")]
     Timeout,
+    /// The client is not connected
+    #[error("Not connected")]
+    NotConnected,
+    /// The server requires authentication but no credentials are set
+    #[error("Authentication required")]
+    AuthRequired,
+    /// Authentication failed
+    #[error("Authentication failed: {0}")]
+    AuthFailed(String),
+    /// No client with the specified id is connected
+    #[error("Client not found: {0}")]
+    ClientNotFound(usize),
+    /// The server is shutting down, new clients are refused
+    #[error("Server is shutting down")]
+    ShuttingDown,
+    /// The maximum number of clients is reached
+    #[error("Too many clients")]
+    TooManyClients,
+    /// TLS configuration or handshake errors
+    #[cfg(feature = "tls")]
+    #[error("TLS error: {0}")]
+    Tls(String),
 }
 
 #[cfg(feature = "async")]
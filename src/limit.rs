@@ -0,0 +1,208 @@
+use std::{
+    io::{self, BufRead},
+    time::{Duration, Instant},
+};
+
+#[cfg(feature = "async")]
+use tokio::io::{AsyncBufRead, AsyncBufReadExt as _};
+
+use crate::Error;
+
+/// What to do with client lines which exceed the maximum length
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub enum LongLinePolicy {
+    /// Truncate the line to the maximum length, the rest of the line is discarded (default)
+    #[default]
+    Truncate,
+    /// Disconnect the client
+    Disconnect,
+}
+
+/// Client line limits
+#[derive(Copy, Clone, Default)]
+pub(crate) struct LineLimit {
+    pub(crate) max_length: Option<usize>,
+    pub(crate) policy: LongLinePolicy,
+}
+
+impl LineLimit {
+    /// Appends the chunk, which is read from a buffered reader, to the line. Returns the number of
+    /// bytes to consume and `true` if the line is complete.
+    fn process_chunk(
+        self,
+        line: &mut Vec<u8>,
+        chunk: &[u8],
+        truncated: &mut bool,
+    ) -> io::Result<(usize, bool)> {
+        let (data, consumed, complete) = match chunk.iter().position(|&b| b == b'\n') {
+            Some(pos) => (&chunk[..pos], pos + 1, true),
+            None => (chunk, chunk.len(), false),
+        };
+        match self.max_length {
+            Some(max) if line.len() + data.len() > max => {
+                if self.policy == LongLinePolicy::Disconnect {
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, "line too long"));
+                }
+                line.extend(&data[..max - line.len()]);
+                *truncated = true;
+            }
+            _ => line.extend(data),
+        }
+        Ok((consumed, complete))
+    }
+}
+
+/// Converts the line, invalid UTF-8 sequences are replaced with `U+FFFD`
+fn line_to_string(mut line: Vec<u8>) -> String {
+    if line.last() == Some(&b'\r') {
+        line.pop();
+    }
+    match String::from_utf8(line) {
+        Ok(v) => v,
+        Err(e) => String::from_utf8_lossy(e.as_bytes()).into_owned(),
+    }
+}
+
+/// Reads client lines, limited by the maximum length. Unlike [`BufRead::lines`], invalid UTF-8
+/// does not cause an error.
+pub(crate) struct LineReader<R> {
+    reader: R,
+    limit: LineLimit,
+}
+
+impl<R: BufRead> LineReader<R> {
+    pub(crate) fn new(reader: R, limit: LineLimit) -> Self {
+        Self { reader, limit }
+    }
+    fn read_line(&mut self) -> io::Result<Option<String>> {
+        let mut line = Vec::new();
+        let mut truncated = false;
+        loop {
+            let chunk = match self.reader.fill_buf() {
+                Ok(v) => v,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            };
+            if chunk.is_empty() {
+                if line.is_empty() && !truncated {
+                    return Ok(None);
+                }
+                break;
+            }
+            let (consumed, complete) =
+                self.limit.process_chunk(&mut line, chunk, &mut truncated)?;
+            self.reader.consume(consumed);
+            if complete {
+                break;
+            }
+        }
+        Ok(Some(line_to_string(line)))
+    }
+}
+
+impl<R: BufRead> Iterator for LineReader<R> {
+    type Item = io::Result<String>;
+    fn next(&mut self) -> Option<Self::Item> {
+        self.read_line().transpose()
+    }
+}
+
+/// Asynchronous version of [`LineReader`]
+#[cfg(feature = "async")]
+pub(crate) struct LineReaderAsync<R> {
+    reader: R,
+    limit: LineLimit,
+}
+
+#[cfg(feature = "async")]
+impl<R: AsyncBufRead + Unpin> LineReaderAsync<R> {
+    pub(crate) fn new(reader: R, limit: LineLimit) -> Self {
+        Self { reader, limit }
+    }
+    pub(crate) async fn next_line(&mut self) -> io::Result<Option<String>> {
+        let mut line = Vec::new();
+        let mut truncated = false;
+        loop {
+            let chunk = self.reader.fill_buf().await?;
+            if chunk.is_empty() {
+                if line.is_empty() && !truncated {
+                    return Ok(None);
+                }
+                break;
+            }
+            let (consumed, complete) =
+                self.limit.process_chunk(&mut line, chunk, &mut truncated)?;
+            self.reader.consume(consumed);
+            if complete {
+                break;
+            }
+        }
+        Ok(Some(line_to_string(line)))
+    }
+}
+
+/// Client message rate limit
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct RateLimit {
+    messages: u32,
+    period: Duration,
+}
+
+impl RateLimit {
+    /// Allow the number of messages per period. The messages are allowed to be sent in a burst,
+    /// the limit is restored gradually. Returns [`Error::InvalidData`] if the number of messages
+    /// or the period is zero.
+    pub fn new(messages: u32, period: Duration) -> Result<Self, Error> {
+        if messages == 0 || period.is_zero() {
+            return Err(Error::InvalidData);
+        }
+        Ok(Self { messages, period })
+    }
+}
+
+/// Token bucket, created for each client
+pub(crate) struct TokenBucket {
+    limit: RateLimit,
+    tokens: f64,
+    updated: Instant,
+    notified: Option<Instant>,
+}
+
+impl TokenBucket {
+    pub(crate) fn new(limit: RateLimit) -> Self {
+        Self {
+            limit,
+            tokens: f64::from(limit.messages),
+            updated: Instant::now(),
+            notified: None,
+        }
+    }
+    /// Returns `false` if the rate limit is exceeded
+    pub(crate) fn take(&mut self) -> bool {
+        let now = Instant::now();
+        let capacity = f64::from(self.limit.messages);
+        let elapsed = now.duration_since(self.updated).as_secs_f64();
+        self.tokens =
+            (self.tokens + elapsed / self.limit.period.as_secs_f64() * capacity).min(capacity);
+        self.updated = now;
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+    /// Returns `true` if the client must be notified about a dropped message. The client is
+    /// notified once per the limit period, so a flood does not fill its outgoing queue.
+    pub(crate) fn notify(&mut self) -> bool {
+        let now = Instant::now();
+        if self
+            .notified
+            .map_or(false, |t| now.duration_since(t) < self.limit.period)
+        {
+            return false;
+        }
+        self.notified = Some(now);
+        true
+    }
+}
@@ -0,0 +1,118 @@
+use std::{
+    collections::VecDeque,
+    io::{self, Read, Write},
+    sync::Arc,
+    time::Duration,
+};
+
+use crate::{Condvar, Mutex, Transport};
+
+/// Creates a pair of connected in-memory streams
+pub(crate) fn pipe() -> (PipeStream, PipeStream) {
+    let a: Arc<Channel> = <_>::default();
+    let b: Arc<Channel> = <_>::default();
+    (
+        PipeStream {
+            rx: a.clone(),
+            tx: b.clone(),
+        },
+        PipeStream { rx: b, tx: a },
+    )
+}
+
+/// One direction of a pipe. The buffer is unbounded, so writes never block.
+struct Channel {
+    data: Mutex<ChannelData>,
+    data_available: Condvar,
+}
+
+impl Default for Channel {
+    fn default() -> Self {
+        Self {
+            data: <_>::default(),
+            data_available: Condvar::new(),
+        }
+    }
+}
+
+#[derive(Default)]
+struct ChannelData {
+    buf: VecDeque<u8>,
+    closed: bool,
+    read_timeout: Option<Duration>,
+}
+
+impl Channel {
+    fn close(&self) {
+        self.data.lock().closed = true;
+        self.data_available.notify_all();
+    }
+}
+
+/// An in-memory stream. Clones share the same pipe.
+#[derive(Clone)]
+pub(crate) struct PipeStream {
+    rx: Arc<Channel>,
+    tx: Arc<Channel>,
+}
+
+impl Transport for PipeStream {
+    fn try_clone(&self) -> io::Result<Box<dyn Transport>> {
+        Ok(Box::new(self.clone()))
+    }
+    fn shutdown(&self) -> io::Result<()> {
+        self.rx.close();
+        self.tx.close();
+        Ok(())
+    }
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.rx.data.lock().read_timeout = timeout;
+        Ok(())
+    }
+}
+
+impl Read for PipeStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut data = self.rx.data.lock();
+        while data.buf.is_empty() {
+            if data.closed {
+                return Ok(0);
+            }
+            if let Some(timeout) = data.read_timeout {
+                if self
+                    .rx
+                    .data_available
+                    .wait_for(&mut data, timeout)
+                    .timed_out()
+                    && data.buf.is_empty()
+                {
+                    return Err(io::ErrorKind::TimedOut.into());
+                }
+            } else {
+                self.rx.data_available.wait(&mut data);
+            }
+        }
+        let len = buf.len().min(data.buf.len());
+        for (dst, src) in buf.iter_mut().zip(data.buf.drain(..len)) {
+            *dst = src;
+        }
+        Ok(len)
+    }
+}
+
+impl Write for PipeStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        {
+            let mut data = self.tx.data.lock();
+            if data.closed {
+                return Err(io::ErrorKind::BrokenPipe.into());
+            }
+            data.buf.extend(buf);
+        }
+        self.tx.data_available.notify_all();
+        Ok(buf.len())
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
@@ -0,0 +1,653 @@
+//! Protocol primitives for custom clients and tools
+//!
+//! The module contains the greeting parser, the capability negotiation helpers, the server
+//! message decoder and encoder and, with the `async` feature, a [`tokio_util::codec`]
+//! implementation. See `protocol.md` for the protocol description.
+//!
+//! ```rust
+//! use rflow::{
+//!     protocol::{Capability, GreetingParser, MessageDecoder},
+//!     Direction,
+//! };
+//!
+//! let mut parser = GreetingParser::new();
+//! assert!(parser.process_line("RFLOW/1").unwrap().is_none());
+//! assert!(parser.process_line("Name: test").unwrap().is_none());
+//! let greeting = parser.process_line("---").unwrap().unwrap();
+//! assert_eq!(greeting.api_version(), 1);
+//! assert_eq!(greeting.headers().get("Name").unwrap(), "test");
+//!
+//! let mut decoder = MessageDecoder::new();
+//! assert_eq!(
+//!     decoder.decode_line("<<<hello").unwrap(),
+//!     (Direction::ServerToClient, "hello")
+//! );
+//! // a line without a prefix continues the previous message direction
+//! assert_eq!(
+//!     decoder.decode_line("world").unwrap(),
+//!     (Direction::ServerToClient, "world")
+//! );
+//!
+//! // multi-line blocks are reassembled if the capability is negotiated
+//! decoder.set_capabilities(&[Capability::Block].into_iter().collect());
+//! assert!(decoder.decode_message("<<<[lines=2]first").unwrap().is_none());
+//! let message = decoder.decode_message("second").unwrap().unwrap();
+//! assert_eq!(message.data, "first\nsecond");
+//! ```
+use std::{
+    borrow::Cow,
+    collections::{BTreeMap, BTreeSet},
+    fmt,
+    str::FromStr,
+    time::Duration,
+};
+
+use tracing::trace;
+
+use crate::{Direction, Error};
+
+/// Greeting line prefix
+pub const GREETING: &str = "RFLOW";
+/// The line which finishes the greeting headers
+pub const HEADERS_TRANSMISSION_END: &str = "---";
+/// The header with the number of history messages, replayed after the greeting
+pub const HEADER_HISTORY: &str = "History";
+/// The header with the comma-separated list of protocol extensions, supported by the server
+pub const HEADER_EXTENSIONS: &str = "Extensions";
+/// The header with the authentication method, required by the server
+pub const HEADER_AUTH: &str = "Auth";
+/// The header with the comma-separated list of capabilities, supported by the server (in the
+/// greeting) or requested by the client (in the hello)
+pub const HEADER_CAPABILITIES: &str = "Capabilities";
+
+/// The header with the keepalive interval in seconds, announced by the server if keepalive is
+/// enabled
+pub const HEADER_KEEPALIVE: &str = "Keepalive";
+
+/// Headers, which are set by the server itself and can not be overridden by the application
+pub(crate) const RESERVED_HEADERS: &[&str] = &[
+    HEADER_AUTH,
+    HEADER_CAPABILITIES,
+    HEADER_EXTENSIONS,
+    HEADER_HISTORY,
+    HEADER_KEEPALIVE,
+];
+
+/// The line the server confirms the negotiated capabilities with
+pub const CAPABILITIES_ACK: &str = "+CAPS";
+/// Keepalive request, sent by the server
+pub const PING: &str = "+PING";
+/// Keepalive reply, sent by the client
+pub const PONG: &str = "+PONG";
+
+/// The connection is considered dead if nothing is received within the number of keepalive
+/// intervals
+pub(crate) const KEEPALIVE_MISSES: u32 = 3;
+
+/// Protocol version. Optional protocol features are negotiated with capabilities, so the
+/// version stays compatible with the clients, which do not support them
+pub const API_VERSION: u8 = 1;
+
+const ATTR_ID: &str = "id";
+const ATTR_SEVERITY: &str = "sev";
+const ATTR_LINES: &str = "lines";
+
+/// Optional protocol capability, negotiated by the server and a client
+#[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug)]
+#[non_exhaustive]
+pub enum Capability {
+    /// Server messages carry unique message ids
+    MessageId,
+    /// Server messages carry severities
+    Severity,
+    /// Multi-line messages are sent as blocks with explicit line counts
+    Block,
+    /// The server sends keepalive pings, the client replies with pongs
+    Keepalive,
+    /// The client tags requests with call ids, the server tags the replies with the same ids
+    CallId,
+}
+
+impl Capability {
+    /// All capabilities, known by the crate
+    pub const ALL: &'static [Capability] = &[
+        Capability::MessageId,
+        Capability::Severity,
+        Capability::Block,
+        Capability::Keepalive,
+        Capability::CallId,
+    ];
+    /// Get capability as string
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::MessageId => "message-id",
+            Self::Severity => "severity",
+            Self::Block => "block",
+            Self::Keepalive => "keepalive",
+            Self::CallId => "call-id",
+        }
+    }
+}
+
+impl fmt::Display for Capability {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for Capability {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "message-id" => Ok(Self::MessageId),
+            "severity" => Ok(Self::Severity),
+            "block" => Ok(Self::Block),
+            "keepalive" => Ok(Self::Keepalive),
+            "call-id" => Ok(Self::CallId),
+            _ => Err(Error::InvalidData),
+        }
+    }
+}
+
+/// Parses a comma-separated capability list. Unknown capabilities are ignored.
+pub fn parse_capabilities(value: &str) -> BTreeSet<Capability> {
+    value
+        .split(',')
+        .filter_map(|c| c.trim().parse().ok())
+        .collect()
+}
+
+/// Formats a comma-separated capability list
+pub fn format_capabilities(capabilities: &BTreeSet<Capability>) -> String {
+    capabilities
+        .iter()
+        .map(|c| c.as_str())
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+/// Formats the client hello, which requests the capabilities. The hello is sent right after the
+/// server greeting (before the credentials) to servers, which announce capabilities.
+pub fn format_hello(capabilities: &BTreeSet<Capability>) -> String {
+    format!(
+        "{}/{}\n{}: {}\n{}\n",
+        GREETING,
+        API_VERSION,
+        HEADER_CAPABILITIES,
+        format_capabilities(capabilities),
+        HEADERS_TRANSMISSION_END
+    )
+}
+
+/// Formats the server confirmation of the negotiated capabilities
+pub(crate) fn format_capabilities_ack(capabilities: &BTreeSet<Capability>) -> String {
+    if capabilities.is_empty() {
+        format!("{}\n", CAPABILITIES_ACK)
+    } else {
+        format!(
+            "{} {}\n",
+            CAPABILITIES_ACK,
+            format_capabilities(capabilities)
+        )
+    }
+}
+
+/// Parses the server confirmation of the negotiated capabilities, returns `None` if the line is
+/// not a confirmation
+pub fn parse_capabilities_ack(line: &str) -> Option<BTreeSet<Capability>> {
+    let line = line.trim_end_matches(['\n', '\r']);
+    let value = line.strip_prefix(CAPABILITIES_ACK)?;
+    if !value.is_empty() && !value.starts_with(' ') {
+        return None;
+    }
+    Some(parse_capabilities(value))
+}
+
+/// Checks if a line, received from a client, starts the client hello
+pub(crate) fn is_hello(line: &str) -> bool {
+    line.strip_prefix(GREETING)
+        .map_or(false, |v| v.starts_with('/'))
+}
+
+/// Message severity (requires [`Capability::Severity`] to be negotiated)
+#[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug)]
+pub enum Severity {
+    /// Debug message
+    Debug,
+    /// Informational message
+    Info,
+    /// Warning
+    Warning,
+    /// Error
+    Error,
+}
+
+impl Severity {
+    /// Get severity as string
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Debug => "debug",
+            Self::Info => "info",
+            Self::Warning => "warning",
+            Self::Error => "error",
+        }
+    }
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for Severity {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "debug" => Ok(Self::Debug),
+            "info" => Ok(Self::Info),
+            "warning" => Ok(Self::Warning),
+            "error" => Ok(Self::Error),
+            _ => Err(Error::InvalidData),
+        }
+    }
+}
+
+/// Parses a greeting header line (`NAME: VALUE`)
+pub fn parse_header(line: &str) -> Option<(String, String)> {
+    let (name, value) = line.split_once(':')?;
+    let name = name.trim();
+    if name.is_empty() {
+        return None;
+    }
+    Some((name.to_owned(), value.trim().to_owned()))
+}
+
+/// Server greeting: the protocol version and the headers
+#[derive(Clone, Debug)]
+pub struct Greeting {
+    api_version: u8,
+    headers: BTreeMap<String, String>,
+}
+
+impl Greeting {
+    /// Protocol version, announced by the server
+    pub fn api_version(&self) -> u8 {
+        self.api_version
+    }
+    /// Greeting headers
+    pub fn headers(&self) -> &BTreeMap<String, String> {
+        &self.headers
+    }
+    /// Capabilities, supported by the server (empty for servers without capability negotiation)
+    pub fn capabilities(&self) -> BTreeSet<Capability> {
+        self.headers
+            .get(HEADER_CAPABILITIES)
+            .map(|v| parse_capabilities(v))
+            .unwrap_or_default()
+    }
+    /// Keepalive interval, announced by the server
+    pub fn keepalive_interval(&self) -> Option<Duration> {
+        self.headers
+            .get(HEADER_KEEPALIVE)
+            .and_then(|v| v.parse::<f64>().ok())
+            .filter(|v| v.is_finite() && *v > 0.0)
+            .map(Duration::from_secs_f64)
+    }
+    /// Check if the server supports the protocol extension
+    pub fn has_extension(&self, extension: &str) -> bool {
+        self.headers
+            .get(HEADER_EXTENSIONS)
+            .map_or(false, |v| v.split(',').any(|e| e.trim() == extension))
+    }
+    /// Convert the greeting into the headers
+    pub fn into_headers(self) -> BTreeMap<String, String> {
+        self.headers
+    }
+}
+
+/// Incremental server greeting parser. Invalid header lines are ignored. The client hello has the
+/// same format and can be parsed as well.
+#[derive(Default, Debug)]
+pub struct GreetingParser {
+    api_version: Option<u8>,
+    headers: BTreeMap<String, String>,
+}
+
+impl GreetingParser {
+    /// Create a new parser
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// Process a greeting line (without the line end), returns the greeting when complete
+    pub fn process_line(&mut self, line: &str) -> Result<Option<Greeting>, Error> {
+        let Some(api_version) = self.api_version else {
+            self.api_version = Some(parse_greeting(line)?);
+            return Ok(None);
+        };
+        if line == HEADERS_TRANSMISSION_END {
+            return Ok(Some(Greeting {
+                api_version,
+                headers: std::mem::take(&mut self.headers),
+            }));
+        }
+        if let Some((name, value)) = parse_header(line) {
+            self.headers.insert(name, value);
+        } else {
+            trace!(line, "invalid header line, ignored");
+        }
+        Ok(None)
+    }
+}
+
+/// Parses the greeting line, returns the protocol version
+fn parse_greeting(line: &str) -> Result<u8, Error> {
+    let mut sp = line.split('/');
+    if sp.next() != Some(GREETING) {
+        return Err(Error::InvalidData);
+    }
+    sp.next()
+        .ok_or_else(|| {
+            trace!("Unable to parse greetings header value");
+            Error::InvalidData
+        })?
+        .trim()
+        .parse()
+        .map_err(|error| {
+            trace!(%error, "Unable to parse greetings header value");
+            Error::InvalidData
+        })
+}
+
+/// A decoded or an encoded server message
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[non_exhaustive]
+pub struct Message<'a> {
+    /// Message direction
+    pub direction: Direction,
+    /// Message id (requires [`Capability::MessageId`])
+    pub id: Option<u64>,
+    /// Message severity (requires [`Capability::Severity`])
+    pub severity: Option<Severity>,
+    /// Message data, lines of multi-line messages are separated with `\n`
+    pub data: Cow<'a, str>,
+}
+
+impl<'a> Message<'a> {
+    /// Create a new message without attributes
+    pub fn new(direction: Direction, data: impl Into<Cow<'a, str>>) -> Self {
+        Self {
+            direction,
+            id: None,
+            severity: None,
+            data: data.into(),
+        }
+    }
+}
+
+/// Checks if the messages carry the attribute block
+fn has_attributes(capabilities: &BTreeSet<Capability>) -> bool {
+    capabilities.contains(&Capability::MessageId)
+        || capabilities.contains(&Capability::Severity)
+        || capabilities.contains(&Capability::Block)
+}
+
+/// Checks if a continuation line of a multi-line message must be escaped (if blocks are not
+/// negotiated), so it is not taken for a new message. The escaped lines are prefixed with a space.
+fn needs_escape(line: &str) -> bool {
+    let line = line.trim_start_matches(' ');
+    line.starts_with(Direction::ClientToServer.as_str())
+        || line.starts_with(Direction::ServerToClient.as_str())
+}
+
+/// A multi-line block which is being received
+#[derive(Debug)]
+struct PendingBlock {
+    message: Message<'static>,
+    remaining: usize,
+}
+
+/// Server message decoder. Lines without a direction prefix continue the message direction of
+/// the previous line ([`Direction::Last`]), the escaping of such lines is removed.
+#[derive(Default, Debug)]
+pub struct MessageDecoder {
+    last_direction: Option<Direction>,
+    attributes: bool,
+    blocks: bool,
+    block: Option<PendingBlock>,
+}
+
+impl MessageDecoder {
+    /// Create a new decoder
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// Set the negotiated capabilities. Must be called right after the capabilities are
+    /// confirmed by the server.
+    pub fn set_capabilities(&mut self, capabilities: &BTreeSet<Capability>) {
+        self.attributes = has_attributes(capabilities);
+        self.blocks = capabilities.contains(&Capability::Block);
+    }
+    /// Check if the line is a keepalive ping (requires [`Capability::Keepalive`]). Lines of
+    /// multi-line blocks are never considered as pings.
+    pub fn is_ping(&self, line: &str) -> bool {
+        self.block.is_none() && line.trim_end_matches(['\n', '\r']) == PING
+    }
+    /// Decode a single message line (the line end is trimmed). Returns [`Error::InvalidData`] if
+    /// the first line has no direction prefix. The message attributes are stripped, multi-line
+    /// blocks are not reassembled (use [`MessageDecoder::decode_message()`]).
+    pub fn decode_line<'a>(&mut self, line: &'a str) -> Result<(Direction, &'a str), Error> {
+        let (direction, data, _) = self.decode_single(line)?;
+        Ok((direction, data))
+    }
+    /// Decode a message line with its attributes (the line end is trimmed). Multi-line blocks
+    /// are reassembled, `None` is returned until the last line of a block is received.
+    pub fn decode_message<'a>(&mut self, line: &'a str) -> Result<Option<Message<'a>>, Error> {
+        if let Some(block) = self.block.as_mut() {
+            let data = block.message.data.to_mut();
+            data.push('\n');
+            data.push_str(line.trim_end_matches(['\n', '\r']));
+            block.remaining -= 1;
+            if block.remaining > 0 {
+                return Ok(None);
+            }
+            return Ok(self.block.take().map(|b| b.message));
+        }
+        let (direction, data, attrs) = self.decode_single(line)?;
+        let mut message = Message::new(direction, data);
+        let mut lines = 1;
+        if let Some(attrs) = attrs {
+            for (name, value) in attrs.split(',').filter_map(|a| a.split_once('=')) {
+                match name {
+                    ATTR_ID => message.id = value.parse().ok(),
+                    ATTR_SEVERITY => message.severity = value.parse().ok(),
+                    ATTR_LINES => lines = value.parse().unwrap_or(1),
+                    _ => {}
+                }
+            }
+        }
+        if lines > 1 {
+            self.block = Some(PendingBlock {
+                message: Message {
+                    data: Cow::Owned(message.data.into_owned()),
+                    ..message
+                },
+                remaining: lines - 1,
+            });
+            return Ok(None);
+        }
+        Ok(Some(message))
+    }
+    /// Decodes the direction, the data and the raw attribute block of a single line
+    fn decode_single<'a>(
+        &mut self,
+        line: &'a str,
+    ) -> Result<(Direction, &'a str, Option<&'a str>), Error> {
+        let line = line.trim_end_matches(['\n', '\r']);
+        for direction in [Direction::ClientToServer, Direction::ServerToClient] {
+            if let Some(msg) = line.strip_prefix(direction.as_str()) {
+                self.last_direction = Some(direction);
+                if self.attributes {
+                    // the attribute block is `[NAME=VALUE,...]`, unknown attributes are ignored
+                    if let Some((attrs, data)) =
+                        msg.strip_prefix('[').and_then(|v| v.split_once(']'))
+                    {
+                        return Ok((direction, data, Some(attrs)));
+                    }
+                }
+                return Ok((direction, msg, None));
+            }
+        }
+        let direction = self.last_direction.ok_or(Error::InvalidData)?;
+        let line = match line.strip_prefix(' ') {
+            Some(unescaped) if !self.blocks && needs_escape(unescaped) => unescaped,
+            _ => line,
+        };
+        Ok((direction, line, None))
+    }
+}
+
+/// Server message encoder
+#[derive(Default, Debug, Clone)]
+pub struct MessageEncoder {
+    capabilities: BTreeSet<Capability>,
+}
+
+impl MessageEncoder {
+    /// Create a new encoder (no capabilities negotiated)
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// Set the negotiated capabilities
+    pub fn set_capabilities(&mut self, capabilities: BTreeSet<Capability>) {
+        self.capabilities = capabilities;
+    }
+    /// Negotiated capabilities
+    pub fn capabilities(&self) -> &BTreeSet<Capability> {
+        &self.capabilities
+    }
+    /// Encodes a message into the buffer. The attributes are encoded only if the corresponding
+    /// capabilities are negotiated.
+    ///
+    /// Multi-line messages are encoded as the first line, prefixed as usual, followed by the
+    /// rest lines as-is (line ends are normalized to `\n`). If [`Capability::Block`] is
+    /// negotiated, the first line carries the number of the block lines. Otherwise the rest lines,
+    /// which start with a direction prefix (after optional spaces), are escaped with a space.
+    pub fn encode(&self, message: &Message, buf: &mut Vec<u8>) {
+        let mut lines = message.data.lines();
+        let first = lines.next().unwrap_or_default();
+        let line_count = message.data.lines().count();
+        let prefix = prefix(message.direction);
+        buf.reserve(prefix.len() + message.data.len() + line_count.max(1));
+        buf.extend(prefix);
+        if message.direction != Direction::Last && has_attributes(&self.capabilities) {
+            let mut attrs = Vec::new();
+            if let Some(id) = message
+                .id
+                .filter(|_| self.capabilities.contains(&Capability::MessageId))
+            {
+                attrs.push(format!("{}={}", ATTR_ID, id));
+            }
+            if let Some(severity) = message
+                .severity
+                .filter(|_| self.capabilities.contains(&Capability::Severity))
+            {
+                attrs.push(format!("{}={}", ATTR_SEVERITY, severity));
+            }
+            if line_count > 1 && self.capabilities.contains(&Capability::Block) {
+                attrs.push(format!("{}={}", ATTR_LINES, line_count));
+            }
+            buf.push(b'[');
+            buf.extend(attrs.join(",").as_bytes());
+            buf.push(b']');
+        }
+        buf.extend(first.as_bytes());
+        buf.push(b'\n');
+        let blocks = self.capabilities.contains(&Capability::Block);
+        for line in lines {
+            if !blocks && needs_escape(line) {
+                buf.push(b' ');
+            }
+            buf.extend(line.as_bytes());
+            buf.push(b'\n');
+        }
+    }
+}
+
+/// Encodes a server message line into the buffer. Messages with [`Direction::Last`] are encoded
+/// without a prefix.
+pub fn encode_message(direction: Direction, data: &str, buf: &mut Vec<u8>) {
+    MessageEncoder::new().encode(&Message::new(direction, data), buf);
+}
+
+fn prefix(direction: Direction) -> &'static [u8] {
+    if direction == Direction::Last {
+        &[]
+    } else {
+        direction.as_bytes()
+    }
+}
+
+/// [`tokio_util::codec`] implementation of server messages (requires `async` feature). The
+/// greeting must be processed before, e.g. with [`GreetingParser`]. The decoded message
+/// attributes are stripped, multi-line blocks are reassembled. Keepalive pings are not handled,
+/// so [`Capability::Keepalive`] must not be requested.
+#[cfg(feature = "async")]
+#[derive(Default, Debug)]
+pub struct Codec {
+    decoder: MessageDecoder,
+    // the position the next line end search starts from
+    next_index: usize,
+}
+
+#[cfg(feature = "async")]
+impl Codec {
+    /// Create a new codec
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// Set the negotiated capabilities
+    pub fn set_capabilities(&mut self, capabilities: &BTreeSet<Capability>) {
+        self.decoder.set_capabilities(capabilities);
+    }
+}
+
+#[cfg(feature = "async")]
+impl tokio_util::codec::Decoder for Codec {
+    type Item = (Direction, String);
+    type Error = Error;
+
+    fn decode(
+        &mut self,
+        src: &mut tokio_util::bytes::BytesMut,
+    ) -> Result<Option<Self::Item>, Self::Error> {
+        loop {
+            let Some(pos) = src[self.next_index..].iter().position(|&b| b == b'\n') else {
+                self.next_index = src.len();
+                return Ok(None);
+            };
+            let line = src.split_to(self.next_index + pos + 1);
+            self.next_index = 0;
+            let line = std::str::from_utf8(&line).map_err(|_| Error::InvalidData)?;
+            if let Some(message) = self.decoder.decode_message(line)? {
+                return Ok(Some((message.direction, message.data.into_owned())));
+            }
+        }
+    }
+}
+
+#[cfg(feature = "async")]
+impl<T: AsRef<str>> tokio_util::codec::Encoder<(Direction, T)> for Codec {
+    type Error = Error;
+
+    fn encode(
+        &mut self,
+        (direction, data): (Direction, T),
+        dst: &mut tokio_util::bytes::BytesMut,
+    ) -> Result<(), Self::Error> {
+        let mut buf = Vec::new();
+        encode_message(direction, data.as_ref(), &mut buf);
+        dst.extend_from_slice(&buf);
+        Ok(())
+    }
+}
@@ -0,0 +1,365 @@
+use std::{
+    collections::{BTreeSet, VecDeque},
+    sync::{atomic, Arc},
+    time::Instant,
+};
+
+use crate::{
+    protocol::{Capability, Message, Severity},
+    Condvar, Direction, Mutex,
+};
+
+/// What to do when the outgoing queue of a slow client is full
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub enum SlowClientPolicy {
+    /// Drop the new messages (default)
+    #[default]
+    DropNewest,
+    /// Drop the oldest queued messages to make room for the new ones
+    DropOldest,
+    /// Disconnect the client
+    Disconnect,
+    /// Drop the new messages and notify the client with a `N messages dropped` message, which
+    /// is delivered in place of the dropped ones
+    Notify,
+}
+
+/// A message, sent by the server to a client
+#[derive(Clone, Debug)]
+pub(crate) struct OutgoingFrame {
+    pub(crate) direction: Direction,
+    pub(crate) id: u64,
+    pub(crate) severity: Option<Severity>,
+    pub(crate) data: Arc<String>,
+}
+
+impl OutgoingFrame {
+    pub(crate) fn message(&self) -> Message<'_> {
+        let mut message = Message::new(self.direction, self.data.as_str());
+        message.id = Some(self.id);
+        message.severity = self.severity;
+        message
+    }
+    /// The number of lines the frame is encoded into
+    pub(crate) fn line_count(&self) -> usize {
+        self.data.lines().count().max(1)
+    }
+}
+
+/// An item, consumed by the client writer
+pub(crate) enum Outgoing {
+    Frame(OutgoingFrame),
+    /// Confirm the negotiated capabilities and use them for the following frames
+    Capabilities(BTreeSet<Capability>),
+    /// The keepalive ping is due
+    Ping,
+    /// The number of the messages, dropped in place of the item
+    Dropped(u64),
+}
+
+/// The notification which replaces dropped messages
+pub(crate) fn dropped_message(count: u64) -> Message<'static> {
+    let mut message = Message::new(
+        Direction::ServerToClient,
+        format!("{} messages dropped", count),
+    );
+    message.severity = Some(Severity::Warning);
+    message
+}
+
+enum QueueItem {
+    Frame(OutgoingFrame),
+    Dropped(u64),
+}
+
+/// Bounded outgoing queue of a server client. The queue is consumed either by a writer thread
+/// (the synchronous server) or by a writer task (the asynchronous server).
+pub(crate) struct ClientQueue {
+    data: Mutex<QueueData>,
+    data_available: Condvar,
+    #[cfg(feature = "async")]
+    data_available_async: tokio::sync::Notify,
+    capacity: usize,
+    policy: SlowClientPolicy,
+    dropped: atomic::AtomicU64,
+}
+
+struct QueueData {
+    // dropped notifications are not limited by the capacity
+    items: VecDeque<QueueItem>,
+    frames: usize,
+    capabilities: Option<BTreeSet<Capability>>,
+    // set on the first dropped frame, cleared on the next pushed one
+    overflow: bool,
+    closed: bool,
+    // the queue has been closed to disconnect the client
+    disconnected: bool,
+}
+
+impl QueueData {
+    /// Control items have priority over the frames
+    fn pop(&mut self) -> Option<Outgoing> {
+        if let Some(capabilities) = self.capabilities.take() {
+            return Some(Outgoing::Capabilities(capabilities));
+        }
+        match self.items.pop_front()? {
+            QueueItem::Frame(frame) => {
+                self.frames -= 1;
+                Some(Outgoing::Frame(frame))
+            }
+            QueueItem::Dropped(count) => Some(Outgoing::Dropped(count)),
+        }
+    }
+}
+
+impl ClientQueue {
+    pub(crate) fn new(capacity: usize, policy: SlowClientPolicy) -> Self {
+        Self {
+            data: Mutex::new(QueueData {
+                items: VecDeque::with_capacity(capacity),
+                frames: 0,
+                capabilities: None,
+                overflow: false,
+                closed: false,
+                disconnected: false,
+            }),
+            data_available: Condvar::new(),
+            #[cfg(feature = "async")]
+            data_available_async: tokio::sync::Notify::new(),
+            capacity,
+            policy,
+            dropped: atomic::AtomicU64::new(0),
+        }
+    }
+    /// Pushes a frame to the queue, never blocks. If the queue is full, the overflow is handled
+    /// according to the policy and [`rtsc::Error::ChannelFull`] is returned for the first
+    /// dropped frame, [`rtsc::Error::ChannelSkipped`] for the following ones until a frame is
+    /// pushed again. With [`SlowClientPolicy::DropOldest`] the frame is queued in place of the
+    /// oldest one, the error reports the dropped oldest frame.
+    pub(crate) fn try_push(&self, frame: OutgoingFrame) -> Result<(), rtsc::Error> {
+        let result = {
+            let mut data = self.data.lock();
+            if data.closed {
+                return Err(rtsc::Error::ChannelClosed);
+            }
+            if data.frames < self.capacity {
+                data.items.push_back(QueueItem::Frame(frame));
+                data.frames += 1;
+                data.overflow = false;
+                Ok(())
+            } else {
+                self.overflow(&mut data, frame);
+                if std::mem::replace(&mut data.overflow, true) {
+                    Err(rtsc::Error::ChannelSkipped)
+                } else {
+                    Err(rtsc::Error::ChannelFull)
+                }
+            }
+        };
+        self.notify();
+        result
+    }
+    fn overflow(&self, data: &mut QueueData, frame: OutgoingFrame) {
+        let dropped = match self.policy {
+            SlowClientPolicy::DropNewest => 1,
+            SlowClientPolicy::DropOldest => {
+                if let Some(pos) = data
+                    .items
+                    .iter()
+                    .position(|item| matches!(item, QueueItem::Frame(_)))
+                {
+                    data.items.remove(pos);
+                    data.items.push_back(QueueItem::Frame(frame));
+                }
+                1
+            }
+            SlowClientPolicy::Disconnect => {
+                // the queued frames are not delivered, so the writer disconnects the client
+                // immediately
+                let dropped = data.frames + 1;
+                data.items.clear();
+                data.frames = 0;
+                data.closed = true;
+                data.disconnected = true;
+                dropped as u64
+            }
+            SlowClientPolicy::Notify => {
+                if let Some(QueueItem::Dropped(count)) = data.items.back_mut() {
+                    *count += 1;
+                } else {
+                    data.items.push_back(QueueItem::Dropped(1));
+                }
+                1
+            }
+        };
+        self.dropped.fetch_add(dropped, atomic::Ordering::Relaxed);
+    }
+    /// The total number of the messages, dropped because of the queue overflow
+    pub(crate) fn dropped(&self) -> u64 {
+        self.dropped.load(atomic::Ordering::Relaxed)
+    }
+    /// Asks the writer to switch to the negotiated capabilities. The switch is not limited by
+    /// the queue capacity and is processed before the frames which are already queued.
+    pub(crate) fn switch_capabilities(&self, capabilities: BTreeSet<Capability>) {
+        self.data.lock().capabilities = Some(capabilities);
+        self.notify();
+    }
+    /// Closes the queue. The frames which are already in the queue are still delivered to the
+    /// consumer
+    pub(crate) fn close(&self) {
+        self.data.lock().closed = true;
+        self.notify();
+    }
+    /// Closes the queue to disconnect the client. The frames which are already in the queue are
+    /// still delivered to the consumer
+    pub(crate) fn disconnect(&self) {
+        {
+            let mut data = self.data.lock();
+            data.closed = true;
+            data.disconnected = true;
+        }
+        self.notify();
+    }
+    /// Returns `true` if the client has been disconnected by the server
+    pub(crate) fn is_disconnected(&self) -> bool {
+        self.data.lock().disconnected
+    }
+    fn notify(&self) {
+        self.data_available.notify_one();
+        #[cfg(feature = "async")]
+        self.data_available_async.notify_one();
+    }
+    /// Blocks until an item is available, returns `None` if the queue is closed and empty. If
+    /// the ping time is set and reached, returns [`Outgoing::Ping`] before the queued items, so
+    /// the pings are sent on schedule regardless of the traffic.
+    pub(crate) fn pop(&self, ping_at: Option<Instant>) -> Option<Outgoing> {
+        let mut data = self.data.lock();
+        loop {
+            if !data.closed && ping_at.map_or(false, |t| Instant::now() >= t) {
+                return Some(Outgoing::Ping);
+            }
+            if let Some(item) = data.pop() {
+                return Some(item);
+            }
+            if data.closed {
+                return None;
+            }
+            if let Some(ping_at) = ping_at {
+                self.data_available.wait_until(&mut data, ping_at);
+            } else {
+                self.data_available.wait(&mut data);
+            }
+        }
+    }
+    /// Waits until an item is available, returns `None` if the queue is closed and empty
+    #[cfg(feature = "async")]
+    pub(crate) async fn pop_async(&self) -> Option<Outgoing> {
+        loop {
+            // a notification which comes in between is stored as a permit so it is never lost
+            let notified = self.data_available_async.notified();
+            {
+                let mut data = self.data.lock();
+                if let Some(item) = data.pop() {
+                    return Some(item);
+                }
+                if data.closed {
+                    return None;
+                }
+            }
+            notified.await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame(id: u64) -> OutgoingFrame {
+        OutgoingFrame {
+            direction: Direction::ServerToClient,
+            id,
+            severity: None,
+            data: Arc::new(id.to_string()),
+        }
+    }
+
+    /// Pushes the frames, returns the push results
+    fn push(queue: &ClientQueue, ids: std::ops::Range<u64>) -> Vec<Result<(), rtsc::Error>> {
+        ids.map(|id| queue.try_push(frame(id))).collect()
+    }
+
+    /// Closes the queue and pops all the items as the messages the client gets
+    fn drain(queue: &ClientQueue) -> Vec<(String, Option<Severity>)> {
+        queue.close();
+        let mut messages = Vec::new();
+        while let Some(item) = queue.pop(None) {
+            let (data, severity) = match item {
+                Outgoing::Frame(frame) => (frame.data.to_string(), frame.severity),
+                Outgoing::Dropped(count) => {
+                    let message = dropped_message(count);
+                    (message.data.into_owned(), message.severity)
+                }
+                Outgoing::Capabilities(_) | Outgoing::Ping => panic!("unexpected item"),
+            };
+            messages.push((data, severity));
+        }
+        messages
+    }
+
+    fn data(messages: &[(String, Option<Severity>)]) -> Vec<&str> {
+        messages.iter().map(|(data, _)| data.as_str()).collect()
+    }
+
+    const OVERFLOW: [Result<(), rtsc::Error>; 4] = [
+        Ok(()),
+        Ok(()),
+        Err(rtsc::Error::ChannelFull),
+        Err(rtsc::Error::ChannelSkipped),
+    ];
+
+    #[test]
+    fn drop_newest() {
+        let queue = ClientQueue::new(2, SlowClientPolicy::DropNewest);
+        assert_eq!(push(&queue, 0..4), OVERFLOW);
+        assert_eq!(queue.dropped(), 2);
+        assert_eq!(data(&drain(&queue)), ["0", "1"]);
+    }
+
+    #[test]
+    fn drop_oldest() {
+        let queue = ClientQueue::new(2, SlowClientPolicy::DropOldest);
+        assert_eq!(push(&queue, 0..4), OVERFLOW);
+        assert_eq!(queue.dropped(), 2);
+        assert_eq!(data(&drain(&queue)), ["2", "3"]);
+    }
+
+    #[test]
+    fn disconnect() {
+        let queue = ClientQueue::new(2, SlowClientPolicy::Disconnect);
+        assert_eq!(push(&queue, 0..3), OVERFLOW[..3]);
+        assert_eq!(queue.try_push(frame(3)), Err(rtsc::Error::ChannelClosed));
+        // the queued frames are dropped as well
+        assert_eq!(queue.dropped(), 3);
+        assert!(queue.is_disconnected());
+        assert!(queue.pop(None).is_none());
+    }
+
+    #[test]
+    fn notify() {
+        let queue = ClientQueue::new(2, SlowClientPolicy::Notify);
+        assert_eq!(push(&queue, 0..4), OVERFLOW);
+        assert_eq!(queue.dropped(), 2);
+        // the overflow is over as soon as a frame is pushed again
+        assert_eq!(queue.pop(None).map(|_| ()), Some(()));
+        assert_eq!(push(&queue, 4..6), [Ok(()), Err(rtsc::Error::ChannelFull)]);
+        assert_eq!(queue.dropped(), 3);
+        let messages = drain(&queue);
+        assert_eq!(
+            data(&messages),
+            ["1", "2 messages dropped", "4", "1 messages dropped"]
+        );
+        assert_eq!(messages[1].1, Some(Severity::Warning));
+        assert!(!queue.is_disconnected());
+    }
+}
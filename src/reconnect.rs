@@ -0,0 +1,235 @@
+use std::{
+    net::{SocketAddr, ToSocketAddrs},
+    sync::{atomic, Arc},
+    thread,
+    time::Duration,
+};
+
+use crate::{Condvar, Mutex, RawMutex};
+use rtsc::channel::{Receiver, Sender};
+use tracing::trace;
+
+use crate::{client::FrameReceiver, Client, ConnectionOptions, Direction, Error};
+
+const DEFAULT_INITIAL_DELAY: Duration = Duration::from_millis(500);
+const DEFAULT_MAX_DELAY: Duration = Duration::from_secs(30);
+// how often a full event queue is checked while the client instances may be dropped
+const EVENT_QUEUE_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Reconnecting client event
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum ClientEvent {
+    /// Connected (or reconnected) to the server
+    Connected,
+    /// The connection is lost, the client is going to reconnect
+    Disconnected,
+    /// A message, received from the server
+    Frame(Direction, String),
+}
+
+pub type EventReceiver = Receiver<ClientEvent, RawMutex, Condvar>;
+type EventSender = Sender<ClientEvent, RawMutex, Condvar>;
+
+/// Reconnecting client options
+#[derive(Clone)]
+pub struct ReconnectOptions {
+    pub(crate) connection_options: ConnectionOptions,
+    pub(crate) initial_delay: Duration,
+    pub(crate) max_delay: Duration,
+    pub(crate) on_connect: Vec<String>,
+}
+
+impl Default for ReconnectOptions {
+    fn default() -> Self {
+        Self {
+            connection_options: ConnectionOptions::default(),
+            initial_delay: DEFAULT_INITIAL_DELAY,
+            max_delay: DEFAULT_MAX_DELAY,
+            on_connect: Vec::new(),
+        }
+    }
+}
+
+impl ReconnectOptions {
+    /// Create a new reconnect options instance
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// Set the connection options
+    pub fn connection_options(mut self, options: ConnectionOptions) -> Self {
+        self.connection_options = options;
+        self
+    }
+    /// Set the delay before the first reconnect attempt (default: 500ms). The delay is doubled
+    /// after each failed attempt and is reset after a successful one.
+    pub fn initial_delay(mut self, delay: Duration) -> Self {
+        self.initial_delay = delay;
+        self
+    }
+    /// Set the maximum delay between reconnect attempts (default: 30 seconds)
+    pub fn max_delay(mut self, delay: Duration) -> Self {
+        self.max_delay = delay;
+        self
+    }
+    /// Add a line (e.g. a login or a subscribe command) which is sent to the server after each
+    /// successful connection
+    pub fn on_connect(mut self, line: impl ToString) -> Self {
+        self.on_connect.push(line.to_string());
+        self
+    }
+    pub(crate) fn next_delay(&self, delay: Duration) -> Duration {
+        (delay * 2).min(self.max_delay)
+    }
+}
+
+/// Client instance which automatically reconnects to the server with exponential backoff
+///
+/// Frames and connection state changes are delivered as [`ClientEvent`]s. The background
+/// reconnect thread is stopped when all the client instances or the event receiver are
+/// dropped.
+#[derive(Clone)]
+pub struct ReconnectingClient {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    shared: Arc<Shared>,
+    worker: thread::Thread,
+}
+
+struct Shared {
+    client: Mutex<Option<Client>>,
+    active: atomic::AtomicBool,
+}
+
+impl ReconnectingClient {
+    /// Create a client instance and start connecting to the server in the background
+    pub fn connect(
+        addr: impl ToSocketAddrs,
+        options: ReconnectOptions,
+    ) -> Result<(Self, EventReceiver), Error> {
+        let addrs: Vec<SocketAddr> = addr.to_socket_addrs()?.collect();
+        if addrs.is_empty() {
+            return Err(Error::InvalidAddress);
+        }
+        let (tx, rx) = rtsc::channel::bounded(options.connection_options.incoming_queue_size);
+        let shared = Arc::new(Shared {
+            client: <_>::default(),
+            active: atomic::AtomicBool::new(true),
+        });
+        let shared_c = shared.clone();
+        let worker = thread::spawn(move || run(&shared_c, &addrs, &options, &tx));
+        Ok((
+            Self {
+                inner: Inner {
+                    shared,
+                    worker: worker.thread().clone(),
+                }
+                .into(),
+            },
+            rx,
+        ))
+    }
+    /// Send a message to the server, fails if the client is currently disconnected
+    pub fn try_send(&self, data: impl ToString) -> Result<(), Error> {
+        self.inner
+            .shared
+            .client
+            .lock()
+            .as_ref()
+            .ok_or(Error::NotConnected)?
+            .try_send(data)
+    }
+    /// Check if the client is currently connected
+    pub fn is_connected(&self) -> bool {
+        self.inner
+            .shared
+            .client
+            .lock()
+            .as_ref()
+            .map_or(false, Client::is_connected)
+    }
+}
+
+impl Drop for Inner {
+    fn drop(&mut self) {
+        self.shared.active.store(false, atomic::Ordering::Relaxed);
+        // dropping the client closes the connection
+        self.shared.client.lock().take();
+        self.worker.unpark();
+    }
+}
+
+fn run(shared: &Shared, addrs: &[SocketAddr], options: &ReconnectOptions, tx: &EventSender) {
+    let mut delay = options.initial_delay;
+    while shared.active.load(atomic::Ordering::Relaxed) {
+        match Client::connect_with_options(addrs, &options.connection_options) {
+            Ok((client, rx)) => {
+                delay = options.initial_delay;
+                if !handle_connection(shared, client, rx, options, tx) {
+                    break;
+                }
+            }
+            Err(error) => {
+                trace!(%error, "unable to connect");
+            }
+        }
+        trace!(?delay, "reconnecting");
+        thread::park_timeout(delay);
+        delay = options.next_delay(delay);
+    }
+    shared.client.lock().take();
+}
+
+/// Returns false if the client must not reconnect
+fn handle_connection(
+    shared: &Shared,
+    client: Client,
+    rx: FrameReceiver,
+    options: &ReconnectOptions,
+    tx: &EventSender,
+) -> bool {
+    for line in &options.on_connect {
+        if let Err(error) = client.try_send(line) {
+            trace!(%error, "unable to send on-connect data");
+            return true;
+        }
+    }
+    {
+        let mut current = shared.client.lock();
+        // the client instances may have been dropped while connecting
+        if !shared.active.load(atomic::Ordering::Relaxed) {
+            return false;
+        }
+        *current = Some(client);
+    }
+    if !send_event(shared, tx, &ClientEvent::Connected) {
+        return false;
+    }
+    for (direction, data) in rx {
+        if !send_event(shared, tx, &ClientEvent::Frame(direction, data)) {
+            return false;
+        }
+    }
+    shared.client.lock().take();
+    shared.active.load(atomic::Ordering::Relaxed)
+        && send_event(shared, tx, &ClientEvent::Disconnected)
+}
+
+/// Sends the event, waiting while the event queue is full. Returns false if the event receiver or
+/// all the client instances are dropped
+fn send_event(shared: &Shared, tx: &EventSender, event: &ClientEvent) -> bool {
+    loop {
+        match tx.try_send(event.clone()) {
+            Ok(()) => return true,
+            Err(rtsc::Error::ChannelFull) => {
+                // the worker is unparked when the client instances are dropped
+                thread::park_timeout(EVENT_QUEUE_POLL_INTERVAL);
+                if !shared.active.load(atomic::Ordering::Relaxed) {
+                    return false;
+                }
+            }
+            Err(_) => return false,
+        }
+    }
+}
@@ -0,0 +1,153 @@
+use std::{
+    net::{SocketAddr, ToSocketAddrs},
+    sync::{atomic, Arc},
+};
+
+use parking_lot_rt::Mutex as SyncMutex;
+use rtsc::channel_async::{Receiver, Sender};
+use tokio::{sync::Mutex, task::JoinHandle};
+use tracing::trace;
+
+use crate::{ClientAsync, ClientEvent, Direction, Error, ReconnectOptions};
+
+/// Asynchronous client instance which automatically reconnects to the server with exponential
+/// backoff
+///
+/// Frames and connection state changes are delivered as [`ClientEvent`]s. The background
+/// reconnect task is aborted when all the client instances are dropped and is stopped when the
+/// event receiver is dropped.
+#[derive(Clone)]
+pub struct ReconnectingClientAsync {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    shared: Arc<Shared>,
+    worker_fut: SyncMutex<JoinHandle<()>>,
+}
+
+struct Shared {
+    client: Mutex<Option<ClientAsync>>,
+    active: atomic::AtomicBool,
+}
+
+impl ReconnectingClientAsync {
+    /// Create a client instance and start connecting to the server in the background
+    pub fn connect(
+        addr: impl ToSocketAddrs,
+        options: ReconnectOptions,
+    ) -> Result<(Self, Receiver<ClientEvent>), Error> {
+        let addrs: Vec<SocketAddr> = addr.to_socket_addrs()?.collect();
+        if addrs.is_empty() {
+            return Err(Error::InvalidAddress);
+        }
+        let (tx, rx) = rtsc::channel_async::bounded(options.connection_options.incoming_queue_size);
+        let shared = Arc::new(Shared {
+            client: <_>::default(),
+            active: atomic::AtomicBool::new(true),
+        });
+        let worker_fut = tokio::spawn(run(shared.clone(), addrs, options, tx));
+        Ok((
+            Self {
+                inner: Inner {
+                    shared,
+                    worker_fut: SyncMutex::new(worker_fut),
+                }
+                .into(),
+            },
+            rx,
+        ))
+    }
+    /// Send a message to the server, fails if the client is currently disconnected
+    pub async fn try_send(&self, data: impl ToString) -> Result<(), Error> {
+        self.inner
+            .shared
+            .client
+            .lock()
+            .await
+            .as_ref()
+            .ok_or(Error::NotConnected)?
+            .try_send(data)
+            .await
+    }
+    /// Check if the client is currently connected
+    pub async fn is_connected(&self) -> bool {
+        self.inner
+            .shared
+            .client
+            .lock()
+            .await
+            .as_ref()
+            .map_or(false, ClientAsync::is_connected)
+    }
+}
+
+impl Drop for Inner {
+    fn drop(&mut self) {
+        // the task is aborted at the next await point only, so a client, which is being
+        // connected at the moment, must not be stored
+        self.shared.active.store(false, atomic::Ordering::Relaxed);
+        self.worker_fut.lock().abort();
+    }
+}
+
+async fn run(
+    shared: Arc<Shared>,
+    addrs: Vec<SocketAddr>,
+    options: ReconnectOptions,
+    tx: Sender<ClientEvent>,
+) {
+    let mut delay = options.initial_delay;
+    while shared.active.load(atomic::Ordering::Relaxed) {
+        match ClientAsync::connect_with_options(&addrs[..], &options.connection_options).await {
+            Ok((client, rx)) => {
+                delay = options.initial_delay;
+                if !handle_connection(&shared, client, rx, &options, &tx).await {
+                    break;
+                }
+            }
+            Err(error) => {
+                trace!(%error, "unable to connect");
+            }
+        }
+        trace!(?delay, "reconnecting");
+        tokio::time::sleep(delay).await;
+        delay = options.next_delay(delay);
+    }
+    shared.client.lock().await.take();
+}
+
+/// Returns false if the client must not reconnect
+async fn handle_connection(
+    shared: &Shared,
+    client: ClientAsync,
+    rx: Receiver<(Direction, String)>,
+    options: &ReconnectOptions,
+    tx: &Sender<ClientEvent>,
+) -> bool {
+    for line in &options.on_connect {
+        if let Err(error) = client.try_send(line).await {
+            trace!(%error, "unable to send on-connect data");
+            return true;
+        }
+    }
+    {
+        let mut current = shared.client.lock().await;
+        // the client instances may have been dropped while connecting
+        if !shared.active.load(atomic::Ordering::Relaxed) {
+            return false;
+        }
+        *current = Some(client);
+    }
+    if tx.send(ClientEvent::Connected).await.is_err() {
+        return false;
+    }
+    while let Ok((direction, data)) = rx.recv().await {
+        if tx.send(ClientEvent::Frame(direction, data)).await.is_err() {
+            return false;
+        }
+    }
+    shared.client.lock().await.take();
+    shared.active.load(atomic::Ordering::Relaxed)
+        && tx.send(ClientEvent::Disconnected).await.is_ok()
+}
@@ -0,0 +1,527 @@
+//! Command router for the server data channel
+//!
+//! The router splits incoming frames into a command name and whitespace-separated arguments,
+//! calls the registered handlers and automatically replies to the sender on `help`, unknown
+//! commands and argument errors.
+//!
+//! ```rust,no_run
+//! use rflow::router::{Command, Router};
+//!
+//! fn main() -> Result<(), Box<dyn std::error::Error>> {
+//!     let data_channel = rflow::take_data_channel()?;
+//!     std::thread::spawn(move || {
+//!         rflow::serve("127.0.0.1:4001").expect("failed to start server");
+//!     });
+//!     Router::new(rflow::default_server())
+//!         .command(
+//!             Command::new("start", |ctx| {
+//!                 ctx.reply("started")?;
+//!                 Ok(())
+//!             })
+//!             .help("start the motor"),
+//!         )
+//!         .command(
+//!             Command::new("speed", |ctx| {
+//!                 let rpm: u32 = ctx.arg("rpm")?;
+//!                 ctx.broadcast(format!("speed set to {} by client #{}", rpm, ctx.client_id()));
+//!                 Ok(())
+//!             })
+//!             .help("set the motor speed")
+//!             .arg("rpm", "speed in RPM"),
+//!         )
+//!         .run(data_channel);
+//!     Ok(())
+//! }
+//! ```
+use std::{collections::BTreeMap, fmt::Write as _, str::FromStr};
+
+use crate::{server::FrameReceiver, IncomingFrame, Role, Server};
+
+const HELP_COMMAND: &str = "help";
+
+type Handler = Box<dyn FnMut(&Context<'_>) -> Result<(), CommandError> + Send>;
+
+/// Command router
+pub struct Router {
+    server: Server,
+    commands: BTreeMap<String, Command>,
+}
+
+impl Router {
+    /// Create a new router for the specified server
+    pub fn new(server: Server) -> Self {
+        Self {
+            server,
+            commands: <_>::default(),
+        }
+    }
+    /// Register a command
+    pub fn command(mut self, command: Command) -> Self {
+        self.commands.insert(command.name.clone(), command);
+        self
+    }
+    /// Process frames from the data channel until it is closed
+    pub fn run(mut self, data_channel: FrameReceiver) {
+        for frame in data_channel {
+            self.dispatch(&frame);
+        }
+    }
+    /// Process a single frame
+    pub fn dispatch(&mut self, frame: &IncomingFrame) {
+        let mut sp = frame.split_whitespace();
+        let Some(name) = sp.next() else {
+            return;
+        };
+        let args: Vec<&str> = sp.collect();
+        if let Some(command) = self.commands.get_mut(name) {
+            let usage = command.usage();
+            let Command {
+                args: arg_specs,
+                handler,
+                role,
+                ..
+            } = command;
+            let result = check_role(*role, frame)
+                .and_then(|()| {
+                    check_args(
+                        arg_specs.iter().map(|a| (a.name.as_str(), a.required)),
+                        &args,
+                    )
+                })
+                .and_then(|()| {
+                    handler(&Context {
+                        server: &self.server,
+                        frame,
+                        arg_specs,
+                        args,
+                    })
+                });
+            if let Err(error) = result {
+                self.server
+                    .reply(frame, error_reply(&error, Some(&usage)))
+                    .ok();
+            }
+        } else if name == HELP_COMMAND {
+            self.server
+                .reply(frame, self.help(args.first().copied()))
+                .ok();
+        } else {
+            self.server
+                .reply(
+                    frame,
+                    error_reply(&CommandError::Unknown(name.to_owned()), None),
+                )
+                .ok();
+        }
+    }
+    fn help(&self, command: Option<&str>) -> String {
+        if let Some(name) = command {
+            let Some(command) = self.commands.get(name) else {
+                return format!("error: {}", CommandError::Unknown(name.to_owned()));
+            };
+            format_command_help(
+                &command.usage(),
+                &command.help,
+                command
+                    .args
+                    .iter()
+                    .map(|a| (a.name.as_str(), a.help.as_str())),
+            )
+        } else {
+            let usages: Vec<String> = self.commands.values().map(Command::usage).collect();
+            format_help(
+                usages
+                    .iter()
+                    .zip(self.commands.values())
+                    .map(|(usage, command)| (usage.as_str(), command.help.as_str())),
+            )
+        }
+    }
+}
+
+fn check_role(required: Option<Role>, frame: &IncomingFrame) -> Result<(), CommandError> {
+    if let (Some(required), Some(role)) = (required, frame.role()) {
+        if role < required {
+            return Err(CommandError::PermissionDenied);
+        }
+    }
+    Ok(())
+}
+
+fn format_help<'a>(commands: impl IntoIterator<Item = (&'a str, &'a str)>) -> String {
+    let mut help = format!("commands: {} [COMMAND]", HELP_COMMAND);
+    for (usage, command_help) in commands {
+        write!(help, "; {}", usage).ok();
+        if !command_help.is_empty() {
+            write!(help, " - {}", command_help).ok();
+        }
+    }
+    help
+}
+
+fn format_command_help<'a>(
+    usage: &str,
+    command_help: &str,
+    args: impl IntoIterator<Item = (&'a str, &'a str)>,
+) -> String {
+    let mut help = usage.to_owned();
+    if !command_help.is_empty() {
+        write!(help, " - {}", command_help).ok();
+    }
+    for (name, arg_help) in args {
+        write!(help, "; {}: {}", name, arg_help).ok();
+    }
+    help
+}
+
+fn error_reply(error: &CommandError, usage: Option<&str>) -> String {
+    let mut msg = format!("error: {}", error);
+    if let CommandError::Unknown(_) = error {
+        write!(msg, ", type \"{}\" for the list of commands", HELP_COMMAND).ok();
+    } else if let Some(usage) = usage.filter(|_| error.is_usage_error()) {
+        write!(msg, ", usage: {}", usage).ok();
+    }
+    msg
+}
+
+fn check_args<'a, I>(specs: I, args: &[&str]) -> Result<(), CommandError>
+where
+    I: IntoIterator<Item = (&'a str, bool)>,
+    I::IntoIter: ExactSizeIterator,
+{
+    let specs = specs.into_iter();
+    if args.len() > specs.len() {
+        return Err(CommandError::TooManyArguments);
+    }
+    if let Some((missing, _)) = specs.skip(args.len()).find(|(_, r)| *r) {
+        return Err(CommandError::MissingArgument(missing.to_owned()));
+    }
+    Ok(())
+}
+
+fn parse_arg<T: FromStr>(args: &[&str], pos: usize, name: &str) -> Result<Option<T>, CommandError> {
+    args.get(pos)
+        .map(|value| {
+            value.parse().map_err(|_| CommandError::InvalidArgument {
+                name: name.to_owned(),
+                value: (*value).to_owned(),
+            })
+        })
+        .transpose()
+}
+
+struct Arg {
+    name: String,
+    help: String,
+    required: bool,
+}
+
+/// Router command
+pub struct Command {
+    name: String,
+    help: String,
+    args: Vec<Arg>,
+    role: Option<Role>,
+    handler: Handler,
+}
+
+impl Command {
+    /// Create a new command with the specified name and handler
+    pub fn new<F>(name: impl ToString, handler: F) -> Self
+    where
+        F: FnMut(&Context<'_>) -> Result<(), CommandError> + Send + 'static,
+    {
+        Self {
+            name: name.to_string(),
+            help: String::new(),
+            args: Vec::new(),
+            role: None,
+            handler: Box::new(handler),
+        }
+    }
+    /// Set the command help text
+    pub fn help(mut self, help: impl ToString) -> Self {
+        self.help = help.to_string();
+        self
+    }
+    /// Add a required argument. Required arguments must be added before optional ones
+    ///
+    /// # Panics
+    ///
+    /// Panics if an optional argument has been already added
+    pub fn arg(mut self, name: impl ToString, help: impl ToString) -> Self {
+        assert!(
+            self.args.iter().all(|a| a.required),
+            "command {}: required argument {} is added after an optional one",
+            self.name,
+            name.to_string()
+        );
+        self.args.push(Arg {
+            name: name.to_string(),
+            help: help.to_string(),
+            required: true,
+        });
+        self
+    }
+    /// Add an optional argument
+    pub fn optional_arg(mut self, name: impl ToString, help: impl ToString) -> Self {
+        self.args.push(Arg {
+            name: name.to_string(),
+            help: help.to_string(),
+            required: false,
+        });
+        self
+    }
+    /// Set the minimum client role required to run the command (ignored if the server does not
+    /// require authentication)
+    pub fn role(mut self, role: Role) -> Self {
+        self.role = Some(role);
+        self
+    }
+    fn usage(&self) -> String {
+        let mut usage = self.name.clone();
+        for arg in &self.args {
+            if arg.required {
+                write!(usage, " <{}>", arg.name).ok();
+            } else {
+                write!(usage, " [{}]", arg.name).ok();
+            }
+        }
+        usage
+    }
+}
+
+/// Command handler context
+pub struct Context<'a> {
+    server: &'a Server,
+    frame: &'a IncomingFrame,
+    arg_specs: &'a [Arg],
+    args: Vec<&'a str>,
+}
+
+impl Context<'_> {
+    /// The frame the command has been received in
+    #[inline]
+    pub fn frame(&self) -> &IncomingFrame {
+        self.frame
+    }
+    /// Sending client id
+    #[inline]
+    pub fn client_id(&self) -> usize {
+        self.frame.client_id()
+    }
+    /// The server instance
+    #[inline]
+    pub fn server(&self) -> &Server {
+        self.server
+    }
+    /// Raw command arguments
+    #[inline]
+    pub fn args(&self) -> &[&str] {
+        &self.args
+    }
+    /// Get and parse a required argument
+    pub fn arg<T: FromStr>(&self, name: &str) -> Result<T, CommandError> {
+        self.optional_arg(name)?
+            .ok_or_else(|| CommandError::MissingArgument(name.to_owned()))
+    }
+    /// Get and parse an optional argument
+    pub fn optional_arg<T: FromStr>(&self, name: &str) -> Result<Option<T>, CommandError> {
+        let pos = self
+            .arg_specs
+            .iter()
+            .position(|a| a.name == name)
+            .ok_or_else(|| CommandError::Failed(format!("argument {} is not defined", name)))?;
+        parse_arg(&self.args, pos, name)
+    }
+    /// Reply to the sending client
+    pub fn reply(&self, data: impl ToString) -> Result<(), crate::Error> {
+        self.server.reply(self.frame, data)
+    }
+    /// Send a message to all clients
+    pub fn broadcast(&self, data: impl ToString) {
+        self.server.send(data);
+    }
+}
+
+/// Command errors, reported back to the sending client
+#[derive(thiserror::Error, Debug)]
+pub enum CommandError {
+    /// Unknown command
+    #[error("unknown command: {0}")]
+    Unknown(String),
+    /// A required argument is missing
+    #[error("missing argument: {0}")]
+    MissingArgument(String),
+    /// Too many arguments
+    #[error("too many arguments")]
+    TooManyArguments,
+    /// Unable to parse an argument
+    #[error("invalid value for {name}: {value}")]
+    InvalidArgument {
+        /// Argument name
+        name: String,
+        /// Argument value
+        value: String,
+    },
+    /// The client role is not sufficient to run the command
+    #[error("permission denied")]
+    PermissionDenied,
+    /// Command failed
+    #[error("{0}")]
+    Failed(String),
+}
+
+impl CommandError {
+    /// Create a command failure error from any displayable value
+    pub fn failed(e: impl std::fmt::Display) -> Self {
+        Self::Failed(e.to_string())
+    }
+    fn is_usage_error(&self) -> bool {
+        matches!(
+            self,
+            Self::MissingArgument(_) | Self::TooManyArguments | Self::InvalidArgument { .. }
+        )
+    }
+}
+
+impl From<crate::Error> for CommandError {
+    fn from(e: crate::Error) -> Self {
+        Self::failed(e)
+    }
+}
+
+/// Typed commands, parsed from incoming frames
+///
+/// The trait is usually implemented with `#[derive(RflowCommand)]` (requires `derive` crate
+/// feature) for enums. Each enum variant is a command, the variant name is converted to
+/// kebab-case (`SetSpeed` becomes `set-speed`), the variant fields are positional arguments
+/// (`Option` fields are optional). Doc comments of the variants are used as the command help.
+/// The attribute `#[rflow(name = "...", help = "...")]` overrides the variant name and help.
+///
+/// ```rust,no_run
+/// # #[cfg(feature = "derive")] {
+/// use rflow::RflowCommand;
+///
+/// #[derive(RflowCommand)]
+/// enum Cmd {
+///     /// start the motor
+///     Start,
+///     /// stop the motor
+///     Stop,
+///     /// set the motor speed
+///     SetSpeed { rpm: u32, ramp: Option<u32> },
+/// }
+///
+/// let server = rflow::default_server();
+/// for frame in server.take_data_channel().unwrap() {
+///     match Cmd::from_frame(&server, &frame) {
+///         Some(Cmd::Start) => { /* ... */ }
+///         Some(Cmd::Stop) => { /* ... */ }
+///         Some(Cmd::SetSpeed { rpm, ramp }) => { /* ... */ }
+///         None => {}
+///     }
+/// }
+/// # }
+/// ```
+pub trait RflowCommand: Sized {
+    /// Parse a command from a line
+    fn parse(line: &str) -> Result<Self, CommandError>;
+    /// Available commands
+    fn commands() -> &'static [CommandInfo];
+    /// Help text with the list of available commands
+    fn help() -> String {
+        format_help(Self::commands().iter().map(|c| (c.usage, c.help)))
+    }
+    /// Parse a command from an incoming frame. Empty frames are ignored, `help` requests and
+    /// parse errors are replied back to the sender, in all these cases `None` is returned.
+    fn from_frame(server: &Server, frame: &IncomingFrame) -> Option<Self> {
+        let mut sp = frame.split_whitespace();
+        let name = sp.next()?;
+        let info = Self::commands().iter().find(|c| c.name == name);
+        if name == HELP_COMMAND && info.is_none() {
+            let help = if let Some(command) = sp.next() {
+                Self::commands()
+                    .iter()
+                    .find(|c| c.name == command)
+                    .map_or_else(
+                        || format!("error: {}", CommandError::Unknown(command.to_owned())),
+                        |c| format_command_help(c.usage, c.help, std::iter::empty()),
+                    )
+            } else {
+                Self::help()
+            };
+            server.reply(frame, help).ok();
+            return None;
+        }
+        match Self::parse(frame) {
+            Ok(command) => Some(command),
+            Err(error) => {
+                server
+                    .reply(frame, error_reply(&error, info.map(|c| c.usage)))
+                    .ok();
+                None
+            }
+        }
+    }
+}
+
+/// Typed command description
+#[derive(Debug, Clone, Copy)]
+pub struct CommandInfo {
+    /// Command name
+    pub name: &'static str,
+    /// Command usage string
+    pub usage: &'static str,
+    /// Command help text
+    pub help: &'static str,
+}
+
+#[doc(hidden)]
+pub mod __private {
+    use std::str::FromStr;
+
+    use super::CommandError;
+
+    pub fn check_args(args: &[&str], specs: &[(&str, bool)]) -> Result<(), CommandError> {
+        super::check_args(specs.iter().copied(), args)
+    }
+
+    pub fn parse_arg<T: FromStr>(args: &[&str], pos: usize, name: &str) -> Result<T, CommandError> {
+        super::parse_arg(args, pos, name)?
+            .ok_or_else(|| CommandError::MissingArgument(name.to_owned()))
+    }
+
+    pub fn parse_optional_arg<T: FromStr>(
+        args: &[&str],
+        pos: usize,
+        name: &str,
+    ) -> Result<Option<T>, CommandError> {
+        super::parse_arg(args, pos, name)
+    }
+}
+
+/// Invalid `#[derive(RflowCommand)]` input must not compile
+///
+/// ```compile_fail
+/// #[derive(rflow::RflowCommand)]
+/// enum Cmd {
+///     SetSpeed { ramp: Option<u32>, rpm: u32 },
+/// }
+/// ```
+///
+/// ```compile_fail
+/// #[derive(rflow::RflowCommand)]
+/// enum Cmd {
+///     Start,
+///     #[rflow(name = "start")]
+///     Run,
+/// }
+/// ```
+///
+/// ```compile_fail
+/// #[derive(rflow::RflowCommand)]
+/// struct Cmd;
+/// ```
+#[cfg(all(doctest, feature = "derive"))]
+struct DeriveCompileFail;
@@ -1,10 +1,20 @@
 use std::{
-    collections::BTreeMap,
-    io::{BufRead as _, BufReader, Write},
-    net::{Shutdown, TcpListener, TcpStream, ToSocketAddrs},
+    collections::{BTreeMap, BTreeSet, VecDeque},
+    fmt::{self, Write as _},
+    fs,
+    io::{self, BufReader, Read, Write},
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr, TcpListener, TcpStream, ToSocketAddrs},
+    ops::Deref,
+    path::Path,
     sync::{atomic, Arc},
     thread,
-    time::Duration,
+    time::{Duration, Instant, SystemTime},
+};
+
+#[cfg(unix)]
+use std::{
+    os::unix::net::{UnixListener, UnixStream},
+    path::PathBuf,
 };
 
 use crate::{Condvar, Mutex, RawMutex};
@@ -12,22 +22,200 @@ use rtsc::{
     channel::{self, Receiver, Sender},
     semaphore::Semaphore,
 };
+use socket2::{SockRef, TcpKeepalive};
 use tracing::{trace, warn};
 
 use crate::{
-    Direction, Error, API_VERSION, DEFAULT_INCOMING_QUEUE_SIZE, DEFAULT_OUTGOING_QUEUE_SIZE,
-    GREETING, HEADERS_TRANSMISSION_END,
+    auth::{
+        AuthMethod, Authenticator, Credentials, Identity, Role, AUTH_ERR, AUTH_FAILED_MESSAGE,
+        AUTH_OK, PERMISSION_DENIED_MESSAGE,
+    },
+    call::{format_call, split_call_id},
+    client,
+    limit::{LineLimit, LineReader, LongLinePolicy, RateLimit, TokenBucket},
+    pipe,
+    protocol::{
+        format_capabilities, format_capabilities_ack, is_hello, Capability, Greeting,
+        GreetingParser, MessageEncoder, Severity, HEADER_CAPABILITIES, HEADER_KEEPALIVE,
+        KEEPALIVE_MISSES, PING, PONG, RESERVED_HEADERS,
+    },
+    queue::{dropped_message, ClientQueue, Outgoing, OutgoingFrame, SlowClientPolicy},
+    stream::{ClientAddr, Stream},
+    transport::IoStream,
+    Client, ConnectionOptions, Direction, Error, Transport, API_VERSION,
+    DEFAULT_INCOMING_QUEUE_SIZE, DEFAULT_OUTGOING_QUEUE_SIZE, GREETING, HEADERS_TRANSMISSION_END,
+    HEADER_AUTH, HEADER_HISTORY,
 };
 
 const DEFAULT_MAX_CLIENTS: usize = 16;
 
+pub(crate) const GOODBYE_MESSAGE: &str = "server shutdown";
+
+pub(crate) const EVENT_QUEUE_SIZE: usize = 128;
+
+const SERVER_BUSY_MESSAGE: &str = "server busy";
+const RATE_LIMIT_MESSAGE: &str = "rate limit exceeded";
+
+/// What to do with the messages from clients when the data channel is full. Messages are echoed
+/// to the clients after they are accepted by the data channel, so a fast reply may precede the
+/// echo.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub enum IncomingPolicy {
+    /// Block the client reader until the application consumes the data channel (default)
+    #[default]
+    Block,
+    /// Drop the message and reply `server busy` to the sender. Dropped messages are not echoed
+    /// to the clients.
+    Reject,
+}
+
 /// Server instance
 #[derive(Clone)]
 pub struct Server {
     inner: Arc<Inner>,
 }
 
-pub type FrameReceiver = Receiver<Arc<String>, RawMutex, Condvar>;
+pub type FrameReceiver = Receiver<IncomingFrame, RawMutex, Condvar>;
+
+pub type EventReceiver = Receiver<ServerEvent, RawMutex, Condvar>;
+
+/// Server event
+#[derive(Clone, Debug)]
+pub enum ServerEvent {
+    /// A client has connected (before authentication)
+    Connected(usize, ClientAddr),
+    /// A client has been authenticated
+    Authenticated(usize, Arc<Identity>),
+    /// A client has been disconnected
+    Disconnected(usize, DisconnectReason),
+    /// A client outgoing queue is full, messages are being dropped. The event is reported once
+    /// until the queue has room again
+    QueueOverflow(usize),
+    /// A connection has been rejected (authentication or connection setup failure)
+    Rejected(ClientAddr, String),
+}
+
+/// The reason a client has been disconnected
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum DisconnectReason {
+    /// The client has closed the connection
+    Closed,
+    /// The client has failed to authenticate
+    AuthFailed,
+    /// The client has sent nothing within the idle or the keepalive timeout
+    Timeout,
+    /// The client has been disconnected by the server (shutdown or slow client)
+    Server,
+    /// A connection or protocol error
+    Error(String),
+}
+
+type EventHandler = Arc<dyn Fn(ServerEvent) + Send + Sync>;
+
+/// A frame, received by the server from a client
+#[derive(Clone, Debug)]
+pub struct IncomingFrame {
+    client_id: usize,
+    addr: ClientAddr,
+    time: SystemTime,
+    data: Arc<String>,
+    call_id: Option<u64>,
+    identity: Option<Arc<Identity>>,
+}
+
+impl IncomingFrame {
+    pub(crate) fn new(
+        client_id: usize,
+        addr: ClientAddr,
+        data: Arc<String>,
+        call_id: Option<u64>,
+        identity: Option<Arc<Identity>>,
+    ) -> Self {
+        Self {
+            client_id,
+            addr,
+            time: SystemTime::now(),
+            data,
+            call_id,
+            identity,
+        }
+    }
+    /// Sending client id (unique for the server instance)
+    #[inline]
+    pub fn client_id(&self) -> usize {
+        self.client_id
+    }
+    /// Sending client address
+    #[inline]
+    pub fn addr(&self) -> ClientAddr {
+        self.addr
+    }
+    /// The moment the frame has been received
+    #[inline]
+    pub fn time(&self) -> SystemTime {
+        self.time
+    }
+    /// Frame data
+    #[inline]
+    pub fn data(&self) -> &str {
+        &self.data
+    }
+    /// Request id if the frame has been sent by a client call, replies to the frame are tagged
+    /// with it automatically
+    #[inline]
+    pub fn call_id(&self) -> Option<u64> {
+        self.call_id
+    }
+    /// Authenticated user name (`None` if authentication is not enabled)
+    #[inline]
+    pub fn user(&self) -> Option<&str> {
+        self.identity.as_ref().map(|i| i.user())
+    }
+    /// Authenticated user role (`None` if authentication is not enabled)
+    #[inline]
+    pub fn role(&self) -> Option<Role> {
+        self.identity.as_ref().map(|i| i.role())
+    }
+}
+
+impl Deref for IncomingFrame {
+    type Target = str;
+    fn deref(&self) -> &Self::Target {
+        &self.data
+    }
+}
+
+impl fmt::Display for IncomingFrame {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.data)
+    }
+}
+
+/// A connected client
+#[derive(Clone, Debug)]
+pub struct ClientInfo {
+    client_id: usize,
+    addr: ClientAddr,
+    dropped: u64,
+}
+
+impl ClientInfo {
+    /// Client id
+    #[inline]
+    pub fn client_id(&self) -> usize {
+        self.client_id
+    }
+    /// Client address
+    #[inline]
+    pub fn addr(&self) -> ClientAddr {
+        self.addr
+    }
+    /// The number of the messages, dropped because the client outgoing queue has been full
+    #[inline]
+    pub fn dropped(&self) -> u64 {
+        self.dropped
+    }
+}
 
 impl Server {
     /// Create a new server instance with the specified timeout
@@ -35,33 +223,37 @@ impl Server {
         let (incoming_data_tx, incoming_data_rx) = channel::bounded(DEFAULT_INCOMING_QUEUE_SIZE);
         Self {
             inner: Inner {
-                timeout,
-                clinet_id: atomic::AtomicUsize::new(0),
-                clients: <_>::default(),
-                client_count: atomic::AtomicUsize::new(0),
-                outgoing_queue_size: atomic::AtomicUsize::new(DEFAULT_OUTGOING_QUEUE_SIZE),
-                max_clients: atomic::AtomicUsize::new(DEFAULT_MAX_CLIENTS),
+                core: Core::new(timeout),
                 incoming_data_tx: Mutex::new(incoming_data_tx),
                 incoming_data_rx: Mutex::new(Some(incoming_data_rx)),
+                shutdown: atomic::AtomicBool::new(false),
+                listeners: <_>::default(),
             }
             .into(),
         }
     }
-    /// Set the maximum number of clients (default: 16)
+    /// Set the maximum number of clients (default: 16), including transport and local clients
     pub fn set_max_clients(&self, max_clients: usize) -> Result<(), Error> {
-        self.inner
-            .max_clients
-            .store(max_clients, atomic::Ordering::Relaxed);
+        self.inner.core.set_max_clients(max_clients);
         Ok(())
     }
     /// Set the outgoing queue size (default: 128). Note: if a client's queue size is full,
-    /// messages are dropped to prevent any server blocking.
+    /// messages are dropped to prevent any server blocking (see
+    /// [`Server::set_slow_client_policy()`]).
     pub fn set_outgoing_queue_size(&self, size: usize) -> Result<(), Error> {
-        self.inner
-            .outgoing_queue_size
-            .store(size, atomic::Ordering::Relaxed);
+        self.inner.core.set_outgoing_queue_size(size);
+        Ok(())
+    }
+    /// Set the policy for the clients which outgoing queues are full (default: drop the new
+    /// messages). The policy is applied to the clients connected after the call.
+    pub fn set_slow_client_policy(&self, policy: SlowClientPolicy) -> Result<(), Error> {
+        self.inner.core.set_slow_client_policy(policy);
         Ok(())
     }
+    /// Connected clients
+    pub fn clients(&self) -> Vec<ClientInfo> {
+        self.inner.core.clients()
+    }
     /// Set the incoming queue size (default: 128)
     pub fn set_incoming_queue_size(&self, size: usize) -> Result<(), Error> {
         let mut rx = self.inner.incoming_data_rx.lock();
@@ -73,6 +265,12 @@ impl Server {
         *rx = Some(incoming_data_rx);
         Ok(())
     }
+    /// Set the policy for the client messages when the data channel is full (default: block
+    /// the client reader). The policy is applied to the clients connected after the call.
+    pub fn set_incoming_policy(&self, policy: IncomingPolicy) -> Result<(), Error> {
+        self.inner.core.set_incoming_policy(policy);
+        Ok(())
+    }
     /// Take the data channel
     pub fn take_data_channel(&self) -> Result<FrameReceiver, Error> {
         self.inner
@@ -81,13 +279,125 @@ impl Server {
             .take()
             .ok_or(Error::DataChannelTaken)
     }
+    /// Take the event channel. Events, which happen before the call, are not reported. If the
+    /// channel is full, new events are dropped.
+    pub fn take_event_channel(&self) -> Result<EventReceiver, Error> {
+        let (tx, rx) = channel::bounded(EVENT_QUEUE_SIZE);
+        self.inner.core.set_event_handler(Arc::new(move |event| {
+            if tx.try_send(event) == Err(rtsc::Error::ChannelFull) {
+                warn!("event channel is full, event dropped");
+            }
+        }))?;
+        Ok(rx)
+    }
+    /// Set a greeting header, which is sent to clients on connect (e.g. application name,
+    /// version, MOTD). The header name must not contain colons and whitespaces and must not be
+    /// one of the protocol headers (`Auth`, `Capabilities`, `Extensions`, `History`,
+    /// `Keepalive`), the value must be a single line. Headers are sent to the clients connected
+    /// after the call.
+    pub fn set_header(&self, name: &str, value: impl ToString) -> Result<(), Error> {
+        self.inner.core.set_header(name, &value.to_string())
+    }
+    /// Set the number of the last broadcast messages (both `<<<` and `>>>`), which are replayed
+    /// to newly connected clients right after the greeting (default: 0, disabled)
+    pub fn set_history_size(&self, size: usize) -> Result<(), Error> {
+        self.inner.core.set_history_size(size);
+        Ok(())
+    }
+    /// Set the maximum age of the replayed history messages (default: unlimited)
+    pub fn set_history_ttl(&self, ttl: Option<Duration>) -> Result<(), Error> {
+        self.inner.core.set_history_ttl(ttl);
+        Ok(())
+    }
+    /// Enable keepalive for the clients connected after the call (default: disabled). Clients
+    /// which negotiate [`Capability::Keepalive`] are pinged every interval and disconnected if they
+    /// do not reply within 3 intervals. For other TCP clients, TCP keepalive is enabled.
+    pub fn set_keepalive(&self, interval: Option<Duration>) -> Result<(), Error> {
+        self.inner.core.set_keepalive(interval);
+        Ok(())
+    }
+    /// Disconnect the clients which send nothing within the timeout (default: disabled). Custom
+    /// transports without read timeouts are not affected.
+    pub fn set_idle_timeout(&self, timeout: Option<Duration>) -> Result<(), Error> {
+        self.inner.core.set_idle_timeout(timeout);
+        Ok(())
+    }
+    /// Set the maximum length of client lines in bytes (default: unlimited)
+    pub fn set_max_line_length(&self, length: Option<usize>) -> Result<(), Error> {
+        self.inner.core.set_max_line_length(length);
+        Ok(())
+    }
+    /// Set the policy for client lines which exceed the maximum length (default: truncate)
+    pub fn set_long_line_policy(&self, policy: LongLinePolicy) -> Result<(), Error> {
+        self.inner.core.set_long_line_policy(policy);
+        Ok(())
+    }
+    /// Set the message rate limit for each client (default: unlimited). Messages which exceed
+    /// the limit are dropped, the sender gets `rate limit exceeded` reply (once per the limit
+    /// period).
+    pub fn set_rate_limit(&self, limit: Option<RateLimit>) -> Result<(), Error> {
+        self.inner.core.set_rate_limit(limit);
+        Ok(())
+    }
     /// Send a message to the clients
     #[inline]
     pub fn send(&self, data: impl ToString) {
-        if self.inner.client_count.load(atomic::Ordering::Relaxed) > 0 {
-            self.inner
-                .send(Direction::ServerToClient, data.to_string().into());
-        }
+        self.inner.core.send_server_message(data, None);
+    }
+    /// Send a message with the severity to the clients. The severity is delivered to the clients
+    /// which have negotiated [`Capability::Severity`], other clients get the message as-is.
+    #[inline]
+    pub fn send_with_severity(&self, severity: Severity, data: impl ToString) {
+        self.inner.core.send_server_message(data, Some(severity));
+    }
+    /// Send a message to a single client. Targeted messages (including replies) are not recorded
+    /// in the history.
+    pub fn send_to(&self, client_id: usize, data: impl ToString) -> Result<(), Error> {
+        self.inner.core.send_to(
+            client_id,
+            Direction::ServerToClient,
+            data.to_string().into(),
+        )
+    }
+    /// Send a message to all clients except the specified one. The message is not recorded in
+    /// the history.
+    pub fn send_except(&self, client_id: usize, data: impl ToString) {
+        self.inner.core.send_except(
+            client_id,
+            Direction::ServerToClient,
+            data.to_string().into(),
+        );
+    }
+    /// Reply to the client the frame has been received from
+    #[inline]
+    pub fn reply(&self, frame: &IncomingFrame, data: impl ToString) -> Result<(), Error> {
+        self.inner.core.reply(frame, &data.to_string())
+    }
+    /// Enable TLS for the connections accepted after the call (requires `tls` feature). See
+    /// [`crate::tls`] for the configuration helpers.
+    #[cfg(feature = "tls")]
+    pub fn set_tls_config(&self, config: Arc<rustls::ServerConfig>) -> Result<(), Error> {
+        self.inner.core.set_tls_config(config);
+        Ok(())
+    }
+    /// Require authentication for the clients connected after the call. The function gets the
+    /// credentials, sent by a client, and returns the client identity or `None` to reject the
+    /// client. Clients with [`Role::Observer`] receive messages but can not send them.
+    pub fn set_authenticator<F>(&self, method: AuthMethod, f: F) -> Result<(), Error>
+    where
+        F: Fn(&Credentials) -> Option<Identity> + Send + Sync + 'static,
+    {
+        self.inner
+            .core
+            .set_authenticator(Authenticator::new(method, f));
+        Ok(())
+    }
+    /// Set the Unix socket file permissions (e.g. `0o660`), applied by [`Server::serve_unix()`]
+    /// (default: the process umask)
+    #[cfg(unix)]
+    pub fn set_unix_socket_permissions(&self, mode: u32) -> Result<(), Error> {
+        self.inner.core.set_unix_socket_permissions(mode);
+        Ok(())
     }
     /// Serve the server
     pub fn serve(&self, addr: impl ToSocketAddrs + std::fmt::Debug) -> Result<(), Error> {
@@ -95,101 +405,1146 @@ impl Server {
         self.serve_with_listener(listener)
     }
     /// Serve the server with the specified listener
+    ///
+    /// The method returns either on a listener error or after [`Server::shutdown()`] is called
+    /// and all the client connections are finished.
     pub fn serve_with_listener(&self, listener: TcpListener) -> Result<(), Error> {
-        trace!(addr = ?listener.local_addr(), "starting server");
-        let semaphore: Semaphore<RawMutex, Condvar> =
-            Semaphore::new(self.inner.max_clients.load(atomic::Ordering::Relaxed));
-        while let Ok((mut socket, addr)) = listener.accept() {
-            trace!(?addr, "new connection");
-            let permission = semaphore.acquire();
-            trace!(?addr, "handling connection");
-            let (outgoing_data_tx, outgoing_data_rx) = channel::bounded(
-                self.inner
-                    .outgoing_queue_size
-                    .load(atomic::Ordering::Relaxed),
+        self.serve_listener(&Listener::Tcp(listener))
+    }
+    /// Serve the server on a Unix domain socket. A stale socket file, left by a crashed server,
+    /// is removed. The socket file is removed when the method returns.
+    #[cfg(unix)]
+    pub fn serve_unix(&self, path: impl AsRef<Path>) -> Result<(), Error> {
+        let path = path.as_ref();
+        crate::unix::remove_stale_socket(path)?;
+        let listener = crate::unix::bind(path, self.inner.core.unix_socket_permissions())?;
+        let _socket_file = crate::unix::SocketFile(path.to_owned());
+        self.serve_listener(&Listener::Unix(listener, Some(path.to_owned())))
+    }
+    /// Serve the server with the specified Unix domain socket listener
+    ///
+    /// The method returns either on a listener error or after [`Server::shutdown()`] is called
+    /// and all the client connections are finished.
+    ///
+    /// [`Server::shutdown()`] wakes up the listener by connecting to its socket file. A listener
+    /// without a path (unnamed or abstract) can not be woken up, so the method returns only after
+    /// the next connection is accepted.
+    #[cfg(unix)]
+    pub fn serve_with_unix_listener(&self, listener: UnixListener) -> Result<(), Error> {
+        let path = listener.local_addr()?.as_pathname().map(Path::to_path_buf);
+        if path.is_none() {
+            warn!("the Unix listener has no path, it can not be woken up on shutdown");
+        }
+        self.serve_listener(&Listener::Unix(listener, path))
+    }
+    /// Set the origins, which are allowed to open WebSocket connections in addition to the
+    /// server itself, e.g. `https://example.com` (`*` allows any). Browser requests from other
+    /// origins are rejected.
+    #[cfg(feature = "websocket")]
+    pub fn set_websocket_origins(
+        &self,
+        origins: impl IntoIterator<Item = impl ToString>,
+    ) -> Result<(), Error> {
+        self.inner
+            .core
+            .set_websocket_origins(origins.into_iter().map(|o| o.to_string()).collect());
+        Ok(())
+    }
+    /// Serve the server over WebSocket (plain HTTP only). Each line is transferred as a single
+    /// text frame. Plain HTTP requests to `/` are served with a simple chat page, which lets a
+    /// browser act as a client. Cross-origin connections are rejected unless allowed with
+    /// [`Server::set_websocket_origins()`].
+    #[cfg(feature = "websocket")]
+    pub fn serve_websocket(&self, addr: impl ToSocketAddrs + std::fmt::Debug) -> Result<(), Error> {
+        let listener = TcpListener::bind(addr)?;
+        self.serve_with_websocket_listener(listener)
+    }
+    /// Serve the server over WebSocket with the specified listener
+    ///
+    /// The method returns either on a listener error or after [`Server::shutdown()`] is called
+    /// and all the client connections are finished.
+    #[cfg(feature = "websocket")]
+    pub fn serve_with_websocket_listener(&self, listener: TcpListener) -> Result<(), Error> {
+        self.serve_listener(&Listener::WebSocket(listener))
+    }
+    /// Serve a single client over a serial device (or any other character device). The device
+    /// must be configured (baud rate, raw mode etc.) in advance, e.g. with `stty`.
+    ///
+    /// See [`Server::serve_stream()`] for details.
+    pub fn serve_serial(&self, path: impl AsRef<Path>) -> Result<(), Error> {
+        let device = fs::OpenOptions::new().read(true).write(true).open(path)?;
+        let writer = device.try_clone()?;
+        self.serve_stream(device, writer)
+    }
+    /// Serve a single client over a generic byte stream, split into a reader and a writer (e.g. a
+    /// serial line or a pair of pipes)
+    ///
+    /// The method returns when the reader reaches EOF or returns an error. Generic streams do
+    /// not support timeouts and a blocked read can not be interrupted, so after
+    /// [`Server::shutdown()`] is called the method returns on the next incoming data only.
+    pub fn serve_stream<R, W>(&self, reader: R, writer: W) -> Result<(), Error>
+    where
+        R: Read + Send + 'static,
+        W: Write + Send + 'static,
+    {
+        self.serve_transport(IoStream::new(reader, writer))
+    }
+    /// Serve a single client over a custom transport
+    ///
+    /// The method returns when the client is disconnected. The client is refused with an error
+    /// if the maximum number of clients is reached.
+    pub fn serve_transport(&self, transport: impl Transport) -> Result<(), Error> {
+        trace!("serving a transport client");
+        let (client_id, queue, history) = self
+            .inner
+            .core
+            .register_client(ClientAddr::Stream, self.inner.core.generation())?;
+        serve_client(
+            &self.inner,
+            Box::new(transport),
+            client_id,
+            ClientAddr::Stream,
+            queue,
+            &history,
+        );
+        Ok(())
+    }
+    /// Connect an in-process client to the server. The connection uses in-memory pipes instead
+    /// of sockets, which is useful for testing
+    pub fn connect_local(&self) -> Result<(Client, client::FrameReceiver), Error> {
+        self.connect_local_with_options(&ConnectionOptions::default())
+    }
+    /// Connect an in-process client to the server with the defined options
+    pub fn connect_local_with_options(
+        &self,
+        options: &ConnectionOptions,
+    ) -> Result<(Client, client::FrameReceiver), Error> {
+        let (client_stream, server_stream) = pipe::pipe();
+        let (client_id, queue, history) = self
+            .inner
+            .core
+            .register_client(ClientAddr::Stream, self.inner.core.generation())?;
+        let inner = self.inner.clone();
+        thread::spawn(move || {
+            serve_client(
+                &inner,
+                Box::new(server_stream),
+                client_id,
+                ClientAddr::Stream,
+                queue,
+                &history,
             );
-            let client_id = self.inner.clinet_id.fetch_add(1, atomic::Ordering::Relaxed);
-            self.inner
-                .clients
-                .lock()
-                .insert(client_id, outgoing_data_tx);
+        });
+        Client::connect_transport(client_stream, options)
+    }
+    fn serve_listener(&self, listener: &Listener) -> Result<(), Error> {
+        let local_addr = listener.local_addr()?;
+        trace!(addr = ?local_addr, "starting server");
+        {
+            let mut listeners = self.inner.listeners.lock();
+            // the server may have been shut down before the listener is registered
+            if self.inner.is_shutting_down() {
+                trace!(addr = ?local_addr, "server has been shut down");
+                return Ok(());
+            }
+            listeners.push(local_addr.clone());
+        }
+        let semaphore: Semaphore<RawMutex, Condvar> = Semaphore::new(self.inner.core.max_clients());
+        let mut workers: Vec<thread::JoinHandle<()>> = Vec::new();
+        while let Ok((incoming, addr)) = listener.accept() {
+            let generation = self.inner.core.generation();
+            if self.inner.is_shutting_down() {
+                break;
+            }
+            trace!(%addr, "new connection");
+            let permission = semaphore.acquire();
+            if self.inner.is_shutting_down() {
+                break;
+            }
+            trace!(%addr, "handling connection");
             let inner = self.inner.clone();
-            let incoming_data_tx = self.inner.incoming_data_tx.lock().clone();
-            self.inner
-                .client_count
-                .fetch_add(1, atomic::Ordering::Relaxed);
-            thread::spawn(move || {
+            workers.retain(|worker| !worker.is_finished());
+            workers.push(thread::spawn(move || {
                 let _permission = permission;
-                let _r = handle_connection(&mut socket, &inner, incoming_data_tx, outgoing_data_rx);
-                inner.client_count.fetch_sub(1, atomic::Ordering::Relaxed);
-                inner.clients.lock().remove(&client_id);
-            });
+                let reject = |error: Error| {
+                    trace!(%addr, %error, "connection setup failed");
+                    inner
+                        .core
+                        .event(ServerEvent::Rejected(addr, error.to_string()));
+                };
+                let stream = match incoming.upgrade(&inner.core) {
+                    Ok(Some(v)) => v,
+                    Ok(None) => return,
+                    Err(error) => return reject(error),
+                };
+                let (client_id, queue, history) = match inner.core.register_client(addr, generation)
+                {
+                    Ok(v) => v,
+                    Err(error) => return reject(error),
+                };
+                serve_client(&inner, stream, client_id, addr, queue, &history);
+            }));
+        }
+        trace!(addr = ?local_addr, "stopping server");
+        {
+            let mut listeners = self.inner.listeners.lock();
+            if let Some(pos) = listeners.iter().position(|a| *a == local_addr) {
+                listeners.remove(pos);
+            }
+        }
+        for worker in workers {
+            worker.join().ok();
         }
         Ok(())
     }
+    /// Gracefully shut down the server: stop accepting new connections, send a goodbye message
+    /// to all clients and disconnect them.
+    ///
+    /// The method does not wait for the serving methods to finish. Call it from another thread
+    /// and wait until [`Server::serve()`]/[`Server::serve_with_listener()`] returns. The shutdown
+    /// is permanent: serving methods, called after, return immediately.
+    pub fn shutdown(&self) {
+        {
+            let listeners = self.inner.listeners.lock();
+            self.inner.shutdown.store(true, atomic::Ordering::Relaxed);
+            // wake up the listeners which are blocked in accept
+            for addr in listeners.iter() {
+                addr.wake(self.inner.core.timeout);
+            }
+        }
+        self.inner.core.disconnect_all(GOODBYE_MESSAGE);
+    }
+}
+
+enum Listener {
+    Tcp(TcpListener),
+    /// The listener and the path its clients connect to
+    #[cfg(unix)]
+    Unix(UnixListener, Option<PathBuf>),
+    #[cfg(feature = "websocket")]
+    WebSocket(TcpListener),
+}
+
+impl Listener {
+    fn local_addr(&self) -> io::Result<ListenerAddr> {
+        Ok(match self {
+            Listener::Tcp(l) => ListenerAddr::Tcp(l.local_addr()?),
+            #[cfg(unix)]
+            Listener::Unix(_, path) => ListenerAddr::Unix(path.clone()),
+            #[cfg(feature = "websocket")]
+            Listener::WebSocket(l) => ListenerAddr::Tcp(l.local_addr()?),
+        })
+    }
+    fn accept(&self) -> io::Result<(Incoming, ClientAddr)> {
+        match self {
+            Listener::Tcp(l) => {
+                let (socket, addr) = l.accept()?;
+                socket.set_nodelay(true)?;
+                Ok((Incoming::Tcp(socket), ClientAddr::Tcp(addr)))
+            }
+            #[cfg(unix)]
+            Listener::Unix(l, _) => {
+                let (socket, _) = l.accept()?;
+                Ok((Incoming::Unix(socket), ClientAddr::Unix))
+            }
+            #[cfg(feature = "websocket")]
+            Listener::WebSocket(l) => {
+                let (socket, addr) = l.accept()?;
+                socket.set_nodelay(true)?;
+                Ok((Incoming::WebSocket(socket), ClientAddr::Tcp(addr)))
+            }
+        }
+    }
+}
+
+/// An accepted connection, set up (TLS/WebSocket handshake) by the worker thread
+enum Incoming {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream),
+    #[cfg(feature = "websocket")]
+    WebSocket(TcpStream),
+}
+
+impl Incoming {
+    /// Returns `None` if the connection has been handled and must not be registered as a client
+    fn upgrade(self, core: &Core) -> Result<Option<Stream>, Error> {
+        match self {
+            // TLS is used for TCP connections only
+            Incoming::Tcp(socket) => {
+                core.set_tcp_keepalive(SockRef::from(&socket))?;
+                #[cfg(feature = "tls")]
+                if let Some(config) = core.tls_config() {
+                    socket.set_write_timeout(Some(core.timeout))?;
+                    return Ok(Some(Box::new(crate::tls::TlsStream::accept(
+                        socket,
+                        config,
+                        core.timeout,
+                    )?)));
+                }
+                Ok(Some(Box::new(socket)))
+            }
+            #[cfg(unix)]
+            Incoming::Unix(socket) => Ok(Some(Box::new(socket))),
+            #[cfg(feature = "websocket")]
+            Incoming::WebSocket(socket) => {
+                core.set_tcp_keepalive(SockRef::from(&socket))?;
+                Ok(
+                    crate::websocket::accept(socket, core.timeout, &core.websocket_origins())?
+                        .map(|ws| Box::new(ws) as Stream),
+                )
+            }
+        }
+    }
 }
 
-type ClientMap = BTreeMap<usize, Sender<(Direction, Arc<String>), RawMutex, Condvar>>;
+#[derive(Clone, Debug, Eq, PartialEq)]
+enum ListenerAddr {
+    Tcp(SocketAddr),
+    #[cfg(unix)]
+    Unix(Option<PathBuf>),
+}
+
+impl ListenerAddr {
+    /// Wakes up the listener which is blocked in accept
+    fn wake(&self, timeout: Duration) {
+        match self {
+            ListenerAddr::Tcp(addr) => {
+                TcpStream::connect_timeout(&wake_addr(*addr), timeout).ok();
+            }
+            #[cfg(unix)]
+            ListenerAddr::Unix(path) => {
+                if let Some(path) = path {
+                    UnixStream::connect(path).ok();
+                }
+            }
+        }
+    }
+}
+
+/// Converts the listener address to an address the listener can be reached at locally
+fn wake_addr(mut addr: SocketAddr) -> SocketAddr {
+    if addr.ip().is_unspecified() {
+        addr.set_ip(if addr.is_ipv4() {
+            Ipv4Addr::LOCALHOST.into()
+        } else {
+            Ipv6Addr::LOCALHOST.into()
+        });
+    }
+    addr
+}
 
 struct Inner {
-    timeout: Duration,
+    core: Core,
+    incoming_data_tx: Mutex<Sender<IncomingFrame, RawMutex, Condvar>>,
+    incoming_data_rx: Mutex<Option<FrameReceiver>>,
+    shutdown: atomic::AtomicBool,
+    listeners: Mutex<Vec<ListenerAddr>>,
+}
+
+impl Inner {
+    #[inline]
+    fn is_shutting_down(&self) -> bool {
+        self.shutdown.load(atomic::Ordering::Relaxed)
+    }
+}
+
+type ClientMap = BTreeMap<usize, ConnectedClient>;
+
+struct ConnectedClient {
+    addr: ClientAddr,
+    queue: Arc<ClientQueue>,
+}
+
+/// Broadcast messages history, replayed to newly connected clients
+#[derive(Default)]
+struct History {
+    frames: VecDeque<(Instant, OutgoingFrame)>,
+    size: usize,
+    ttl: Option<Duration>,
+}
+
+impl History {
+    fn set_size(&mut self, size: usize) {
+        self.size = size;
+        while self.frames.len() > size {
+            self.frames.pop_front();
+        }
+    }
+    #[inline]
+    fn is_enabled(&self) -> bool {
+        self.size > 0
+    }
+    fn push(&mut self, frame: OutgoingFrame) {
+        if !self.is_enabled() {
+            return;
+        }
+        if self.frames.len() >= self.size {
+            self.frames.pop_front();
+        }
+        self.frames.push_back((Instant::now(), frame));
+    }
+    fn snapshot(&mut self) -> Vec<OutgoingFrame> {
+        if let Some(ttl) = self.ttl {
+            while self
+                .frames
+                .front()
+                .map_or(false, |(t, _)| t.elapsed() > ttl)
+            {
+                self.frames.pop_front();
+            }
+        }
+        self.frames.iter().map(|(_, frame)| frame.clone()).collect()
+    }
+}
+
+/// Client keepalive and idle timeout settings
+#[derive(Default, Clone, Copy)]
+pub(crate) struct Keepalive {
+    pub(crate) interval: Option<Duration>,
+    pub(crate) idle_timeout: Option<Duration>,
+}
+
+impl Keepalive {
+    /// The ping interval for a client
+    pub(crate) fn ping_interval(&self, capabilities: &BTreeSet<Capability>) -> Option<Duration> {
+        self.interval
+            .filter(|_| capabilities.contains(&Capability::Keepalive))
+    }
+    /// The time of the next ping for a client, pings are sent every interval regardless of the
+    /// traffic
+    pub(crate) fn next_ping(&self, capabilities: &BTreeSet<Capability>) -> Option<Instant> {
+        self.ping_interval(capabilities)
+            .map(|interval| Instant::now() + interval)
+    }
+    /// The read timeout for a client: a client is disconnected if it sends nothing (including
+    /// pongs) within the timeout
+    pub(crate) fn read_timeout(&self, capabilities: &BTreeSet<Capability>) -> Option<Duration> {
+        let keepalive_timeout = self
+            .ping_interval(capabilities)
+            .map(|interval| interval * KEEPALIVE_MISSES);
+        match (self.idle_timeout, keepalive_timeout) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        }
+    }
+}
+
+/// Server state and logic, shared between the synchronous and the asynchronous servers
+pub(crate) struct Core {
+    pub(crate) timeout: Duration,
     clinet_id: atomic::AtomicUsize,
+    message_id: atomic::AtomicU64,
     clients: Mutex<ClientMap>,
     client_count: atomic::AtomicUsize,
+    // incremented on each shutdown, connections accepted before are not registered
+    generation: atomic::AtomicU64,
     outgoing_queue_size: atomic::AtomicUsize,
+    slow_client_policy: Mutex<SlowClientPolicy>,
+    incoming_policy: Mutex<IncomingPolicy>,
+    line_limit: Mutex<LineLimit>,
+    rate_limit: Mutex<Option<RateLimit>>,
     max_clients: atomic::AtomicUsize,
-    incoming_data_tx: Mutex<Sender<Arc<String>, RawMutex, Condvar>>,
-    incoming_data_rx: Mutex<Option<FrameReceiver>>,
+    headers: Mutex<BTreeMap<String, String>>,
+    history: Mutex<History>,
+    authenticator: Mutex<Option<Authenticator>>,
+    keepalive: Mutex<Keepalive>,
+    event_handler: Mutex<Option<EventHandler>>,
+    #[cfg(unix)]
+    unix_socket_permissions: Mutex<Option<u32>>,
+    #[cfg(feature = "tls")]
+    tls_config: Mutex<Option<Arc<rustls::ServerConfig>>>,
+    #[cfg(feature = "websocket")]
+    websocket_origins: Mutex<Vec<String>>,
 }
 
-impl Inner {
-    fn send(&self, direction: Direction, data: Arc<String>) {
-        for client in self.clients.lock().values() {
-            if let Err(e) = client.try_send((direction, data.clone())) {
-                if e == rtsc::Error::ChannelFull {
-                    warn!("failed to send data to a client, queue overflow");
-                }
-                // ignore all other errors
+impl Core {
+    pub(crate) fn new(timeout: Duration) -> Self {
+        Self {
+            timeout,
+            clinet_id: atomic::AtomicUsize::new(0),
+            message_id: atomic::AtomicU64::new(1),
+            clients: <_>::default(),
+            client_count: atomic::AtomicUsize::new(0),
+            generation: atomic::AtomicU64::new(0),
+            outgoing_queue_size: atomic::AtomicUsize::new(DEFAULT_OUTGOING_QUEUE_SIZE),
+            slow_client_policy: <_>::default(),
+            incoming_policy: <_>::default(),
+            line_limit: <_>::default(),
+            rate_limit: <_>::default(),
+            max_clients: atomic::AtomicUsize::new(DEFAULT_MAX_CLIENTS),
+            headers: <_>::default(),
+            history: <_>::default(),
+            authenticator: <_>::default(),
+            keepalive: <_>::default(),
+            event_handler: <_>::default(),
+            #[cfg(unix)]
+            unix_socket_permissions: <_>::default(),
+            #[cfg(feature = "tls")]
+            tls_config: <_>::default(),
+            #[cfg(feature = "websocket")]
+            websocket_origins: <_>::default(),
+        }
+    }
+    pub(crate) fn set_max_clients(&self, max_clients: usize) {
+        self.max_clients
+            .store(max_clients, atomic::Ordering::Relaxed);
+    }
+    pub(crate) fn max_clients(&self) -> usize {
+        self.max_clients.load(atomic::Ordering::Relaxed)
+    }
+    pub(crate) fn set_outgoing_queue_size(&self, size: usize) {
+        self.outgoing_queue_size
+            .store(size, atomic::Ordering::Relaxed);
+    }
+    pub(crate) fn set_slow_client_policy(&self, policy: SlowClientPolicy) {
+        *self.slow_client_policy.lock() = policy;
+    }
+    pub(crate) fn set_incoming_policy(&self, policy: IncomingPolicy) {
+        *self.incoming_policy.lock() = policy;
+    }
+    pub(crate) fn incoming_policy(&self) -> IncomingPolicy {
+        *self.incoming_policy.lock()
+    }
+    pub(crate) fn set_max_line_length(&self, length: Option<usize>) {
+        self.line_limit.lock().max_length = length;
+    }
+    pub(crate) fn set_long_line_policy(&self, policy: LongLinePolicy) {
+        self.line_limit.lock().policy = policy;
+    }
+    pub(crate) fn line_limit(&self) -> LineLimit {
+        *self.line_limit.lock()
+    }
+    pub(crate) fn set_rate_limit(&self, limit: Option<RateLimit>) {
+        *self.rate_limit.lock() = limit;
+    }
+    /// Creates a token bucket for a new client if the rate limit is set
+    pub(crate) fn token_bucket(&self) -> Option<TokenBucket> {
+        self.rate_limit.lock().map(TokenBucket::new)
+    }
+    pub(crate) fn set_history_size(&self, size: usize) {
+        self.history.lock().set_size(size);
+    }
+    pub(crate) fn set_history_ttl(&self, ttl: Option<Duration>) {
+        self.history.lock().ttl = ttl;
+    }
+    pub(crate) fn set_keepalive(&self, interval: Option<Duration>) {
+        self.keepalive.lock().interval = interval;
+    }
+    pub(crate) fn set_idle_timeout(&self, timeout: Option<Duration>) {
+        self.keepalive.lock().idle_timeout = timeout;
+    }
+    pub(crate) fn keepalive(&self) -> Keepalive {
+        *self.keepalive.lock()
+    }
+    /// Enables TCP keepalive for the socket if keepalive is enabled. The socket option has
+    /// a resolution of seconds, so shorter intervals are rounded up to a second.
+    pub(crate) fn set_tcp_keepalive(&self, socket: SockRef<'_>) -> io::Result<()> {
+        if let Some(interval) = self.keepalive().interval {
+            let time = interval.max(Duration::from_secs(1));
+            socket.set_tcp_keepalive(&TcpKeepalive::new().with_time(time))?;
+        }
+        Ok(())
+    }
+    pub(crate) fn set_event_handler(&self, handler: EventHandler) -> Result<(), Error> {
+        let mut event_handler = self.event_handler.lock();
+        if event_handler.is_some() {
+            return Err(Error::EventChannelTaken);
+        }
+        event_handler.replace(handler);
+        Ok(())
+    }
+    /// Reports the event if the event channel is taken. Never blocks.
+    pub(crate) fn event(&self, event: ServerEvent) {
+        let handler = self.event_handler.lock().clone();
+        if let Some(handler) = handler {
+            handler(event);
+        }
+    }
+    pub(crate) fn set_authenticator(&self, authenticator: Authenticator) {
+        self.authenticator.lock().replace(authenticator);
+    }
+    pub(crate) fn authenticator(&self) -> Option<Authenticator> {
+        self.authenticator.lock().clone()
+    }
+    #[cfg(unix)]
+    pub(crate) fn set_unix_socket_permissions(&self, mode: u32) {
+        self.unix_socket_permissions.lock().replace(mode);
+    }
+    #[cfg(unix)]
+    pub(crate) fn unix_socket_permissions(&self) -> Option<u32> {
+        *self.unix_socket_permissions.lock()
+    }
+    #[cfg(feature = "tls")]
+    pub(crate) fn set_tls_config(&self, config: Arc<rustls::ServerConfig>) {
+        self.tls_config.lock().replace(config);
+    }
+    #[cfg(feature = "tls")]
+    pub(crate) fn tls_config(&self) -> Option<Arc<rustls::ServerConfig>> {
+        self.tls_config.lock().clone()
+    }
+    #[cfg(feature = "websocket")]
+    pub(crate) fn set_websocket_origins(&self, origins: Vec<String>) {
+        *self.websocket_origins.lock() = origins;
+    }
+    #[cfg(feature = "websocket")]
+    pub(crate) fn websocket_origins(&self) -> Vec<String> {
+        self.websocket_origins.lock().clone()
+    }
+    pub(crate) fn set_header(&self, name: &str, value: &str) -> Result<(), Error> {
+        if name.is_empty()
+            || name.contains(|c: char| c == ':' || c.is_whitespace() || c.is_control())
+            || value.contains(['\n', '\r'])
+            || RESERVED_HEADERS
+                .iter()
+                .any(|reserved| reserved.eq_ignore_ascii_case(name))
+        {
+            return Err(Error::InvalidData);
+        }
+        self.headers
+            .lock()
+            .insert(name.to_owned(), value.trim().to_owned());
+        Ok(())
+    }
+    /// Formats the greeting (the history messages are sent separately, after authentication)
+    pub(crate) fn greeting(
+        &self,
+        history: &[OutgoingFrame],
+        auth_method: Option<AuthMethod>,
+    ) -> String {
+        let mut greeting = format!("{}/{}\n", GREETING, API_VERSION);
+        for (name, value) in &*self.headers.lock() {
+            writeln!(greeting, "{}: {}", name, value).ok();
+        }
+        writeln!(
+            greeting,
+            "{}: {}",
+            HEADER_CAPABILITIES,
+            format_capabilities(&self.capabilities())
+        )
+        .ok();
+        if let Some(method) = auth_method {
+            writeln!(greeting, "{}: {}", HEADER_AUTH, method).ok();
+        }
+        if let Some(interval) = self.keepalive().interval {
+            writeln!(greeting, "{}: {}", HEADER_KEEPALIVE, interval.as_secs_f64()).ok();
+        }
+        if !history.is_empty() {
+            // the number of lines, as multi-line messages can not be told apart by v1 clients
+            let lines: usize = history.iter().map(OutgoingFrame::line_count).sum();
+            writeln!(greeting, "{}: {}", HEADER_HISTORY, lines).ok();
+        }
+        greeting.push_str(HEADERS_TRANSMISSION_END);
+        greeting.push('\n');
+        greeting
+    }
+    /// Capabilities, supported by the server
+    pub(crate) fn capabilities(&self) -> BTreeSet<Capability> {
+        let keepalive = self.keepalive().interval.is_some();
+        Capability::ALL
+            .iter()
+            .copied()
+            .filter(|c| keepalive || *c != Capability::Keepalive)
+            .collect()
+    }
+    /// Negotiates the capabilities, requested by the client hello
+    pub(crate) fn negotiate(&self, hello: &Greeting) -> BTreeSet<Capability> {
+        let capabilities = hello
+            .capabilities()
+            .intersection(&self.capabilities())
+            .copied()
+            .collect();
+        trace!(
+            capabilities = format_capabilities(&capabilities),
+            "capabilities negotiated"
+        );
+        capabilities
+    }
+    /// Creates an outgoing frame with a new message id
+    pub(crate) fn frame(
+        &self,
+        direction: Direction,
+        data: Arc<String>,
+        severity: Option<Severity>,
+    ) -> OutgoingFrame {
+        OutgoingFrame {
+            direction,
+            id: self.message_id.fetch_add(1, atomic::Ordering::Relaxed),
+            severity,
+            data,
+        }
+    }
+    /// Processes a line received from a client and creates an incoming frame, which must be
+    /// echoed with [`Core::echo()`] when accepted. Call ids are stripped from the data if the
+    /// client has negotiated them. Lines from observers are rejected.
+    pub(crate) fn process_line(
+        &self,
+        client_id: usize,
+        addr: ClientAddr,
+        identity: Option<&Arc<Identity>>,
+        line: String,
+        call_ids: bool,
+    ) -> Option<IncomingFrame> {
+        if identity.map_or(false, |i| i.role() < Role::Operator) {
+            self.send_to(
+                client_id,
+                Direction::ServerToClient,
+                PERMISSION_DENIED_MESSAGE.to_owned().into(),
+            )
+            .ok();
+            return None;
+        }
+        let (data, call_id): (Arc<String>, _) =
+            if let Some((id, data)) = split_call_id(&line).filter(|_| call_ids) {
+                (data.to_owned().into(), Some(id))
+            } else {
+                (line.into(), None)
+            };
+        Some(IncomingFrame::new(
+            client_id,
+            addr,
+            data,
+            call_id,
+            identity.cloned(),
+        ))
+    }
+    /// Echoes the frame data to all clients
+    pub(crate) fn echo(&self, frame: &IncomingFrame) {
+        self.send(Direction::ClientToServer, frame.data.clone(), None);
+    }
+    /// Replies to the frame sender, tagging the reply with the call id if set
+    pub(crate) fn reply(&self, frame: &IncomingFrame, data: &str) -> Result<(), Error> {
+        let data = if let Some(call_id) = frame.call_id {
+            format_call(call_id, data)
+        } else {
+            data.to_owned()
+        };
+        self.send_to(frame.client_id, Direction::ServerToClient, data.into())
+    }
+    /// Notifies the sender that the frame has been rejected as the data channel is full
+    pub(crate) fn reject_busy(&self, frame: &IncomingFrame) {
+        warn!(addr = %frame.addr, "data channel is full, client message rejected");
+        self.reply(frame, SERVER_BUSY_MESSAGE).ok();
+    }
+    /// Notifies the client that the line has been dropped as the rate limit is exceeded. The
+    /// reply is tagged with the call id if the line has one and call ids have been negotiated.
+    pub(crate) fn reject_rate_limited(
+        &self,
+        client_id: usize,
+        addr: ClientAddr,
+        line: &str,
+        call_ids: bool,
+    ) {
+        warn!(%addr, "client rate limit exceeded, message dropped");
+        let data = if let Some((call_id, _)) = split_call_id(line).filter(|_| call_ids) {
+            format_call(call_id, RATE_LIMIT_MESSAGE)
+        } else {
+            RATE_LIMIT_MESSAGE.to_owned()
+        };
+        self.send_to(client_id, Direction::ServerToClient, data.into())
+            .ok();
+    }
+    /// Returns the current shutdown generation, which must be obtained when a connection is
+    /// accepted and passed to [`Core::register_client()`]
+    pub(crate) fn generation(&self) -> u64 {
+        self.generation.load(atomic::Ordering::SeqCst)
+    }
+    /// Allocates a client id and an outgoing queue for a new client, returns the history to
+    /// replay. The history snapshot and the registration are atomic, so the client neither
+    /// misses nor duplicates messages.
+    ///
+    /// The client is refused if the server has been shut down since the connection has been
+    /// accepted or if the maximum number of clients is reached.
+    pub(crate) fn register_client(
+        &self,
+        addr: ClientAddr,
+        generation: u64,
+    ) -> Result<(usize, Arc<ClientQueue>, Vec<OutgoingFrame>), Error> {
+        let mut history = self.history.lock();
+        let mut clients = self.clients.lock();
+        if self.generation.load(atomic::Ordering::SeqCst) != generation {
+            return Err(Error::ShuttingDown);
+        }
+        if self.client_count.load(atomic::Ordering::SeqCst) >= self.max_clients() {
+            return Err(Error::TooManyClients);
+        }
+        let queue = Arc::new(ClientQueue::new(
+            self.outgoing_queue_size.load(atomic::Ordering::Relaxed),
+            *self.slow_client_policy.lock(),
+        ));
+        let client_id = self.clinet_id.fetch_add(1, atomic::Ordering::Relaxed);
+        let snapshot = history.snapshot();
+        clients.insert(
+            client_id,
+            ConnectedClient {
+                addr,
+                queue: queue.clone(),
+            },
+        );
+        self.client_count.fetch_add(1, atomic::Ordering::SeqCst);
+        drop(clients);
+        drop(history);
+        self.event(ServerEvent::Connected(client_id, addr));
+        Ok((client_id, queue, snapshot))
+    }
+    /// Must be called exactly once for each registered client
+    pub(crate) fn unregister_client(&self, client_id: usize, reason: DisconnectReason) {
+        self.remove_client(client_id);
+        self.client_count.fetch_sub(1, atomic::Ordering::SeqCst);
+        trace!(client_id, ?reason, "client disconnected");
+        self.event(ServerEvent::Disconnected(client_id, reason));
+    }
+    /// Removes the client from the map and closes its outgoing queue to let the writer finish
+    pub(crate) fn remove_client(&self, client_id: usize) {
+        if let Some(client) = self.clients.lock().remove(&client_id) {
+            client.queue.close();
+        }
+    }
+    pub(crate) fn clients(&self) -> Vec<ClientInfo> {
+        self.clients
+            .lock()
+            .iter()
+            .map(|(client_id, client)| ClientInfo {
+                client_id: *client_id,
+                addr: client.addr,
+                dropped: client.queue.dropped(),
+            })
+            .collect()
+    }
+    /// Sends the message to all clients and closes their outgoing queues, so the writers
+    /// disconnect the clients as soon as the queues are flushed. Connections, which are accepted
+    /// but not registered yet, are refused.
+    pub(crate) fn disconnect_all(&self, message: &str) {
+        let clients = {
+            let mut clients = self.clients.lock();
+            self.generation.fetch_add(1, atomic::Ordering::SeqCst);
+            std::mem::take(&mut *clients)
+        };
+        let frame = self.frame(Direction::ServerToClient, message.to_owned().into(), None);
+        for (client_id, client) in &clients {
+            self.push_to_client(*client_id, client, frame.clone());
+            client.queue.disconnect();
+        }
+    }
+    pub(crate) fn send_server_message(&self, data: impl ToString, severity: Option<Severity>) {
+        if self.client_count.load(atomic::Ordering::Relaxed) > 0 || self.history.lock().is_enabled()
+        {
+            self.send(Direction::ServerToClient, data.to_string().into(), severity);
+        }
+    }
+    /// Broadcasts the message to all clients and records it in the history
+    pub(crate) fn send(&self, direction: Direction, data: Arc<String>, severity: Option<Severity>) {
+        let mut history = self.history.lock();
+        let frame = self.frame(direction, data, severity);
+        history.push(frame.clone());
+        for (client_id, client) in &*self.clients.lock() {
+            self.push_to_client(*client_id, client, frame.clone());
+        }
+    }
+    pub(crate) fn send_to(
+        &self,
+        client_id: usize,
+        direction: Direction,
+        data: Arc<String>,
+    ) -> Result<(), Error> {
+        let clients = self.clients.lock();
+        let client = clients
+            .get(&client_id)
+            .ok_or(Error::ClientNotFound(client_id))?;
+        self.push_to_client(client_id, client, self.frame(direction, data, None));
+        Ok(())
+    }
+    pub(crate) fn send_except(&self, client_id: usize, direction: Direction, data: Arc<String>) {
+        let frame = self.frame(direction, data, None);
+        for (id, client) in self
+            .clients
+            .lock()
+            .iter()
+            .filter(|(id, _)| **id != client_id)
+        {
+            self.push_to_client(*id, client, frame.clone());
+        }
+    }
+    fn push_to_client(&self, client_id: usize, client: &ConnectedClient, frame: OutgoingFrame) {
+        if let Err(e) = client.queue.try_push(frame) {
+            if e == rtsc::Error::ChannelFull {
+                warn!(addr = %client.addr, "failed to send data to a client, queue overflow");
+                self.event(ServerEvent::QueueOverflow(client_id));
             }
+            // ignore all other errors
+        }
+    }
+    /// Authenticates a client with the credentials line, returns the reply for the client
+    pub(crate) fn authenticate(
+        &self,
+        authenticator: &Authenticator,
+        client_id: usize,
+        addr: ClientAddr,
+        line: &str,
+    ) -> (Option<Arc<Identity>>, String) {
+        if let Some(identity) = authenticator.authenticate(line.trim_end_matches(['\n', '\r'])) {
+            trace!(%addr, user = identity.user(), role = %identity.role(), "client authenticated");
+            let identity = Arc::new(identity);
+            self.event(ServerEvent::Authenticated(client_id, identity.clone()));
+            let reply = format!("{} {}\n", AUTH_OK, identity.role());
+            (Some(identity), reply)
+        } else {
+            warn!(%addr, "client authentication failed");
+            self.event(ServerEvent::Rejected(addr, AUTH_FAILED_MESSAGE.to_owned()));
+            (None, format!("{} {}\n", AUTH_ERR, AUTH_FAILED_MESSAGE))
         }
     }
 }
 
-fn handle_connection(
-    socket: &mut TcpStream,
+/// Determines why the client has been disconnected by the connection handler result
+pub(crate) fn disconnect_reason(
+    error: Option<&(dyn std::error::Error + 'static)>,
+    queue: &ClientQueue,
+) -> DisconnectReason {
+    if queue.is_disconnected() {
+        return DisconnectReason::Server;
+    }
+    let Some(error) = error else {
+        return DisconnectReason::Closed;
+    };
+    let io_error = match error.downcast_ref::<Error>() {
+        Some(Error::AuthFailed(_)) => return DisconnectReason::AuthFailed,
+        Some(Error::Timeout) => return DisconnectReason::Timeout,
+        Some(Error::Io(e)) => Some(e),
+        Some(_) => None,
+        None => error.downcast_ref::<io::Error>(),
+    };
+    match io_error.map(io::Error::kind) {
+        Some(io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) => DisconnectReason::Timeout,
+        Some(
+            io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::BrokenPipe
+            | io::ErrorKind::UnexpectedEof,
+        ) => DisconnectReason::Closed,
+        _ => DisconnectReason::Error(error.to_string()),
+    }
+}
+
+/// Formats the history messages, replayed to a client
+pub(crate) fn format_history(history: &[OutgoingFrame], encoder: &MessageEncoder) -> Vec<u8> {
+    let mut buf = Vec::new();
+    for frame in history {
+        encoder.encode(&frame.message(), &mut buf);
+    }
+    buf
+}
+
+/// Serves a registered client and unregisters it when the connection is finished
+fn serve_client(
     inner: &Inner,
-    incoming_data_tx: Sender<Arc<String>, RawMutex, Condvar>,
-    outgoing_data_rx: Receiver<(Direction, Arc<String>), RawMutex, Condvar>,
+    stream: Stream,
+    client_id: usize,
+    addr: ClientAddr,
+    queue: Arc<ClientQueue>,
+    history: &[OutgoingFrame],
+) {
+    let incoming_data_tx = inner.incoming_data_tx.lock().clone();
+    let result = handle_connection(
+        stream,
+        client_id,
+        addr,
+        &inner.core,
+        incoming_data_tx,
+        queue.clone(),
+        history,
+    );
+    inner.core.unregister_client(
+        client_id,
+        disconnect_reason(result.as_ref().err().map(|e| &**e), &queue),
+    );
+}
+
+/// Reads the rest of the client hello, which starts with the line
+fn read_hello(
+    line: &str,
+    mut lines: impl Iterator<Item = io::Result<String>>,
+) -> Result<Greeting, Error> {
+    let mut parser = GreetingParser::new();
+    let mut line = line.to_owned();
+    loop {
+        if let Some(hello) = parser.process_line(&line)? {
+            return Ok(hello);
+        }
+        line = lines.next().ok_or(Error::InvalidData)??;
+    }
+}
+
+fn handle_connection(
+    mut stream: Stream,
+    client_id: usize,
+    addr: ClientAddr,
+    core: &Core,
+    incoming_data_tx: Sender<IncomingFrame, RawMutex, Condvar>,
+    queue: Arc<ClientQueue>,
+    history: &[OutgoingFrame],
 ) -> Result<(), Box<dyn std::error::Error>> {
-    socket.set_write_timeout(Some(inner.timeout))?;
-    socket.set_nodelay(true)?;
-    socket.write_all(
-        format!(
-            "{}/{}\n{}\n",
-            GREETING, API_VERSION, HEADERS_TRANSMISSION_END
-        )
-        .as_bytes(),
+    stream.set_write_timeout(Some(core.timeout))?;
+    let authenticator = core.authenticator();
+    stream.write_all(
+        core.greeting(history, authenticator.as_ref().map(|a| a.method))
+            .as_bytes(),
     )?;
-    let reader = BufReader::new(socket.try_clone()?);
-    let mut writer = socket.try_clone()?;
-    thread::spawn(move || {
-        for (direction, data) in outgoing_data_rx {
-            if writer.write_all(direction.as_bytes()).is_err()
-                || writer.write_all(data.as_bytes()).is_err()
-                || writer.write_all(b"\n").is_err()
-            {
-                trace!("writer error or finished - shutting down");
-                writer.shutdown(Shutdown::Both).ok();
-                break;
+    let mut lines = LineReader::new(BufReader::new(stream.try_clone()?), core.line_limit());
+    let mut encoder = MessageEncoder::new();
+    // without authentication, the hello is processed by the reader, as v1 clients may send
+    // nothing
+    let mut hello_expected = true;
+    let identity = if let Some(authenticator) = authenticator {
+        stream.set_read_timeout(Some(core.timeout))?;
+        let mut line = lines.next().ok_or(Error::InvalidData)??;
+        if is_hello(&line) {
+            let capabilities = core.negotiate(&read_hello(&line, &mut lines)?);
+            stream.write_all(format_capabilities_ack(&capabilities).as_bytes())?;
+            encoder.set_capabilities(capabilities);
+            line = lines.next().ok_or(Error::InvalidData)??;
+        }
+        hello_expected = false;
+        let (identity, reply) = core.authenticate(&authenticator, client_id, addr, &line);
+        stream.write_all(reply.as_bytes())?;
+        let Some(identity) = identity else {
+            stream.shutdown().ok();
+            return Err(Error::AuthFailed(AUTH_FAILED_MESSAGE.to_owned()).into());
+        };
+        Some(identity)
+    } else {
+        None
+    };
+    let keepalive = core.keepalive();
+    stream.set_read_timeout(keepalive.read_timeout(encoder.capabilities()))?;
+    stream.write_all(&format_history(history, &encoder))?;
+    let call_ids = encoder.capabilities().contains(&Capability::CallId);
+    let writer = stream.try_clone()?;
+    let writer_queue = queue.clone();
+    let writer_thread =
+        thread::spawn(move || handle_outgoing(writer, &writer_queue, encoder, keepalive));
+    let result = handle_incoming(
+        lines,
+        client_id,
+        addr,
+        identity.as_ref(),
+        core,
+        incoming_data_tx,
+        &queue,
+        hello_expected,
+        call_ids,
+    );
+    trace!("shutting down connection");
+    stream.shutdown().ok();
+    core.remove_client(client_id);
+    writer_thread.join().ok();
+    result
+}
+
+/// If the hello is expected, the first line may start it
+#[allow(clippy::too_many_arguments)]
+fn handle_incoming(
+    mut lines: LineReader<BufReader<Stream>>,
+    client_id: usize,
+    addr: ClientAddr,
+    identity: Option<&Arc<Identity>>,
+    core: &Core,
+    incoming_data_tx: Sender<IncomingFrame, RawMutex, Condvar>,
+    queue: &ClientQueue,
+    mut hello_expected: bool,
+    mut call_ids: bool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    // pongs are reserved if keepalive is enabled
+    let keepalive = core.keepalive().interval.is_some();
+    let policy = core.incoming_policy();
+    let mut token_bucket = core.token_bucket();
+    while let Some(line) = lines.next() {
+        let line = line.map_err(|error| {
+            if matches!(
+                error.kind(),
+                io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+            ) {
+                trace!(%addr, "client idle timeout");
+            }
+            error
+        })?;
+        // the line may be read after the client has been disconnected, e.g. streams which can
+        // not be shut down
+        if queue.is_disconnected() {
+            break;
+        }
+        if std::mem::take(&mut hello_expected) && is_hello(&line) {
+            let capabilities = core.negotiate(&read_hello(&line, &mut lines)?);
+            call_ids = capabilities.contains(&Capability::CallId);
+            queue.switch_capabilities(capabilities);
+            continue;
+        }
+        if keepalive && line == PONG {
+            continue;
+        }
+        if let Some(ref mut bucket) = token_bucket {
+            if !bucket.take() {
+                if bucket.notify() {
+                    core.reject_rate_limited(client_id, addr, &line, call_ids);
+                }
+                continue;
+            }
+        }
+        let Some(frame) = core.process_line(client_id, addr, identity, line, call_ids) else {
+            continue;
+        };
+        match policy {
+            // the frame is echoed only if accepted
+            IncomingPolicy::Block => {
+                incoming_data_tx.send(frame.clone())?;
+                core.echo(&frame);
             }
+            IncomingPolicy::Reject => match incoming_data_tx.try_send(frame.clone()) {
+                Ok(()) => core.echo(&frame),
+                Err(rtsc::Error::ChannelFull) => core.reject_busy(&frame),
+                Err(e) => return Err(e.into()),
+            },
         }
-    });
-    for line in reader.lines() {
-        let line: Arc<String> = line?.into();
-        inner.send(Direction::ClientToServer, line.clone());
-        incoming_data_tx.send(line)?;
     }
-    trace!("shutting down connection");
-    socket.shutdown(Shutdown::Both)?;
     Ok(())
 }
+
+fn handle_outgoing(
+    mut writer: Stream,
+    queue: &ClientQueue,
+    mut encoder: MessageEncoder,
+    keepalive: Keepalive,
+) {
+    let mut ping_at = keepalive.next_ping(encoder.capabilities());
+    while let Some(item) = queue.pop(ping_at) {
+        // a single write per line, so TLS sessions do not split lines into several records
+        let mut buf = Vec::new();
+        match item {
+            Outgoing::Frame(frame) => encoder.encode(&frame.message(), &mut buf),
+            Outgoing::Capabilities(capabilities) => {
+                // the reader and the writer share the connection, so the timeout is set for both
+                if writer
+                    .set_read_timeout(keepalive.read_timeout(&capabilities))
+                    .is_err()
+                {
+                    break;
+                }
+                buf.extend(format_capabilities_ack(&capabilities).as_bytes());
+                encoder.set_capabilities(capabilities);
+                ping_at = keepalive.next_ping(encoder.capabilities());
+            }
+            Outgoing::Ping => {
+                buf.extend(PING.as_bytes());
+                buf.push(b'\n');
+                ping_at = keepalive.next_ping(encoder.capabilities());
+            }
+            Outgoing::Dropped(count) => encoder.encode(&dropped_message(count), &mut buf),
+        }
+        if writer.write_all(&buf).is_err() {
+            trace!("writer error - shutting down");
+            break;
+        }
+    }
+    trace!("writer finished");
+    writer.shutdown().ok();
+}
@@ -0,0 +1,789 @@
+use std::{collections::BTreeSet, io, sync::Arc, time::Instant};
+
+#[cfg(unix)]
+use std::path::Path;
+
+use parking_lot_rt::Mutex as SyncMutex;
+use rtsc::channel_async::{Receiver, Sender};
+use tokio::{
+    io::{AsyncBufRead, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader},
+    net::{TcpListener, TcpStream, ToSocketAddrs},
+    sync::{watch, Semaphore},
+    task::JoinSet,
+};
+
+use socket2::SockRef;
+#[cfg(unix)]
+use tokio::net::{UnixListener, UnixStream};
+use tracing::{trace, warn};
+
+use crate::{
+    auth::{Authenticator, AUTH_FAILED_MESSAGE},
+    limit::{LineReaderAsync, LongLinePolicy, RateLimit},
+    protocol::{
+        format_capabilities_ack, is_hello, Capability, Greeting, GreetingParser, MessageEncoder,
+        Severity, PING, PONG,
+    },
+    queue::{dropped_message, ClientQueue, Outgoing, OutgoingFrame, SlowClientPolicy},
+    server::{
+        disconnect_reason, format_history, Core, DisconnectReason, Keepalive, EVENT_QUEUE_SIZE,
+        GOODBYE_MESSAGE,
+    },
+    AuthMethod, ClientAddr, ClientAsync, ClientInfo, ConnectionOptions, Credentials, Direction,
+    Error, Identity, IncomingFrame, IncomingPolicy, ServerEvent, TransportAsync,
+    DEFAULT_INCOMING_QUEUE_SIZE,
+};
+
+const LOCAL_PIPE_BUFFER_SIZE: usize = 65_536;
+
+/// Asynchronous server instance
+///
+/// The server has the same behaviour as [`crate::Server`] but serves clients in tokio tasks
+/// instead of dedicated threads.
+#[derive(Clone)]
+pub struct ServerAsync {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    core: Core,
+    incoming_data_tx: SyncMutex<Sender<IncomingFrame>>,
+    incoming_data_rx: SyncMutex<Option<Receiver<IncomingFrame>>>,
+    shutdown_tx: watch::Sender<bool>,
+}
+
+impl ServerAsync {
+    /// Create a new server instance with the specified timeout
+    pub fn new(timeout: std::time::Duration) -> Self {
+        let (incoming_data_tx, incoming_data_rx) =
+            rtsc::channel_async::bounded(DEFAULT_INCOMING_QUEUE_SIZE);
+        let (shutdown_tx, _) = watch::channel(false);
+        Self {
+            inner: Inner {
+                core: Core::new(timeout),
+                incoming_data_tx: SyncMutex::new(incoming_data_tx),
+                incoming_data_rx: SyncMutex::new(Some(incoming_data_rx)),
+                shutdown_tx,
+            }
+            .into(),
+        }
+    }
+    /// Set the maximum number of clients (default: 16), including transport and local clients
+    pub fn set_max_clients(&self, max_clients: usize) -> Result<(), Error> {
+        self.inner.core.set_max_clients(max_clients);
+        Ok(())
+    }
+    /// Set the outgoing queue size (default: 128). Note: if a client's queue size is full,
+    /// messages are dropped to prevent any server blocking (see
+    /// [`ServerAsync::set_slow_client_policy()`]).
+    pub fn set_outgoing_queue_size(&self, size: usize) -> Result<(), Error> {
+        self.inner.core.set_outgoing_queue_size(size);
+        Ok(())
+    }
+    /// Set the policy for the clients which outgoing queues are full (default: drop the new
+    /// messages). The policy is applied to the clients connected after the call.
+    pub fn set_slow_client_policy(&self, policy: SlowClientPolicy) -> Result<(), Error> {
+        self.inner.core.set_slow_client_policy(policy);
+        Ok(())
+    }
+    /// Connected clients
+    pub fn clients(&self) -> Vec<ClientInfo> {
+        self.inner.core.clients()
+    }
+    /// Set the incoming queue size (default: 128)
+    pub fn set_incoming_queue_size(&self, size: usize) -> Result<(), Error> {
+        let mut rx = self.inner.incoming_data_rx.lock();
+        if rx.is_none() {
+            return Err(Error::DataChannelTaken);
+        }
+        let (incoming_data_tx, incoming_data_rx) = rtsc::channel_async::bounded(size);
+        *self.inner.incoming_data_tx.lock() = incoming_data_tx;
+        *rx = Some(incoming_data_rx);
+        Ok(())
+    }
+    /// Set the policy for the client messages when the data channel is full (default: block
+    /// the client reader). The policy is applied to the clients connected after the call.
+    pub fn set_incoming_policy(&self, policy: IncomingPolicy) -> Result<(), Error> {
+        self.inner.core.set_incoming_policy(policy);
+        Ok(())
+    }
+    /// Take the data channel
+    pub fn take_data_channel(&self) -> Result<Receiver<IncomingFrame>, Error> {
+        self.inner
+            .incoming_data_rx
+            .lock()
+            .take()
+            .ok_or(Error::DataChannelTaken)
+    }
+    /// Take the event channel. Events, which happen before the call, are not reported. If the
+    /// channel is full, new events are dropped.
+    pub fn take_event_channel(&self) -> Result<Receiver<ServerEvent>, Error> {
+        let (tx, rx) = rtsc::channel_async::bounded(EVENT_QUEUE_SIZE);
+        self.inner.core.set_event_handler(Arc::new(move |event| {
+            if tx.try_send(event) == Err(rtsc::Error::ChannelFull) {
+                warn!("event channel is full, event dropped");
+            }
+        }))?;
+        Ok(rx)
+    }
+    /// Set a greeting header, which is sent to clients on connect (e.g. application name,
+    /// version, MOTD). The header name must not contain colons and whitespaces and must not be
+    /// one of the protocol headers (`Auth`, `Capabilities`, `Extensions`, `History`,
+    /// `Keepalive`), the value must be a single line. Headers are sent to the clients connected
+    /// after the call.
+    pub fn set_header(&self, name: &str, value: impl ToString) -> Result<(), Error> {
+        self.inner.core.set_header(name, &value.to_string())
+    }
+    /// Set the number of the last broadcast messages (both `<<<` and `>>>`), which are replayed
+    /// to newly connected clients right after the greeting (default: 0, disabled)
+    pub fn set_history_size(&self, size: usize) -> Result<(), Error> {
+        self.inner.core.set_history_size(size);
+        Ok(())
+    }
+    /// Set the maximum age of the replayed history messages (default: unlimited)
+    pub fn set_history_ttl(&self, ttl: Option<std::time::Duration>) -> Result<(), Error> {
+        self.inner.core.set_history_ttl(ttl);
+        Ok(())
+    }
+    /// Enable keepalive for the clients connected after the call (default: disabled). Clients
+    /// which negotiate [`Capability::Keepalive`] are pinged every interval and disconnected if they
+    /// do not reply within 3 intervals. For other TCP clients, TCP keepalive is enabled.
+    pub fn set_keepalive(&self, interval: Option<std::time::Duration>) -> Result<(), Error> {
+        self.inner.core.set_keepalive(interval);
+        Ok(())
+    }
+    /// Disconnect the clients which send nothing within the timeout (default: disabled)
+    pub fn set_idle_timeout(&self, timeout: Option<std::time::Duration>) -> Result<(), Error> {
+        self.inner.core.set_idle_timeout(timeout);
+        Ok(())
+    }
+    /// Set the maximum length of client lines in bytes (default: unlimited)
+    pub fn set_max_line_length(&self, length: Option<usize>) -> Result<(), Error> {
+        self.inner.core.set_max_line_length(length);
+        Ok(())
+    }
+    /// Set the policy for client lines which exceed the maximum length (default: truncate)
+    pub fn set_long_line_policy(&self, policy: LongLinePolicy) -> Result<(), Error> {
+        self.inner.core.set_long_line_policy(policy);
+        Ok(())
+    }
+    /// Set the message rate limit for each client (default: unlimited). Messages which exceed
+    /// the limit are dropped, the sender gets `rate limit exceeded` reply (once per the limit
+    /// period).
+    pub fn set_rate_limit(&self, limit: Option<RateLimit>) -> Result<(), Error> {
+        self.inner.core.set_rate_limit(limit);
+        Ok(())
+    }
+    /// Send a message to the clients
+    #[inline]
+    pub fn send(&self, data: impl ToString) {
+        self.inner.core.send_server_message(data, None);
+    }
+    /// Send a message with the severity to the clients. The severity is delivered to the clients
+    /// which have negotiated [`crate::Capability::Severity`], other clients get the message
+    /// as-is.
+    #[inline]
+    pub fn send_with_severity(&self, severity: Severity, data: impl ToString) {
+        self.inner.core.send_server_message(data, Some(severity));
+    }
+    /// Send a message to a single client. Targeted messages (including replies) are not recorded
+    /// in the history.
+    pub fn send_to(&self, client_id: usize, data: impl ToString) -> Result<(), Error> {
+        self.inner.core.send_to(
+            client_id,
+            Direction::ServerToClient,
+            data.to_string().into(),
+        )
+    }
+    /// Send a message to all clients except the specified one. The message is not recorded in
+    /// the history.
+    pub fn send_except(&self, client_id: usize, data: impl ToString) {
+        self.inner.core.send_except(
+            client_id,
+            Direction::ServerToClient,
+            data.to_string().into(),
+        );
+    }
+    /// Reply to the client the frame has been received from
+    #[inline]
+    pub fn reply(&self, frame: &IncomingFrame, data: impl ToString) -> Result<(), Error> {
+        self.inner.core.reply(frame, &data.to_string())
+    }
+    /// Enable TLS for the connections accepted after the call (requires `tls` feature). See
+    /// [`crate::tls`] for the configuration helpers.
+    #[cfg(feature = "tls")]
+    pub fn set_tls_config(&self, config: Arc<rustls::ServerConfig>) -> Result<(), Error> {
+        self.inner.core.set_tls_config(config);
+        Ok(())
+    }
+    /// Require authentication for the clients connected after the call. The function gets the
+    /// credentials, sent by a client, and returns the client identity or `None` to reject the
+    /// client. Clients with [`crate::Role::Observer`] receive messages but can not send them.
+    pub fn set_authenticator<F>(&self, method: AuthMethod, f: F) -> Result<(), Error>
+    where
+        F: Fn(&Credentials) -> Option<Identity> + Send + Sync + 'static,
+    {
+        self.inner
+            .core
+            .set_authenticator(Authenticator::new(method, f));
+        Ok(())
+    }
+    /// Set the Unix socket file permissions (e.g. `0o660`), applied by
+    /// [`ServerAsync::serve_unix()`] (default: the process umask)
+    #[cfg(unix)]
+    pub fn set_unix_socket_permissions(&self, mode: u32) -> Result<(), Error> {
+        self.inner.core.set_unix_socket_permissions(mode);
+        Ok(())
+    }
+    /// Serve the server
+    pub async fn serve(&self, addr: impl ToSocketAddrs) -> Result<(), Error> {
+        let listener = TcpListener::bind(addr).await?;
+        self.serve_with_listener(listener).await
+    }
+    /// Serve the server with the specified listener
+    ///
+    /// The method returns either on a listener error or after [`ServerAsync::shutdown()`] is
+    /// called and all the client connections are finished. If the future is dropped, all client
+    /// connections are aborted.
+    pub async fn serve_with_listener(&self, listener: TcpListener) -> Result<(), Error> {
+        trace!(addr = ?listener.local_addr(), "starting server");
+        self.serve_listener(Listener::Tcp(listener)).await
+    }
+    /// Serve the server on a Unix domain socket. A stale socket file, left by a crashed server,
+    /// is removed. The socket file is removed when the future is finished or dropped.
+    #[cfg(unix)]
+    pub async fn serve_unix(&self, path: impl AsRef<Path>) -> Result<(), Error> {
+        let path = path.as_ref();
+        crate::unix::remove_stale_socket(path)?;
+        let listener = crate::unix::bind(path, self.inner.core.unix_socket_permissions())?;
+        let _socket_file = crate::unix::SocketFile(path.to_owned());
+        listener.set_nonblocking(true)?;
+        let listener = UnixListener::from_std(listener)?;
+        self.serve_with_unix_listener(listener).await
+    }
+    /// Serve the server with the specified Unix domain socket listener
+    ///
+    /// The method returns either on a listener error or after [`ServerAsync::shutdown()`] is
+    /// called and all the client connections are finished. If the future is dropped, all client
+    /// connections are aborted.
+    #[cfg(unix)]
+    pub async fn serve_with_unix_listener(&self, listener: UnixListener) -> Result<(), Error> {
+        trace!(addr = ?listener.local_addr(), "starting server");
+        self.serve_listener(Listener::Unix(listener)).await
+    }
+    /// Serve a single client over a custom transport
+    ///
+    /// The method returns when the client is disconnected. The client is refused with an error
+    /// if the maximum number of clients is reached.
+    pub async fn serve_transport(&self, transport: impl TransportAsync) -> Result<(), Error> {
+        trace!("serving a transport client");
+        let (client_id, queue, history) = self
+            .inner
+            .core
+            .register_client(ClientAddr::Stream, self.inner.core.generation())?;
+        serve_transport(self.inner.clone(), transport, client_id, queue, history).await;
+        Ok(())
+    }
+    /// Connect an in-process client to the server. The connection uses in-memory pipes instead
+    /// of sockets, which is useful for testing
+    pub async fn connect_local(
+        &self,
+    ) -> Result<(ClientAsync, Receiver<(Direction, String)>), Error> {
+        self.connect_local_with_options(&ConnectionOptions::default())
+            .await
+    }
+    /// Connect an in-process client to the server with the defined options
+    pub async fn connect_local_with_options(
+        &self,
+        options: &ConnectionOptions,
+    ) -> Result<(ClientAsync, Receiver<(Direction, String)>), Error> {
+        let (client_stream, server_stream) = tokio::io::duplex(LOCAL_PIPE_BUFFER_SIZE);
+        let (client_id, queue, history) = self
+            .inner
+            .core
+            .register_client(ClientAddr::Stream, self.inner.core.generation())?;
+        tokio::spawn(serve_transport(
+            self.inner.clone(),
+            server_stream,
+            client_id,
+            queue,
+            history,
+        ));
+        ClientAsync::connect_transport(client_stream, options).await
+    }
+    async fn serve_listener(&self, listener: Listener) -> Result<(), Error> {
+        let mut shutdown_rx = self.inner.shutdown_tx.subscribe();
+        // the server may have been shut down before the call
+        if *shutdown_rx.borrow_and_update() {
+            trace!("server has been shut down");
+            return Ok(());
+        }
+        let semaphore = Arc::new(Semaphore::new(self.inner.core.max_clients()));
+        let mut workers = JoinSet::new();
+        loop {
+            let (socket, addr) = tokio::select! {
+                result = listener.accept() => {
+                    let Ok(v) = result else {
+                        break;
+                    };
+                    v
+                }
+                _ = shutdown_rx.changed() => break,
+            };
+            let generation = self.inner.core.generation();
+            trace!(%addr, "new connection");
+            let permission = tokio::select! {
+                result = semaphore.clone().acquire_owned() => {
+                    let Ok(v) = result else {
+                        break;
+                    };
+                    v
+                }
+                _ = shutdown_rx.changed() => break,
+            };
+            trace!(%addr, "handling connection");
+            let inner = self.inner.clone();
+            while workers.try_join_next().is_some() {}
+            workers.spawn(async move {
+                let _permission = permission;
+                let reject = |error: Error| {
+                    trace!(%addr, %error, "connection setup failed");
+                    inner
+                        .core
+                        .event(ServerEvent::Rejected(addr, error.to_string()));
+                };
+                // the client is registered after the connection is set up
+                let connection = match socket.upgrade(&inner.core).await {
+                    Ok(v) => v,
+                    Err(error) => return reject(error),
+                };
+                let (client_id, queue, history) = match inner.core.register_client(addr, generation)
+                {
+                    Ok(v) => v,
+                    Err(error) => return reject(error),
+                };
+                let mut guard = ClientGuard::new(inner, client_id);
+                let incoming_data_tx = guard.inner.incoming_data_tx.lock().clone();
+                let result = handle_connection(
+                    connection,
+                    client_id,
+                    addr,
+                    &guard.inner.core,
+                    incoming_data_tx,
+                    queue.clone(),
+                    &history,
+                )
+                .await;
+                guard.set_result(&result, &queue);
+            });
+        }
+        trace!("stopping server");
+        while workers.join_next().await.is_some() {}
+        Ok(())
+    }
+    /// Gracefully shut down the server: stop accepting new connections, send a goodbye message
+    /// to all clients and disconnect them.
+    ///
+    /// The method does not wait for the serving methods to finish, await
+    /// [`ServerAsync::serve()`]/[`ServerAsync::serve_with_listener()`] futures instead. The
+    /// shutdown is permanent: serving methods, called after, return immediately.
+    pub fn shutdown(&self) {
+        self.inner.shutdown_tx.send_replace(true);
+        self.inner.core.disconnect_all(GOODBYE_MESSAGE);
+    }
+}
+
+enum Listener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(UnixListener),
+}
+
+impl Listener {
+    async fn accept(&self) -> io::Result<(Socket, ClientAddr)> {
+        match self {
+            Listener::Tcp(l) => {
+                let (socket, addr) = l.accept().await?;
+                Ok((Socket::Tcp(socket), ClientAddr::Tcp(addr)))
+            }
+            #[cfg(unix)]
+            Listener::Unix(l) => {
+                let (socket, _) = l.accept().await?;
+                Ok((Socket::Unix(socket), ClientAddr::Unix))
+            }
+        }
+    }
+}
+
+enum Socket {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream),
+}
+
+impl Socket {
+    /// Sets up the accepted connection (TLS handshake)
+    #[cfg_attr(not(feature = "tls"), allow(clippy::unused_async))]
+    async fn upgrade(self, core: &Core) -> Result<Connection, Error> {
+        match self {
+            // TLS is used for TCP connections only
+            Socket::Tcp(socket) => {
+                socket.set_nodelay(true)?;
+                core.set_tcp_keepalive(SockRef::from(&socket))?;
+                #[cfg(feature = "tls")]
+                if let Some(config) = core.tls_config() {
+                    let acceptor = tokio_rustls::TlsAcceptor::from(config);
+                    let stream =
+                        tokio::time::timeout(core.timeout, acceptor.accept(socket)).await??;
+                    return Ok(Connection::Tls(Box::new(stream)));
+                }
+                Ok(Connection::Tcp(socket))
+            }
+            #[cfg(unix)]
+            Socket::Unix(socket) => Ok(Connection::Unix(socket)),
+        }
+    }
+}
+
+/// A connection, which has been set up and is ready to be served
+enum Connection {
+    Tcp(TcpStream),
+    #[cfg(feature = "tls")]
+    Tls(Box<tokio_rustls::server::TlsStream<TcpStream>>),
+    #[cfg(unix)]
+    Unix(UnixStream),
+}
+
+/// Unregisters the client when the connection task is finished or aborted
+struct ClientGuard {
+    inner: Arc<Inner>,
+    client_id: usize,
+    // aborted tasks are considered disconnected by the server
+    reason: DisconnectReason,
+}
+
+impl ClientGuard {
+    fn new(inner: Arc<Inner>, client_id: usize) -> Self {
+        Self {
+            inner,
+            client_id,
+            reason: DisconnectReason::Server,
+        }
+    }
+    fn set_result(&mut self, result: &Result<(), Error>, queue: &ClientQueue) {
+        self.reason = disconnect_reason(result.as_ref().err().map(|e| e as _), queue);
+    }
+}
+
+impl Drop for ClientGuard {
+    fn drop(&mut self) {
+        self.inner
+            .core
+            .unregister_client(self.client_id, self.reason.clone());
+    }
+}
+
+/// Serves a registered transport client
+async fn serve_transport(
+    inner: Arc<Inner>,
+    transport: impl TransportAsync,
+    client_id: usize,
+    queue: Arc<ClientQueue>,
+    history: Vec<OutgoingFrame>,
+) {
+    let mut guard = ClientGuard::new(inner, client_id);
+    let incoming_data_tx = guard.inner.incoming_data_tx.lock().clone();
+    let (reader, writer) = tokio::io::split(transport);
+    let result = handle_stream(
+        reader,
+        writer,
+        client_id,
+        ClientAddr::Stream,
+        &guard.inner.core,
+        incoming_data_tx,
+        queue.clone(),
+        &history,
+    )
+    .await;
+    guard.set_result(&result, &queue);
+}
+
+async fn handle_connection(
+    connection: Connection,
+    client_id: usize,
+    addr: ClientAddr,
+    core: &Core,
+    incoming_data_tx: Sender<IncomingFrame>,
+    queue: Arc<ClientQueue>,
+    history: &[OutgoingFrame],
+) -> Result<(), Error> {
+    match connection {
+        Connection::Tcp(socket) => {
+            let (reader, writer) = socket.into_split();
+            handle_stream(
+                reader,
+                writer,
+                client_id,
+                addr,
+                core,
+                incoming_data_tx,
+                queue,
+                history,
+            )
+            .await
+        }
+        #[cfg(feature = "tls")]
+        Connection::Tls(stream) => {
+            let (reader, writer) = tokio::io::split(*stream);
+            handle_stream(
+                reader,
+                writer,
+                client_id,
+                addr,
+                core,
+                incoming_data_tx,
+                queue,
+                history,
+            )
+            .await
+        }
+        #[cfg(unix)]
+        Connection::Unix(socket) => {
+            let (reader, writer) = socket.into_split();
+            handle_stream(
+                reader,
+                writer,
+                client_id,
+                addr,
+                core,
+                incoming_data_tx,
+                queue,
+                history,
+            )
+            .await
+        }
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn handle_stream<R, W>(
+    reader: R,
+    mut writer: W,
+    client_id: usize,
+    addr: ClientAddr,
+    core: &Core,
+    incoming_data_tx: Sender<IncomingFrame>,
+    queue: Arc<ClientQueue>,
+    history: &[OutgoingFrame],
+) -> Result<(), Error>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let authenticator = core.authenticator();
+    tokio::time::timeout(
+        core.timeout,
+        writer.write_all(
+            core.greeting(history, authenticator.as_ref().map(|a| a.method))
+                .as_bytes(),
+        ),
+    )
+    .await??;
+    let mut lines = LineReaderAsync::new(BufReader::new(reader), core.line_limit());
+    let mut encoder = MessageEncoder::new();
+    // without authentication, the hello is processed by the reader, as v1 clients may send
+    // nothing
+    let mut hello_expected = true;
+    let identity = if let Some(authenticator) = authenticator {
+        let mut line = tokio::time::timeout(core.timeout, lines.next_line())
+            .await??
+            .ok_or(Error::InvalidData)?;
+        if is_hello(&line) {
+            let hello = tokio::time::timeout(core.timeout, read_hello(&line, &mut lines)).await??;
+            let capabilities = core.negotiate(&hello);
+            tokio::time::timeout(
+                core.timeout,
+                writer.write_all(format_capabilities_ack(&capabilities).as_bytes()),
+            )
+            .await??;
+            encoder.set_capabilities(capabilities);
+            line = tokio::time::timeout(core.timeout, lines.next_line())
+                .await??
+                .ok_or(Error::InvalidData)?;
+        }
+        hello_expected = false;
+        let (identity, reply) = core.authenticate(&authenticator, client_id, addr, &line);
+        tokio::time::timeout(core.timeout, writer.write_all(reply.as_bytes())).await??;
+        let Some(identity) = identity else {
+            writer.shutdown().await.ok();
+            return Err(Error::AuthFailed(AUTH_FAILED_MESSAGE.to_owned()));
+        };
+        Some(identity)
+    } else {
+        None
+    };
+    tokio::time::timeout(
+        core.timeout,
+        writer.write_all(&format_history(history, &encoder)),
+    )
+    .await??;
+    let keepalive = core.keepalive();
+    let capabilities = encoder.capabilities().clone();
+    // the reader and the writer run in the same task, if one finishes, the other is cancelled
+    let result = tokio::select! {
+        result = handle_incoming(
+            lines,
+            client_id,
+            addr,
+            identity.as_ref(),
+            core,
+            incoming_data_tx,
+            hello_expected.then_some(&queue),
+            &capabilities,
+        ) => result,
+        () = handle_outgoing(writer, &queue, encoder, keepalive, core) => Ok(()),
+    };
+    trace!("shutting down connection");
+    result
+}
+
+/// Reads the rest of the client hello, which starts with the line
+async fn read_hello<R>(line: &str, lines: &mut LineReaderAsync<R>) -> Result<Greeting, Error>
+where
+    R: AsyncBufRead + Unpin,
+{
+    let mut parser = GreetingParser::new();
+    let mut line = line.to_owned();
+    loop {
+        if let Some(hello) = parser.process_line(&line)? {
+            return Ok(hello);
+        }
+        line = lines.next_line().await?.ok_or(Error::InvalidData)?;
+    }
+}
+
+/// If the queue is set, the first line may start the client hello. Otherwise the capabilities
+/// have already been negotiated.
+#[allow(clippy::too_many_arguments)]
+async fn handle_incoming(
+    mut lines: LineReaderAsync<impl AsyncBufRead + Unpin>,
+    client_id: usize,
+    addr: ClientAddr,
+    identity: Option<&Arc<Identity>>,
+    core: &Core,
+    incoming_data_tx: Sender<IncomingFrame>,
+    mut hello_queue: Option<&Arc<ClientQueue>>,
+    capabilities: &BTreeSet<Capability>,
+) -> Result<(), Error> {
+    let keepalive = core.keepalive();
+    let policy = core.incoming_policy();
+    let mut token_bucket = core.token_bucket();
+    let mut read_timeout = keepalive.read_timeout(capabilities);
+    let mut call_ids = capabilities.contains(&Capability::CallId);
+    loop {
+        let line = if let Some(timeout) = read_timeout {
+            let Ok(line) = tokio::time::timeout(timeout, lines.next_line()).await else {
+                trace!(%addr, "client idle timeout");
+                return Err(Error::Timeout);
+            };
+            line?
+        } else {
+            lines.next_line().await?
+        };
+        let Some(line) = line else {
+            break;
+        };
+        if let Some(queue) = hello_queue.take() {
+            if is_hello(&line) {
+                let capabilities = core.negotiate(&read_hello(&line, &mut lines).await?);
+                read_timeout = keepalive.read_timeout(&capabilities);
+                call_ids = capabilities.contains(&Capability::CallId);
+                queue.switch_capabilities(capabilities);
+                continue;
+            }
+        }
+        // pongs are reserved if keepalive is enabled
+        if keepalive.interval.is_some() && line == PONG {
+            continue;
+        }
+        if let Some(ref mut bucket) = token_bucket {
+            if !bucket.take() {
+                if bucket.notify() {
+                    core.reject_rate_limited(client_id, addr, &line, call_ids);
+                }
+                continue;
+            }
+        }
+        let Some(frame) = core.process_line(client_id, addr, identity, line, call_ids) else {
+            continue;
+        };
+        let result = match policy {
+            // the frame is echoed only if accepted
+            IncomingPolicy::Block => incoming_data_tx.send(frame.clone()).await.map(|()| {
+                core.echo(&frame);
+            }),
+            IncomingPolicy::Reject => match incoming_data_tx.try_send(frame.clone()) {
+                Ok(()) => {
+                    core.echo(&frame);
+                    Ok(())
+                }
+                Err(rtsc::Error::ChannelFull) => {
+                    core.reject_busy(&frame);
+                    Ok(())
+                }
+                Err(e) => Err(e),
+            },
+        };
+        if result.is_err() {
+            break;
+        }
+    }
+    Ok(())
+}
+
+async fn handle_outgoing(
+    mut writer: impl AsyncWrite + Unpin,
+    queue: &ClientQueue,
+    mut encoder: MessageEncoder,
+    keepalive: Keepalive,
+    core: &Core,
+) {
+    let mut ping_at = keepalive.next_ping(encoder.capabilities());
+    loop {
+        // pings are sent on schedule regardless of the traffic
+        let item = match ping_at {
+            Some(t) if Instant::now() >= t => Some(Outgoing::Ping),
+            Some(t) => tokio::time::timeout_at(t.into(), queue.pop_async())
+                .await
+                .unwrap_or(Some(Outgoing::Ping)),
+            None => queue.pop_async().await,
+        };
+        let Some(item) = item else {
+            break;
+        };
+        let mut buf = Vec::new();
+        match item {
+            Outgoing::Frame(frame) => encoder.encode(&frame.message(), &mut buf),
+            Outgoing::Capabilities(capabilities) => {
+                buf.extend(format_capabilities_ack(&capabilities).as_bytes());
+                encoder.set_capabilities(capabilities);
+                ping_at = keepalive.next_ping(encoder.capabilities());
+            }
+            Outgoing::Ping => {
+                buf.extend(PING.as_bytes());
+                buf.push(b'\n');
+                ping_at = keepalive.next_ping(encoder.capabilities());
+            }
+            Outgoing::Dropped(count) => encoder.encode(&dropped_message(count), &mut buf),
+        }
+        if !matches!(
+            tokio::time::timeout(core.timeout, writer.write_all(&buf)).await,
+            Ok(Ok(()))
+        ) {
+            trace!("writer error - shutting down");
+            break;
+        }
+    }
+    trace!("writer finished");
+    writer.shutdown().await.ok();
+}
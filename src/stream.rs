@@ -0,0 +1,27 @@
+use std::{fmt, net::SocketAddr};
+
+use crate::Transport;
+
+/// Client address
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum ClientAddr {
+    /// TCP/IP client
+    Tcp(SocketAddr),
+    /// Unix domain socket client
+    Unix,
+    /// A client, connected with a custom transport (e.g. a serial line)
+    Stream,
+}
+
+impl fmt::Display for ClientAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ClientAddr::Tcp(addr) => write!(f, "{}", addr),
+            ClientAddr::Unix => f.write_str("unix"),
+            ClientAddr::Stream => f.write_str("stream"),
+        }
+    }
+}
+
+/// A blocking connection stream
+pub(crate) type Stream = Box<dyn Transport>;
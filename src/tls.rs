@@ -0,0 +1,256 @@
+//! TLS support (requires `tls` feature)
+//!
+//! The module contains helpers to build TLS configurations from PEM files. Custom
+//! configurations can be built with the re-exported [`rustls`] crate.
+use std::{
+    fs::File,
+    io::{self, BufReader, Read, Write},
+    net::{Shutdown, TcpStream},
+    path::Path,
+    sync::Arc,
+    time::Duration,
+};
+
+pub use rustls;
+use rustls::{
+    crypto::CryptoProvider,
+    pki_types::{CertificateDer, PrivateKeyDer, ServerName},
+    server::WebPkiClientVerifier,
+    ClientConfig, ClientConnection, Connection, RootCertStore, ServerConfig, ServerConnection,
+};
+
+use crate::{Error, Mutex, Transport};
+
+const TLS_BUFFER_SIZE: usize = 16_384;
+
+impl From<rustls::Error> for Error {
+    fn from(error: rustls::Error) -> Self {
+        Self::Tls(error.to_string())
+    }
+}
+
+fn provider() -> Arc<CryptoProvider> {
+    Arc::new(rustls::crypto::ring::default_provider())
+}
+
+fn load_certs(path: &Path) -> Result<Vec<CertificateDer<'static>>, Error> {
+    let certs = rustls_pemfile::certs(&mut BufReader::new(File::open(path)?))
+        .collect::<Result<Vec<_>, _>>()?;
+    if certs.is_empty() {
+        return Err(Error::Tls(format!("no certificates in {}", path.display())));
+    }
+    Ok(certs)
+}
+
+fn load_key(path: &Path) -> Result<PrivateKeyDer<'static>, Error> {
+    rustls_pemfile::private_key(&mut BufReader::new(File::open(path)?))?
+        .ok_or_else(|| Error::Tls(format!("no private key in {}", path.display())))
+}
+
+fn load_roots(path: &Path) -> Result<RootCertStore, Error> {
+    let mut roots = RootCertStore::empty();
+    for cert in load_certs(path)? {
+        roots.add(cert)?;
+    }
+    Ok(roots)
+}
+
+/// Create a server configuration from PEM certificate chain and private key files
+pub fn server_config(
+    cert_path: impl AsRef<Path>,
+    key_path: impl AsRef<Path>,
+) -> Result<Arc<ServerConfig>, Error> {
+    let config = ServerConfig::builder_with_provider(provider())
+        .with_safe_default_protocol_versions()?
+        .with_no_client_auth()
+        .with_single_cert(
+            load_certs(cert_path.as_ref())?,
+            load_key(key_path.as_ref())?,
+        )?;
+    Ok(config.into())
+}
+
+/// Create a server configuration which requires clients to present certificates, signed by the
+/// authorities from the specified PEM file
+pub fn server_config_with_client_auth(
+    cert_path: impl AsRef<Path>,
+    key_path: impl AsRef<Path>,
+    client_ca_path: impl AsRef<Path>,
+) -> Result<Arc<ServerConfig>, Error> {
+    let roots = load_roots(client_ca_path.as_ref())?;
+    let verifier = WebPkiClientVerifier::builder_with_provider(roots.into(), provider())
+        .build()
+        .map_err(|e| Error::Tls(e.to_string()))?;
+    let config = ServerConfig::builder_with_provider(provider())
+        .with_safe_default_protocol_versions()?
+        .with_client_cert_verifier(verifier)
+        .with_single_cert(
+            load_certs(cert_path.as_ref())?,
+            load_key(key_path.as_ref())?,
+        )?;
+    Ok(config.into())
+}
+
+/// Create a client configuration which trusts the authorities from the specified PEM file
+pub fn client_config(ca_path: impl AsRef<Path>) -> Result<Arc<ClientConfig>, Error> {
+    let config = ClientConfig::builder_with_provider(provider())
+        .with_safe_default_protocol_versions()?
+        .with_root_certificates(load_roots(ca_path.as_ref())?)
+        .with_no_client_auth();
+    Ok(config.into())
+}
+
+/// Create a client configuration with a client certificate (for servers which require client
+/// certificate authentication)
+pub fn client_config_with_auth(
+    ca_path: impl AsRef<Path>,
+    cert_path: impl AsRef<Path>,
+    key_path: impl AsRef<Path>,
+) -> Result<Arc<ClientConfig>, Error> {
+    let config = ClientConfig::builder_with_provider(provider())
+        .with_safe_default_protocol_versions()?
+        .with_root_certificates(load_roots(ca_path.as_ref())?)
+        .with_client_auth_cert(
+            load_certs(cert_path.as_ref())?,
+            load_key(key_path.as_ref())?,
+        )?;
+    Ok(config.into())
+}
+
+pub(crate) fn server_name(name: &str) -> Result<ServerName<'static>, Error> {
+    ServerName::try_from(name.to_owned()).map_err(|_| Error::InvalidAddress)
+}
+
+/// A blocking TLS stream. Clones share the TLS session, so the stream can be read and written
+/// from different threads. The session is locked only to process the data, never while waiting
+/// for the socket to become readable.
+pub(crate) struct TlsStream {
+    conn: Arc<Mutex<Connection>>,
+    socket: TcpStream,
+    pending: Vec<u8>,
+}
+
+impl TlsStream {
+    /// Performs the server-side handshake
+    pub(crate) fn accept(
+        socket: TcpStream,
+        config: Arc<ServerConfig>,
+        timeout: Duration,
+    ) -> Result<Self, Error> {
+        Self::handshake(socket, ServerConnection::new(config)?.into(), timeout)
+    }
+    /// Performs the client-side handshake
+    pub(crate) fn connect(
+        socket: TcpStream,
+        config: Arc<ClientConfig>,
+        server_name: ServerName<'static>,
+        timeout: Duration,
+    ) -> Result<Self, Error> {
+        Self::handshake(
+            socket,
+            ClientConnection::new(config, server_name)?.into(),
+            timeout,
+        )
+    }
+    fn handshake(
+        mut socket: TcpStream,
+        mut conn: Connection,
+        timeout: Duration,
+    ) -> Result<Self, Error> {
+        let read_timeout = socket.read_timeout()?;
+        socket.set_read_timeout(Some(timeout))?;
+        while conn.is_handshaking() {
+            conn.complete_io(&mut socket)?;
+        }
+        socket.set_read_timeout(read_timeout)?;
+        Ok(Self {
+            conn: Arc::new(Mutex::new(conn)),
+            socket,
+            pending: Vec::new(),
+        })
+    }
+}
+
+impl Transport for TlsStream {
+    fn try_clone(&self) -> io::Result<Box<dyn Transport>> {
+        Ok(Box::new(Self {
+            conn: self.conn.clone(),
+            socket: self.socket.try_clone()?,
+            pending: Vec::new(),
+        }))
+    }
+    /// Sends close notification (best effort) and shuts down the socket
+    fn shutdown(&self) -> io::Result<()> {
+        {
+            let mut conn = self.conn.lock();
+            conn.send_close_notify();
+            write_pending_tls(&mut conn, &self.socket).ok();
+        }
+        self.socket.shutdown(Shutdown::Both)
+    }
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.socket.set_read_timeout(timeout)
+    }
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.socket.set_write_timeout(timeout)
+    }
+}
+
+fn write_pending_tls(conn: &mut Connection, mut socket: &TcpStream) -> io::Result<()> {
+    while conn.wants_write() {
+        conn.write_tls(&mut socket)?;
+    }
+    Ok(())
+}
+
+impl Read for TlsStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            {
+                let mut conn = self.conn.lock();
+                match conn.reader().read(buf) {
+                    Ok(n) => return Ok(n),
+                    Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                    Err(e) => return Err(e),
+                }
+                if !self.pending.is_empty() {
+                    let mut data = &self.pending[..];
+                    let n = conn.read_tls(&mut data)?;
+                    self.pending.drain(..n);
+                    conn.process_new_packets()
+                        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+                    write_pending_tls(&mut conn, &self.socket)?;
+                    if n > 0 {
+                        continue;
+                    }
+                }
+            }
+            // the session is not locked while waiting for the data
+            let mut raw = [0; TLS_BUFFER_SIZE];
+            let n = self.socket.read(&mut raw)?;
+            if n == 0 {
+                // let the session know about EOF
+                let mut conn = self.conn.lock();
+                conn.read_tls(&mut &raw[..0])?;
+                conn.process_new_packets()
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+                continue;
+            }
+            self.pending.extend_from_slice(&raw[..n]);
+        }
+    }
+}
+
+impl Write for TlsStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut conn = self.conn.lock();
+        let n = conn.writer().write(buf)?;
+        write_pending_tls(&mut conn, &self.socket)?;
+        Ok(n)
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        let mut conn = self.conn.lock();
+        conn.writer().flush()?;
+        write_pending_tls(&mut conn, &self.socket)
+    }
+}
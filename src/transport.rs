@@ -0,0 +1,151 @@
+use std::{
+    io::{self, Read, Write},
+    net::{Shutdown, TcpStream},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+
+#[cfg(feature = "async")]
+use tokio::io::{AsyncRead, AsyncWrite};
+
+use crate::Mutex;
+
+/// A blocking byte stream the protocol is transferred over (TCP, Unix domain sockets, TLS,
+/// serial lines, pipes etc.). The greeting, the headers and the message framing are handled by
+/// the server and the clients the same way for all transports.
+pub trait Transport: Read + Write + Send + 'static {
+    /// Create another handle of the same connection, used to read and write from different
+    /// threads
+    fn try_clone(&self) -> io::Result<Box<dyn Transport>>;
+    /// Shut down the connection. Reads, blocked in other handles, should return
+    fn shutdown(&self) -> io::Result<()>;
+    /// Set the read timeout, `None` blocks indefinitely (ignored by default)
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        let _ = timeout;
+        Ok(())
+    }
+    /// Set the write timeout, `None` blocks indefinitely (ignored by default)
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        let _ = timeout;
+        Ok(())
+    }
+}
+
+/// An asynchronous byte stream the protocol is transferred over, implemented for all tokio
+/// streams
+#[cfg(feature = "async")]
+pub trait TransportAsync: AsyncRead + AsyncWrite + Send + Unpin + 'static {}
+
+#[cfg(feature = "async")]
+impl<T> TransportAsync for T where T: AsyncRead + AsyncWrite + Send + Unpin + 'static {}
+
+impl Transport for Box<dyn Transport> {
+    fn try_clone(&self) -> io::Result<Box<dyn Transport>> {
+        (**self).try_clone()
+    }
+    fn shutdown(&self) -> io::Result<()> {
+        (**self).shutdown()
+    }
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        (**self).set_read_timeout(timeout)
+    }
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        (**self).set_write_timeout(timeout)
+    }
+}
+
+impl Transport for TcpStream {
+    fn try_clone(&self) -> io::Result<Box<dyn Transport>> {
+        Ok(Box::new(TcpStream::try_clone(self)?))
+    }
+    fn shutdown(&self) -> io::Result<()> {
+        TcpStream::shutdown(self, Shutdown::Both)
+    }
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        TcpStream::set_read_timeout(self, timeout)
+    }
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        TcpStream::set_write_timeout(self, timeout)
+    }
+}
+
+#[cfg(unix)]
+impl Transport for UnixStream {
+    fn try_clone(&self) -> io::Result<Box<dyn Transport>> {
+        Ok(Box::new(UnixStream::try_clone(self)?))
+    }
+    fn shutdown(&self) -> io::Result<()> {
+        UnixStream::shutdown(self, Shutdown::Both)
+    }
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        UnixStream::set_read_timeout(self, timeout)
+    }
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        UnixStream::set_write_timeout(self, timeout)
+    }
+}
+
+/// A generic byte stream, split into a reader and a writer. Clones share the same stream.
+#[derive(Clone)]
+pub(crate) struct IoStream {
+    reader: Arc<Mutex<Box<dyn Read + Send>>>,
+    writer: Arc<Mutex<Box<dyn Write + Send>>>,
+    closed: Arc<AtomicBool>,
+}
+
+impl IoStream {
+    pub(crate) fn new<R, W>(reader: R, writer: W) -> Self
+    where
+        R: Read + Send + 'static,
+        W: Write + Send + 'static,
+    {
+        Self {
+            reader: Arc::new(Mutex::new(Box::new(reader))),
+            writer: Arc::new(Mutex::new(Box::new(writer))),
+            closed: <_>::default(),
+        }
+    }
+    fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::Relaxed)
+    }
+}
+
+impl Transport for IoStream {
+    fn try_clone(&self) -> io::Result<Box<dyn Transport>> {
+        Ok(Box::new(self.clone()))
+    }
+    /// Marks the stream closed. A blocked read can not be interrupted, so it returns on the next
+    /// incoming data only.
+    fn shutdown(&self) -> io::Result<()> {
+        self.closed.store(true, Ordering::Relaxed);
+        self.writer.lock().flush()
+    }
+}
+
+impl Read for IoStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.is_closed() {
+            return Ok(0);
+        }
+        let n = self.reader.lock().read(buf)?;
+        Ok(if self.is_closed() { 0 } else { n })
+    }
+}
+
+impl Write for IoStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.is_closed() {
+            return Err(io::ErrorKind::NotConnected.into());
+        }
+        self.writer.lock().write(buf)
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.lock().flush()
+    }
+}
@@ -0,0 +1,69 @@
+use std::{
+    ffi::OsString,
+    fs, io,
+    os::unix::{
+        fs::{DirBuilderExt as _, FileTypeExt as _, PermissionsExt as _},
+        net::{UnixListener, UnixStream},
+    },
+    path::{Path, PathBuf},
+};
+
+use tracing::trace;
+
+/// Removes a stale socket file, left by a crashed server. Returns an error if the path is not a
+/// socket or another server is listening on it.
+pub(crate) fn remove_stale_socket(path: &Path) -> io::Result<()> {
+    let metadata = match fs::symlink_metadata(path) {
+        Ok(v) => v,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e),
+    };
+    if !metadata.file_type().is_socket() {
+        return Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            format!("{} exists and is not a socket", path.display()),
+        ));
+    }
+    if UnixStream::connect(path).is_ok() {
+        return Err(io::Error::new(
+            io::ErrorKind::AddrInUse,
+            format!("{} is in use by another server", path.display()),
+        ));
+    }
+    trace!(path = %path.display(), "removing stale socket");
+    fs::remove_file(path)
+}
+
+/// Binds a listener to the path. If the permissions are set, the socket is bound in a private
+/// directory next to the path and moved to the path after the permissions are applied, so it is
+/// never reachable with the default ones.
+pub(crate) fn bind(path: &Path, mode: Option<u32>) -> io::Result<UnixListener> {
+    let Some(mode) = mode else {
+        return UnixListener::bind(path);
+    };
+    let file_name = path
+        .file_name()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "invalid socket path"))?;
+    let mut dir_name = OsString::from(".");
+    dir_name.push(file_name);
+    dir_name.push(format!(".{}", std::process::id()));
+    let dir = path.with_file_name(dir_name);
+    fs::DirBuilder::new().mode(0o700).create(&dir)?;
+    let private_path = dir.join("socket");
+    let result = UnixListener::bind(&private_path).and_then(|listener| {
+        fs::set_permissions(&private_path, fs::Permissions::from_mode(mode))?;
+        fs::rename(&private_path, path)?;
+        Ok(listener)
+    });
+    fs::remove_dir_all(&dir).ok();
+    result
+}
+
+/// Removes the socket file when the server stops
+pub(crate) struct SocketFile(pub(crate) PathBuf);
+
+impl Drop for SocketFile {
+    fn drop(&mut self) {
+        fs::remove_file(&self.0).ok();
+    }
+}
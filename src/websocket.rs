@@ -0,0 +1,297 @@
+use std::{
+    io::{self, Read, Write},
+    net::{Shutdown, TcpStream},
+    sync::Arc,
+    time::Duration,
+};
+
+use tracing::trace;
+use tungstenite::{handshake::derive_accept_key, protocol::Role, Message, WebSocket};
+
+use crate::{Mutex, Transport};
+
+const MAX_REQUEST_SIZE: usize = 8192;
+
+const CHAT_PAGE: &str = include_str!("websocket.html");
+
+/// Performs the HTTP part of a WebSocket connection. Upgrade requests are switched to the
+/// WebSocket protocol, other GET requests are served with the embedded chat page. Returns `None`
+/// if the connection has not been upgraded.
+///
+/// Upgrade requests from foreign origins (sent by browsers on behalf of other sites) are
+/// rejected, unless the origin is allowed.
+pub(crate) fn accept(
+    mut socket: TcpStream,
+    timeout: Duration,
+    allowed_origins: &[String],
+) -> io::Result<Option<WsStream>> {
+    socket.set_read_timeout(Some(timeout))?;
+    socket.set_write_timeout(Some(timeout))?;
+    let request = read_request(&mut socket)?;
+    socket.set_read_timeout(None)?;
+    let mut lines = request.split("\r\n");
+    let mut request_line = lines.next().unwrap_or_default().split_whitespace();
+    let method = request_line.next().unwrap_or_default();
+    let path = request_line.next().unwrap_or_default();
+    let mut upgrade = false;
+    let mut key = None;
+    let mut host = None;
+    let mut origin = None;
+    for line in lines {
+        let Some((name, value)) = line.split_once(':') else {
+            continue;
+        };
+        let name = name.trim();
+        let value = value.trim();
+        if name.eq_ignore_ascii_case("upgrade") {
+            upgrade = value.eq_ignore_ascii_case("websocket");
+        } else if name.eq_ignore_ascii_case("sec-websocket-key") {
+            key = Some(value);
+        } else if name.eq_ignore_ascii_case("host") {
+            host = Some(value);
+        } else if name.eq_ignore_ascii_case("origin") {
+            origin = Some(value);
+        }
+    }
+    if method != "GET" {
+        trace!(method, "invalid HTTP method");
+        respond(&mut socket, "405 Method Not Allowed", "text/plain", "")?;
+        return Ok(None);
+    }
+    if upgrade {
+        let Some(key) = key else {
+            respond(&mut socket, "400 Bad Request", "text/plain", "")?;
+            return Ok(None);
+        };
+        if let Some(origin) = origin {
+            if !origin_allowed(origin, host, allowed_origins) {
+                trace!(origin, "WebSocket origin rejected");
+                respond(&mut socket, "403 Forbidden", "text/plain", "")?;
+                return Err(io::Error::new(
+                    io::ErrorKind::PermissionDenied,
+                    format!("origin not allowed: {}", origin),
+                ));
+            }
+        }
+        write!(
+            socket,
+            "HTTP/1.1 101 Switching Protocols\r\n\
+            Upgrade: websocket\r\n\
+            Connection: Upgrade\r\n\
+            Sec-WebSocket-Accept: {}\r\n\r\n",
+            derive_accept_key(key.as_bytes())
+        )?;
+        trace!("connection upgraded to WebSocket");
+        return Ok(Some(WsStream::new(socket)?));
+    }
+    match path {
+        "/" | "/index.html" => {
+            respond(&mut socket, "200 OK", "text/html; charset=utf-8", CHAT_PAGE)?;
+        }
+        _ => respond(&mut socket, "404 Not Found", "text/plain", "")?,
+    }
+    socket.shutdown(Shutdown::Both).ok();
+    Ok(None)
+}
+
+/// Requests without the origin are sent by non-browser clients and are not checked. Browsers
+/// are allowed to connect from the pages, served by the server itself (the origin host matches
+/// the requested one), and from the allowed origins.
+fn origin_allowed(origin: &str, host: Option<&str>, allowed_origins: &[String]) -> bool {
+    if allowed_origins
+        .iter()
+        .any(|o| o == "*" || o.eq_ignore_ascii_case(origin))
+    {
+        return true;
+    }
+    let origin_host = origin.split_once("://").map(|(_, host)| host);
+    matches!((origin_host, host), (Some(o), Some(h)) if o.eq_ignore_ascii_case(h))
+}
+
+/// Reads the HTTP request head. The socket is read byte-by-byte to leave the data which follows
+/// the head for the WebSocket protocol.
+fn read_request(socket: &mut TcpStream) -> io::Result<String> {
+    let mut buf = Vec::new();
+    let mut byte = [0u8];
+    while !buf.ends_with(b"\r\n\r\n") {
+        if buf.len() >= MAX_REQUEST_SIZE {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "HTTP request is too large",
+            ));
+        }
+        if socket.read(&mut byte)? == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        buf.push(byte[0]);
+    }
+    String::from_utf8(buf).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+fn respond(socket: &mut TcpStream, status: &str, content_type: &str, body: &str) -> io::Result<()> {
+    write!(
+        socket,
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        content_type,
+        body.len(),
+        body
+    )?;
+    socket.flush()
+}
+
+/// A WebSocket connection which transfers each RFlow line as a single text frame. Clones share
+/// the connection, so the stream can be read and written from different threads. The connection
+/// is locked only to process the data, never while waiting for the socket to become readable.
+pub(crate) struct WsStream {
+    ws: Arc<Mutex<WebSocket<WsSocket>>>,
+    // a handle to receive incoming data without locking the connection
+    socket: TcpStream,
+    // incoming data which has not been read yet
+    pending: Vec<u8>,
+    // outgoing incomplete line
+    partial: Vec<u8>,
+}
+
+/// The socket of the WebSocket protocol. Writes go directly to the socket, reads are served from
+/// the data, received by the reader outside of the connection lock. The socket always stays in
+/// the blocking mode, as its handles are shared with the writers.
+struct WsSocket {
+    socket: TcpStream,
+    incoming: Vec<u8>,
+}
+
+impl Read for WsSocket {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.incoming.is_empty() {
+            return Err(io::ErrorKind::WouldBlock.into());
+        }
+        let len = buf.len().min(self.incoming.len());
+        buf[..len].copy_from_slice(&self.incoming[..len]);
+        self.incoming.drain(..len);
+        Ok(len)
+    }
+}
+
+impl Write for WsSocket {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.socket.write(buf)
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        self.socket.flush()
+    }
+}
+
+impl WsStream {
+    fn new(socket: TcpStream) -> io::Result<Self> {
+        Ok(Self {
+            socket: socket.try_clone()?,
+            ws: Arc::new(Mutex::new(WebSocket::from_raw_socket(
+                WsSocket {
+                    socket,
+                    incoming: Vec::new(),
+                },
+                Role::Server,
+                None,
+            ))),
+            pending: Vec::new(),
+            partial: Vec::new(),
+        })
+    }
+    /// Reads a message if the received data contain a complete one
+    fn try_read_message(&self, received: &[u8]) -> io::Result<Option<Message>> {
+        let mut ws = self.ws.lock();
+        ws.get_mut().incoming.extend_from_slice(received);
+        match ws.read() {
+            Ok(message) => Ok(Some(message)),
+            Err(tungstenite::Error::Io(e)) if e.kind() == io::ErrorKind::WouldBlock => Ok(None),
+            Err(tungstenite::Error::ConnectionClosed | tungstenite::Error::AlreadyClosed) => {
+                Ok(Some(Message::Close(None)))
+            }
+            Err(e) => Err(io_error(e)),
+        }
+    }
+}
+
+impl Transport for WsStream {
+    fn try_clone(&self) -> io::Result<Box<dyn Transport>> {
+        Ok(Box::new(Self {
+            ws: self.ws.clone(),
+            socket: self.socket.try_clone()?,
+            pending: Vec::new(),
+            partial: Vec::new(),
+        }))
+    }
+    /// Sends the close frame and shuts down the socket
+    fn shutdown(&self) -> io::Result<()> {
+        {
+            let mut ws = self.ws.lock();
+            ws.close(None).ok();
+            ws.flush().ok();
+        }
+        self.socket.shutdown(Shutdown::Both)
+    }
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.socket.set_read_timeout(timeout)
+    }
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.socket.set_write_timeout(timeout)
+    }
+}
+
+fn io_error(error: tungstenite::Error) -> io::Error {
+    match error {
+        tungstenite::Error::Io(e) => e,
+        tungstenite::Error::ConnectionClosed | tungstenite::Error::AlreadyClosed => {
+            io::ErrorKind::NotConnected.into()
+        }
+        e => io::Error::new(io::ErrorKind::InvalidData, e),
+    }
+}
+
+impl Read for WsStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut received = [0u8; 4096];
+        let mut len = 0;
+        while self.pending.is_empty() {
+            let Some(message) = self.try_read_message(&received[..len])? else {
+                // the connection is not locked while waiting for the data, the read timeout
+                // applies
+                len = self.socket.read(&mut received)?;
+                if len == 0 {
+                    return Ok(0);
+                }
+                continue;
+            };
+            len = 0;
+            match message {
+                Message::Text(text) => self.pending = text.into_bytes(),
+                Message::Binary(data) => self.pending = data,
+                Message::Close(_) => return Ok(0),
+                Message::Ping(_) | Message::Pong(_) | Message::Frame(_) => continue,
+            }
+            self.pending.push(b'\n');
+        }
+        let len = buf.len().min(self.pending.len());
+        buf[..len].copy_from_slice(&self.pending[..len]);
+        self.pending.drain(..len);
+        Ok(len)
+    }
+}
+
+impl Write for WsStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.partial.extend(buf);
+        let mut ws = self.ws.lock();
+        while let Some(pos) = self.partial.iter().position(|&b| b == b'\n') {
+            let line: Vec<u8> = self.partial.drain(..=pos).collect();
+            let text = String::from_utf8_lossy(&line[..pos]);
+            ws.send(Message::Text(text.trim_end_matches('\r').to_owned()))
+                .map_err(io_error)?;
+        }
+        Ok(buf.len())
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        self.ws.lock().flush().map_err(io_error)
+    }
+}
@@ -0,0 +1,112 @@
+#![cfg(feature = "tls")]
+#![allow(dead_code)]
+
+use std::{fs, path::PathBuf, sync::Arc, time::Duration};
+
+use rcgen::{
+    BasicConstraints, Certificate, CertificateParams, DnType, ExtendedKeyUsagePurpose, IsCa,
+    KeyPair,
+};
+use rflow::{
+    tls::{self, rustls},
+    ConnectionOptions,
+};
+
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Certificates and keys, generated for a test and stored as PEM files in a temporary directory
+pub struct Pki {
+    dir: PathBuf,
+}
+
+impl Pki {
+    /// Generates the test authority "ca", the server certificate "server" for `localhost` and the
+    /// client certificate "client", signed by the authority, and an unrelated authority "other"
+    pub fn generate(test: &str) -> Self {
+        let dir = std::env::temp_dir().join(format!("rflow-tls-{}-{}", std::process::id(), test));
+        fs::create_dir_all(&dir).unwrap();
+        let pki = Self { dir };
+        let (ca, ca_key) = pki.authority("ca");
+        pki.authority("other");
+        pki.leaf(
+            "server",
+            "localhost",
+            ExtendedKeyUsagePurpose::ServerAuth,
+            &ca,
+            &ca_key,
+        );
+        pki.leaf(
+            "client",
+            "client",
+            ExtendedKeyUsagePurpose::ClientAuth,
+            &ca,
+            &ca_key,
+        );
+        pki
+    }
+    fn authority(&self, name: &str) -> (Certificate, KeyPair) {
+        let mut params = CertificateParams::new(Vec::<String>::new()).unwrap();
+        params
+            .distinguished_name
+            .push(DnType::CommonName, format!("rflow test {}", name));
+        params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        let key = KeyPair::generate().unwrap();
+        let cert = params.self_signed(&key).unwrap();
+        fs::write(self.cert(name), cert.pem()).unwrap();
+        (cert, key)
+    }
+    fn leaf(
+        &self,
+        name: &str,
+        subject: &str,
+        usage: ExtendedKeyUsagePurpose,
+        ca: &Certificate,
+        ca_key: &KeyPair,
+    ) {
+        let mut params = CertificateParams::new(vec![subject.to_owned()]).unwrap();
+        params.extended_key_usages = vec![usage];
+        let key = KeyPair::generate().unwrap();
+        let cert = params.signed_by(&key, ca, ca_key).unwrap();
+        fs::write(self.cert(name), cert.pem()).unwrap();
+        fs::write(self.key(name), key.serialize_pem()).unwrap();
+    }
+    fn cert(&self, name: &str) -> PathBuf {
+        self.dir.join(format!("{}.crt", name))
+    }
+    fn key(&self, name: &str) -> PathBuf {
+        self.dir.join(format!("{}.key", name))
+    }
+    pub fn server_config(&self) -> Arc<rustls::ServerConfig> {
+        tls::server_config(self.cert("server"), self.key("server")).unwrap()
+    }
+    pub fn server_config_with_client_auth(&self) -> Arc<rustls::ServerConfig> {
+        tls::server_config_with_client_auth(
+            self.cert("server"),
+            self.key("server"),
+            self.cert("ca"),
+        )
+        .unwrap()
+    }
+    pub fn client_config(&self, ca: &str) -> Arc<rustls::ClientConfig> {
+        tls::client_config(self.cert(ca)).unwrap()
+    }
+    pub fn client_options(&self, ca: &str, server_name: &str) -> ConnectionOptions {
+        ConnectionOptions::new()
+            .timeout(TIMEOUT)
+            .tls(self.client_config(ca), server_name)
+    }
+    pub fn client_options_with_auth(&self) -> ConnectionOptions {
+        let config =
+            tls::client_config_with_auth(self.cert("ca"), self.cert("client"), self.key("client"))
+                .unwrap();
+        ConnectionOptions::new()
+            .timeout(TIMEOUT)
+            .tls(config, "localhost")
+    }
+}
+
+impl Drop for Pki {
+    fn drop(&mut self) {
+        fs::remove_dir_all(&self.dir).ok();
+    }
+}
@@ -0,0 +1,301 @@
+//! The same protocol checks, run over all the transports
+
+use std::{
+    net::TcpListener,
+    thread,
+    time::{Duration, Instant},
+};
+
+use rflow::{
+    AuthMethod, Client, ConnectionOptions, Credentials, Direction, Error, Identity, Role, Server,
+};
+
+mod common;
+
+const TIMEOUT: Duration = Duration::from_secs(5);
+const TOKEN: &str = "secret";
+
+/// Creates a server which requires authentication and replies to calls
+fn server() -> Server {
+    let server = Server::new(TIMEOUT);
+    server.set_header("Application", "conformance").unwrap();
+    server
+        .set_authenticator(AuthMethod::Token, |credentials| match credentials {
+            Credentials::Token(token) if token == TOKEN => {
+                Some(Identity::new("operator", Role::Operator))
+            }
+            _ => None,
+        })
+        .unwrap();
+    let data_channel = server.take_data_channel().unwrap();
+    let s = server.clone();
+    thread::spawn(move || {
+        for frame in data_channel {
+            if frame.call_id().is_some() {
+                s.reply(&frame, frame.data().to_uppercase()).ok();
+            }
+        }
+    });
+    server
+}
+
+fn options(token: &str) -> ConnectionOptions {
+    ConnectionOptions::new()
+        .timeout(TIMEOUT)
+        .credentials(Credentials::Token(token.to_owned()))
+}
+
+/// Runs the checks with clients, connected by the function
+fn conformance<F, R>(server: &Server, connect: F)
+where
+    F: Fn(ConnectionOptions) -> Result<(Client, R), Error>,
+    R: IntoIterator<Item = (Direction, String)>,
+{
+    // auth
+    assert!(connect(options("invalid")).is_err());
+    let (client1, rx1) = connect(options(TOKEN)).unwrap();
+    let (client2, rx2) = connect(options(TOKEN)).unwrap();
+    let mut rx1 = rx1.into_iter();
+    let mut rx2 = rx2.into_iter();
+    // greeting
+    let headers = client1.headers();
+    assert_eq!(headers.get("Application").unwrap(), "conformance");
+    assert_eq!(headers.get("Auth").unwrap(), "token");
+    assert_eq!(client1.role(), Some(Role::Operator));
+    for capability in ["block", "call-id"] {
+        assert!(client1
+            .capabilities()
+            .iter()
+            .any(|c| c.as_str() == capability));
+    }
+    // echo, which is sent to all clients
+    client1.try_send("hello").unwrap();
+    for rx in [&mut rx1, &mut rx2] {
+        assert_eq!(
+            rx.next().unwrap(),
+            (Direction::ClientToServer, "hello".to_owned())
+        );
+    }
+    // broadcast, multi-line messages are transferred as blocks
+    server.send("two\nlines");
+    for rx in [&mut rx1, &mut rx2] {
+        assert_eq!(
+            rx.next().unwrap(),
+            (Direction::ServerToClient, "two\nlines".to_owned())
+        );
+    }
+    // call ids, the reply is delivered to the caller only
+    assert_eq!(client2.call("ping", TIMEOUT).unwrap(), "PING");
+    // the request is echoed after it is accepted, so the echo may follow the reply
+    for rx in [&mut rx1, &mut rx2] {
+        assert_eq!(rx.next().unwrap().1, "ping");
+    }
+    server.send("after call");
+    for rx in [&mut rx1, &mut rx2] {
+        assert_eq!(rx.next().unwrap().1, "after call");
+    }
+    // disconnect
+    drop(client1);
+    let started = Instant::now();
+    while server.clients().len() > 1 {
+        assert!(
+            started.elapsed() < TIMEOUT,
+            "the client is not disconnected"
+        );
+        thread::sleep(Duration::from_millis(10));
+    }
+    server.shutdown();
+    assert!(rx2.any(|(_, data)| data == "server shutdown"));
+}
+
+#[test]
+fn tcp() {
+    let server = server();
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let s = server.clone();
+    thread::spawn(move || s.serve_with_listener(listener));
+    conformance(&server, |options| {
+        Client::connect_with_options(addr, &options)
+    });
+}
+
+#[cfg(feature = "tls")]
+#[test]
+fn tls() {
+    let pki = common::Pki::generate("conformance");
+    let server = server();
+    server.set_tls_config(pki.server_config()).unwrap();
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let s = server.clone();
+    thread::spawn(move || s.serve_with_listener(listener));
+    let config = pki.client_config("ca");
+    conformance(&server, |options| {
+        Client::connect_with_options(addr, &options.tls(config.clone(), "localhost"))
+    });
+}
+
+#[cfg(unix)]
+#[test]
+fn unix() {
+    let path = std::env::temp_dir().join(format!("rflow-conformance-{}.sock", std::process::id()));
+    let server = server();
+    let s = server.clone();
+    let p = path.clone();
+    thread::spawn(move || s.serve_unix(p));
+    let started = Instant::now();
+    while !path.exists() {
+        assert!(started.elapsed() < TIMEOUT);
+        thread::sleep(Duration::from_millis(10));
+    }
+    conformance(&server, |options| {
+        Client::connect_unix_with_options(&path, &options)
+    });
+}
+
+#[cfg(feature = "websocket")]
+#[test]
+fn websocket() {
+    let server = server();
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let s = server.clone();
+    thread::spawn(move || s.serve_with_websocket_listener(listener));
+    conformance(&server, |options| {
+        Client::connect_transport(ws::WsTransport::connect(addr), &options)
+    });
+}
+
+#[test]
+fn local() {
+    let server = server();
+    conformance(&server, |options| {
+        server.connect_local_with_options(&options)
+    });
+}
+
+#[cfg(unix)]
+#[test]
+fn stream() {
+    use std::os::unix::net::UnixStream;
+
+    let server = server();
+    conformance(&server, |options| {
+        let (server_socket, client_socket) = UnixStream::pair().unwrap();
+        let s = server.clone();
+        thread::spawn(move || {
+            let writer = server_socket.try_clone().unwrap();
+            s.serve_stream(server_socket, writer)
+        });
+        Client::connect_transport(client_socket, &options)
+    });
+}
+
+/// A client-side WebSocket transport, each line is transferred as a single text frame
+#[cfg(feature = "websocket")]
+mod ws {
+    use std::{
+        io::{self, Read, Write},
+        net::{Shutdown, SocketAddr, TcpStream},
+        sync::{Arc, Mutex},
+        time::Duration,
+    };
+
+    use rflow::Transport;
+    use tungstenite::{Message, WebSocket};
+
+    pub struct WsTransport {
+        ws: Arc<Mutex<WebSocket<TcpStream>>>,
+        socket: TcpStream,
+        pending: Vec<u8>,
+        partial: Vec<u8>,
+    }
+
+    impl WsTransport {
+        pub fn connect(addr: SocketAddr) -> Self {
+            let socket = TcpStream::connect(addr).unwrap();
+            let (ws, _) = tungstenite::client(format!("ws://{}/", addr), socket).unwrap();
+            Self {
+                socket: ws.get_ref().try_clone().unwrap(),
+                ws: Arc::new(Mutex::new(ws)),
+                pending: Vec::new(),
+                partial: Vec::new(),
+            }
+        }
+        /// Reads a message without blocking, so the connection is not locked while waiting
+        fn try_read_message(&self) -> io::Result<Option<Message>> {
+            let mut ws = self.ws.lock().unwrap();
+            self.socket.set_nonblocking(true)?;
+            let result = ws.read();
+            self.socket.set_nonblocking(false)?;
+            match result {
+                Ok(message) => Ok(Some(message)),
+                Err(tungstenite::Error::Io(e)) if e.kind() == io::ErrorKind::WouldBlock => Ok(None),
+                Err(_) => Ok(Some(Message::Close(None))),
+            }
+        }
+    }
+
+    impl Transport for WsTransport {
+        fn try_clone(&self) -> io::Result<Box<dyn Transport>> {
+            Ok(Box::new(Self {
+                ws: self.ws.clone(),
+                socket: self.socket.try_clone()?,
+                pending: Vec::new(),
+                partial: Vec::new(),
+            }))
+        }
+        fn shutdown(&self) -> io::Result<()> {
+            self.ws.lock().unwrap().close(None).ok();
+            self.socket.shutdown(Shutdown::Both)
+        }
+        fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+            self.socket.set_read_timeout(timeout)
+        }
+        fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+            self.socket.set_write_timeout(timeout)
+        }
+    }
+
+    impl Read for WsTransport {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            while self.pending.is_empty() {
+                match self.try_read_message()? {
+                    Some(Message::Text(text)) => {
+                        self.pending = text.into_bytes();
+                        self.pending.push(b'\n');
+                    }
+                    Some(Message::Close(_)) => return Ok(0),
+                    Some(_) => {}
+                    None => {
+                        if self.socket.peek(&mut [0])? == 0 {
+                            return Ok(0);
+                        }
+                    }
+                }
+            }
+            let len = buf.len().min(self.pending.len());
+            buf[..len].copy_from_slice(&self.pending[..len]);
+            self.pending.drain(..len);
+            Ok(len)
+        }
+    }
+
+    impl Write for WsTransport {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.partial.extend(buf);
+            let mut ws = self.ws.lock().unwrap();
+            while let Some(pos) = self.partial.iter().position(|&b| b == b'\n') {
+                let line: Vec<u8> = self.partial.drain(..=pos).collect();
+                let text = String::from_utf8_lossy(&line[..pos]).into_owned();
+                ws.send(Message::Text(text))
+                    .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+            }
+            Ok(buf.len())
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+}
@@ -0,0 +1,71 @@
+#![cfg(feature = "derive")]
+
+use rflow::{router::CommandError, RflowCommand};
+
+#[derive(RflowCommand, Debug, PartialEq)]
+enum Cmd {
+    /// start the motor
+    Start,
+    /// set the motor speed
+    SetSpeed { rpm: u32, ramp: Option<u32> },
+    #[rflow(name = "go", help = "move to the position")]
+    MoveTo(i32, Option<i32>),
+}
+
+#[test]
+fn parse() {
+    assert_eq!(Cmd::parse("start").unwrap(), Cmd::Start);
+    assert_eq!(
+        Cmd::parse("set-speed 100").unwrap(),
+        Cmd::SetSpeed {
+            rpm: 100,
+            ramp: None
+        }
+    );
+    assert_eq!(
+        Cmd::parse("  set-speed   100 5 ").unwrap(),
+        Cmd::SetSpeed {
+            rpm: 100,
+            ramp: Some(5)
+        }
+    );
+    assert_eq!(Cmd::parse("go -5").unwrap(), Cmd::MoveTo(-5, None));
+}
+
+#[test]
+fn parse_errors() {
+    assert!(matches!(
+        Cmd::parse("set-speed"),
+        Err(CommandError::MissingArgument(name)) if name == "rpm"
+    ));
+    assert!(matches!(
+        Cmd::parse("set-speed 1 2 3"),
+        Err(CommandError::TooManyArguments)
+    ));
+    assert!(matches!(
+        Cmd::parse("set-speed fast"),
+        Err(CommandError::InvalidArgument { name, value }) if name == "rpm" && value == "fast"
+    ));
+    assert!(matches!(
+        Cmd::parse("start now"),
+        Err(CommandError::TooManyArguments)
+    ));
+    assert!(matches!(
+        Cmd::parse("move-to 1"),
+        Err(CommandError::Unknown(name)) if name == "move-to"
+    ));
+}
+
+#[test]
+fn help() {
+    let usages: Vec<&str> = Cmd::commands().iter().map(|c| c.usage).collect();
+    assert_eq!(
+        usages,
+        ["start", "set-speed <rpm> [ramp]", "go <arg1> [arg2]"]
+    );
+    assert_eq!(
+        Cmd::help(),
+        "commands: help [COMMAND]; start - start the motor; \
+        set-speed <rpm> [ramp] - set the motor speed; go <arg1> [arg2] - move to the position"
+    );
+}
@@ -0,0 +1,110 @@
+use std::{collections::BTreeSet, thread, time::Duration};
+
+use rflow::{
+    protocol::{Message, MessageDecoder, MessageEncoder},
+    Capability, Direction, Server,
+};
+
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+fn encode(capabilities: &BTreeSet<Capability>, data: &str) -> String {
+    let mut encoder = MessageEncoder::new();
+    encoder.set_capabilities(capabilities.clone());
+    let mut buf = Vec::new();
+    encoder.encode(&Message::new(Direction::ServerToClient, data), &mut buf);
+    String::from_utf8(buf).unwrap()
+}
+
+/// Decodes the encoded lines, returns the reassembled messages
+fn decode(capabilities: &BTreeSet<Capability>, encoded: &str) -> Vec<(Direction, String)> {
+    let mut decoder = MessageDecoder::new();
+    decoder.set_capabilities(capabilities);
+    encoded
+        .split_inclusive('\n')
+        .filter_map(|line| decoder.decode_message(line).unwrap())
+        .map(|m| (m.direction, m.data.into_owned()))
+        .collect()
+}
+
+/// Decodes the encoded lines one by one, as clients without blocks do
+fn decode_lines(encoded: &str) -> Vec<(Direction, String)> {
+    let mut decoder = MessageDecoder::new();
+    encoded
+        .lines()
+        .map(|line| {
+            let (direction, data) = decoder.decode_line(line).unwrap();
+            (direction, data.to_owned())
+        })
+        .collect()
+}
+
+#[test]
+fn block_crlf() {
+    let blocks: BTreeSet<Capability> = [Capability::Block].into_iter().collect();
+    let encoded = encode(&blocks, "motor\r\nm1\r\n");
+    assert_eq!(encoded, "<<<[lines=2]motor\nm1\n");
+    assert_eq!(
+        decode(&blocks, &encoded),
+        [(Direction::ServerToClient, "motor\nm1".to_owned())]
+    );
+    // CRLF line ends on the wire
+    assert_eq!(
+        decode(&blocks, "<<<[lines=2]motor\r\nm1\r\n<<<[]next\r\n"),
+        [
+            (Direction::ServerToClient, "motor\nm1".to_owned()),
+            (Direction::ServerToClient, "next".to_owned())
+        ]
+    );
+}
+
+#[test]
+fn block_prefixed_lines() {
+    let data = "diff\n>>>added\n  <<<removed";
+    let blocks: BTreeSet<Capability> = [Capability::Block].into_iter().collect();
+    let encoded = encode(&blocks, data);
+    assert_eq!(encoded, "<<<[lines=3]diff\n>>>added\n  <<<removed\n");
+    assert_eq!(
+        decode(&blocks, &encoded),
+        [(Direction::ServerToClient, data.to_owned())]
+    );
+}
+
+#[test]
+fn escaped_prefixed_lines() {
+    let none = BTreeSet::new();
+    let encoded = encode(&none, "diff\n>>>added\n  <<<removed\nplain");
+    assert_eq!(encoded, "<<<diff\n >>>added\n   <<<removed\nplain\n");
+    // clients without blocks get the continuation lines, not new messages
+    assert_eq!(
+        decode_lines(&encoded),
+        [
+            (Direction::ServerToClient, "diff".to_owned()),
+            (Direction::ServerToClient, ">>>added".to_owned()),
+            (Direction::ServerToClient, "  <<<removed".to_owned()),
+            (Direction::ServerToClient, "plain".to_owned()),
+        ]
+    );
+}
+
+#[test]
+fn multi_line_call_reply() {
+    let server = Server::new(TIMEOUT);
+    let data_channel = server.take_data_channel().unwrap();
+    let s = server.clone();
+    thread::spawn(move || {
+        for frame in data_channel {
+            s.reply(&frame, "motor  state\r\nm1     on\n>>>m2     off")
+                .unwrap();
+        }
+    });
+    let (client, rx) = server.connect_local().unwrap();
+    assert!(client.capabilities().contains(&Capability::CallId));
+    assert_eq!(
+        client.call("status", TIMEOUT).unwrap(),
+        "motor  state\nm1     on\n>>>m2     off"
+    );
+    // the reply is delivered to the caller only
+    assert_eq!(rx.recv().unwrap().1, "status");
+    server.send("after");
+    assert_eq!(rx.recv().unwrap().1, "after");
+}
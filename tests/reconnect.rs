@@ -0,0 +1,159 @@
+use std::{
+    io::{ErrorKind, Read, Write},
+    net::{SocketAddr, TcpListener},
+    sync::mpsc,
+    thread,
+    time::{Duration, Instant},
+};
+
+use rflow::{
+    ClientEvent, ConnectionOptions, Direction, ReconnectOptions, ReconnectingClient, Server,
+};
+
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Starts the server on the address, the returned channel receives the result of the serving
+/// method
+fn start(server: &Server, addr: SocketAddr) -> mpsc::Receiver<Result<(), rflow::Error>> {
+    let listener = TcpListener::bind(addr).unwrap();
+    let (tx, rx) = mpsc::channel();
+    let server = server.clone();
+    thread::spawn(move || tx.send(server.serve_with_listener(listener)).ok());
+    rx
+}
+
+#[test]
+fn reconnect_after_restart() {
+    let addr = TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap();
+    let options = ReconnectOptions::new()
+        .initial_delay(Duration::from_millis(50))
+        .on_connect("login");
+    let (client, rx) = ReconnectingClient::connect(addr, options).unwrap();
+    // returns the next connection state event, skipping the frames
+    let next_state = || loop {
+        let event = rx.recv().unwrap();
+        if !matches!(event, ClientEvent::Frame(..)) {
+            break event;
+        }
+    };
+    for round in 0..2 {
+        let server = Server::new(TIMEOUT);
+        let data_channel = server.take_data_channel().unwrap();
+        let finished = start(&server, addr);
+        assert_eq!(next_state(), ClientEvent::Connected);
+        // the on-connect lines are sent again after each reconnect
+        assert_eq!(data_channel.recv().unwrap().data(), "login");
+        assert!(client.is_connected());
+        let message = format!("round {}", round);
+        server.send(&message);
+        loop {
+            if rx.recv().unwrap() == ClientEvent::Frame(Direction::ServerToClient, message.clone())
+            {
+                break;
+            }
+        }
+        server.shutdown();
+        finished.recv_timeout(TIMEOUT).unwrap().unwrap();
+        assert_eq!(next_state(), ClientEvent::Disconnected);
+    }
+    assert!(!client.is_connected());
+}
+
+#[test]
+fn backoff() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let options = ReconnectOptions::new()
+        .initial_delay(Duration::from_millis(50))
+        .max_delay(Duration::from_millis(200));
+    let (_client, _rx) =
+        ReconnectingClient::connect(listener.local_addr().unwrap(), options).unwrap();
+    // the connections are closed without the greeting, so each attempt fails
+    let mut attempts = Vec::new();
+    for _ in 0..5 {
+        let (socket, _) = listener.accept().unwrap();
+        attempts.push(Instant::now());
+        drop(socket);
+    }
+    let delays: Vec<Duration> = attempts.windows(2).map(|w| w[1] - w[0]).collect();
+    for (delay, expected) in delays.iter().zip([50, 100, 200, 200]) {
+        assert!(*delay >= Duration::from_millis(expected), "{:?}", delays);
+    }
+    // the delay does not grow beyond the maximum
+    assert!(delays[3] < Duration::from_millis(400), "{:?}", delays);
+}
+
+#[test]
+fn drop_with_full_event_queue() {
+    let server = Server::new(TIMEOUT);
+    let addr = TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap();
+    let _finished = start(&server, addr);
+    let options = ReconnectOptions::new()
+        .connection_options(ConnectionOptions::new().incoming_queue_size(1))
+        .initial_delay(Duration::from_millis(50));
+    let (client, rx) = ReconnectingClient::connect(addr, options).unwrap();
+    while !client.is_connected() {
+        thread::sleep(Duration::from_millis(10));
+    }
+    for i in 0..10 {
+        server.send(i);
+    }
+    // the events are not read, the worker waits for the event queue
+    thread::sleep(Duration::from_millis(200));
+    drop(client);
+    thread::sleep(Duration::from_millis(300));
+    // the worker is stopped, only the already queued event is left
+    assert_eq!(rx.recv().unwrap(), ClientEvent::Connected);
+    assert!(rx.recv().is_err());
+    server.shutdown();
+}
+
+#[test]
+fn drop_while_connecting() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let (client, _rx) =
+        ReconnectingClient::connect(listener.local_addr().unwrap(), ReconnectOptions::new())
+            .unwrap();
+    let (mut socket, _) = listener.accept().unwrap();
+    // the worker is waiting for the greeting
+    thread::sleep(Duration::from_millis(100));
+    drop(client);
+    socket.write_all(b"RFLOW/1\n---\n").unwrap();
+    socket.set_read_timeout(Some(TIMEOUT)).unwrap();
+    let mut buf = [0; 64];
+    // the connection is closed by the client
+    match socket.read(&mut buf) {
+        Ok(n) => assert_eq!(n, 0),
+        Err(e) => assert!(
+            !matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut),
+            "{}",
+            e
+        ),
+    }
+}
+
+#[cfg(feature = "async")]
+#[tokio::test]
+async fn drop_while_connecting_async() {
+    use rflow::ReconnectingClientAsync;
+    use tokio::io::{AsyncReadExt as _, AsyncWriteExt as _};
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let (client, _rx) =
+        ReconnectingClientAsync::connect(listener.local_addr().unwrap(), ReconnectOptions::new())
+            .unwrap();
+    let (mut socket, _) = listener.accept().await.unwrap();
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    drop(client);
+    socket.write_all(b"RFLOW/1\n---\n").await.unwrap();
+    let mut buf = [0; 64];
+    let result = tokio::time::timeout(TIMEOUT, socket.read(&mut buf))
+        .await
+        .expect("the connection is not closed");
+    assert!(matches!(result, Ok(0) | Err(_)));
+}
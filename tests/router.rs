@@ -0,0 +1,99 @@
+use std::{thread, time::Duration};
+
+use rflow::{
+    router::{Command, Router},
+    Client, Direction, Server,
+};
+
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+fn start() -> (Client, impl Iterator<Item = (Direction, String)>) {
+    let server = Server::new(TIMEOUT);
+    let data_channel = server.take_data_channel().unwrap();
+    let router = Router::new(server.clone())
+        .command(
+            Command::new("start", |ctx| {
+                ctx.reply("started")?;
+                Ok(())
+            })
+            .help("start the motor"),
+        )
+        .command(
+            Command::new("speed", |ctx| {
+                let rpm: u32 = ctx.arg("rpm")?;
+                let ramp: Option<u32> = ctx.optional_arg("ramp")?;
+                ctx.reply(format!("rpm={} ramp={:?}", rpm, ramp))?;
+                Ok(())
+            })
+            .help("set the motor speed")
+            .arg("rpm", "speed in RPM")
+            .optional_arg("ramp", "ramp time"),
+        );
+    thread::spawn(move || router.run(data_channel));
+    server.connect_local().unwrap()
+}
+
+#[test]
+fn dispatch() {
+    let (client, _rx) = start();
+    assert_eq!(client.call("start", TIMEOUT).unwrap(), "started");
+    assert_eq!(
+        client.call("speed 100", TIMEOUT).unwrap(),
+        "rpm=100 ramp=None"
+    );
+    assert_eq!(
+        client.call("speed 100 5", TIMEOUT).unwrap(),
+        "rpm=100 ramp=Some(5)"
+    );
+}
+
+#[test]
+fn argument_errors() {
+    let (client, _rx) = start();
+    assert_eq!(
+        client.call("speed", TIMEOUT).unwrap(),
+        "error: missing argument: rpm, usage: speed <rpm> [ramp]"
+    );
+    assert_eq!(
+        client.call("speed 100 5 1", TIMEOUT).unwrap(),
+        "error: too many arguments, usage: speed <rpm> [ramp]"
+    );
+    assert_eq!(
+        client.call("speed fast", TIMEOUT).unwrap(),
+        "error: invalid value for rpm: fast, usage: speed <rpm> [ramp]"
+    );
+    assert_eq!(
+        client.call("start now", TIMEOUT).unwrap(),
+        "error: too many arguments, usage: start"
+    );
+    assert_eq!(
+        client.call("stop", TIMEOUT).unwrap(),
+        "error: unknown command: stop, type \"help\" for the list of commands"
+    );
+}
+
+#[test]
+fn help() {
+    let (client, _rx) = start();
+    assert_eq!(
+        client.call("help", TIMEOUT).unwrap(),
+        "commands: help [COMMAND]; speed <rpm> [ramp] - set the motor speed; \
+        start - start the motor"
+    );
+    assert_eq!(
+        client.call("help speed", TIMEOUT).unwrap(),
+        "speed <rpm> [ramp] - set the motor speed; rpm: speed in RPM; ramp: ramp time"
+    );
+    assert_eq!(
+        client.call("help stop", TIMEOUT).unwrap(),
+        "error: unknown command: stop"
+    );
+}
+
+#[test]
+#[should_panic(expected = "required argument rpm is added after an optional one")]
+fn required_after_optional() {
+    let _command = Command::new("speed", |_| Ok(()))
+        .optional_arg("ramp", "ramp time")
+        .arg("rpm", "speed in RPM");
+}
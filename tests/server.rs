@@ -0,0 +1,394 @@
+use std::{
+    io::{BufRead, BufReader, Write as _},
+    net::{SocketAddr, TcpListener, TcpStream},
+    sync::mpsc,
+    thread,
+    time::Duration,
+};
+
+use rflow::{
+    AuthMethod, Capability, Client, ClientInfo, ConnectionOptions, Credentials, Direction, Error,
+    Identity, IncomingPolicy, RateLimit, Role, Server, ServerEvent, Severity,
+};
+
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Starts the server on a random local port, the returned channel receives the result of the
+/// serving method
+fn start(server: &Server) -> (SocketAddr, mpsc::Receiver<Result<(), Error>>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let (tx, rx) = mpsc::channel();
+    let server = server.clone();
+    thread::spawn(move || tx.send(server.serve_with_listener(listener)).ok());
+    (addr, rx)
+}
+
+#[test]
+fn shutdown_disconnects_clients() {
+    let server = Server::new(TIMEOUT);
+    let (addr, finished) = start(&server);
+    let (client1, rx1) = Client::connect(addr).unwrap();
+    let (_client2, rx2) = Client::connect(addr).unwrap();
+    client1.try_send("hello").unwrap();
+    assert_eq!(rx1.recv().unwrap().1, "hello");
+    server.shutdown();
+    finished.recv_timeout(TIMEOUT).unwrap().unwrap();
+    assert_eq!(rx2.into_iter().last().unwrap().1, "server shutdown");
+    assert_eq!(rx1.into_iter().last().unwrap().1, "server shutdown");
+}
+
+#[cfg(feature = "websocket")]
+#[test]
+fn shutdown_during_handshake() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server = Server::new(TIMEOUT);
+    let (tx, finished) = mpsc::channel();
+    let s = server.clone();
+    thread::spawn(move || tx.send(s.serve_with_websocket_listener(listener)).ok());
+    let mut socket = TcpStream::connect(addr).unwrap();
+    socket.set_read_timeout(Some(TIMEOUT)).unwrap();
+    socket
+        .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\nUpgrade: websocket\r\n")
+        .unwrap();
+    thread::sleep(Duration::from_millis(100));
+    server.shutdown();
+    // the handshake is completed after the shutdown
+    socket
+        .write_all(
+            b"Connection: Upgrade\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+            Sec-WebSocket-Version: 13\r\n\r\n",
+        )
+        .unwrap();
+    finished.recv_timeout(TIMEOUT).unwrap().unwrap();
+}
+
+#[test]
+fn local_clients_are_limited() {
+    let server = Server::new(TIMEOUT);
+    server.set_max_clients(1).unwrap();
+    let (client, _rx) = server.connect_local().unwrap();
+    assert!(matches!(server.connect_local(), Err(Error::TooManyClients)));
+    drop(client);
+    for _ in 0..50 {
+        if server.clients().is_empty() {
+            break;
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    assert!(server.connect_local().is_ok());
+}
+
+#[test]
+fn reserved_headers() {
+    let server = Server::new(TIMEOUT);
+    for name in ["Auth", "Capabilities", "Extensions", "History", "keepalive"] {
+        assert!(matches!(
+            server.set_header(name, "1"),
+            Err(Error::InvalidData)
+        ));
+    }
+    for name in ["", "Name:", "Long Name"] {
+        assert!(server.set_header(name, "1").is_err());
+    }
+    server.set_header("Application", "test").unwrap();
+    let (client, _rx) = server.connect_local().unwrap();
+    assert_eq!(client.headers().get("Application").unwrap(), "test");
+    assert!(!client.headers().contains_key("Auth"));
+}
+
+fn history_server() -> Server {
+    let server = Server::new(TIMEOUT);
+    server.set_history_size(3).unwrap();
+    for data in ["one", "two\nlines", "three", "four"] {
+        server.send(data);
+    }
+    server
+}
+
+fn assert_history(
+    client: &Client,
+    rx: impl Iterator<Item = (Direction, String)>,
+    expected: &[&str],
+) {
+    assert_eq!(client.headers().get("History").unwrap(), "4");
+    assert_eq!(client.history_len(), expected.len());
+    let frames: Vec<String> = rx.take(expected.len() + 1).map(|(_, data)| data).collect();
+    assert_eq!(frames[..expected.len()], *expected);
+    assert_eq!(frames[expected.len()], "live");
+}
+
+#[test]
+fn history() {
+    let server = history_server();
+    let (client, rx) = server.connect_local().unwrap();
+    server.send("live");
+    // replayed before the capabilities are negotiated, so the lines are not reassembled
+    assert_history(&client, rx, &["two", "lines", "three", "four"]);
+}
+
+#[test]
+fn history_after_auth() {
+    let server = history_server();
+    server
+        .set_authenticator(AuthMethod::Token, |_| {
+            Some(Identity::new("operator", Role::Operator))
+        })
+        .unwrap();
+    let options = ConnectionOptions::new().credentials(Credentials::Token("secret".to_owned()));
+    let (client, rx) = server.connect_local_with_options(&options).unwrap();
+    server.send("live");
+    assert_history(&client, rx, &["two\nlines", "three", "four"]);
+}
+
+#[test]
+fn history_v1_client() {
+    let server = history_server();
+    let (addr, _finished) = start(&server);
+    let socket = TcpStream::connect(addr).unwrap();
+    socket.set_read_timeout(Some(TIMEOUT)).unwrap();
+    let mut lines = BufReader::new(socket).lines().map(Result::unwrap);
+    let greeting: Vec<String> = lines.by_ref().take_while(|l| l != "---").collect();
+    assert!(greeting.contains(&"History: 4".to_owned()));
+    let history: Vec<String> = lines.take(4).collect();
+    assert_eq!(history, ["<<<two", "lines", "<<<three", "<<<four"]);
+    server.shutdown();
+}
+
+#[test]
+fn call_ids_negotiated() {
+    let server = Server::new(TIMEOUT);
+    let data_channel = server.take_data_channel().unwrap();
+    let s = server.clone();
+    thread::spawn(move || {
+        for frame in data_channel {
+            s.reply(&frame, format!("{}:{:?}", frame.data(), frame.call_id()))
+                .unwrap();
+        }
+    });
+    let (addr, _finished) = start(&server);
+    // the client has not requested call ids, the line is processed as-is
+    let socket = TcpStream::connect(addr).unwrap();
+    socket.set_read_timeout(Some(TIMEOUT)).unwrap();
+    let mut writer = socket.try_clone().unwrap();
+    let mut lines = BufReader::new(socket).lines().map(Result::unwrap);
+    let greeting: Vec<String> = lines.by_ref().take_while(|l| l != "---").collect();
+    assert!(greeting.iter().any(|l| l.contains("call-id")));
+    assert!(!greeting.iter().any(|l| l.starts_with("Extensions")));
+    writer.write_all(b"@1:hello\n").unwrap();
+    // the echo is sent after the frame is accepted, so the reply may precede it
+    let mut received: Vec<String> = lines.by_ref().take(2).collect();
+    received.sort();
+    assert_eq!(received, ["<<<@1:hello:None", ">>>@1:hello"]);
+    let (client, _rx) = Client::connect(addr).unwrap();
+    assert!(client
+        .call("hello", TIMEOUT)
+        .unwrap()
+        .starts_with("hello:Some("));
+    server.shutdown();
+}
+
+#[test]
+fn v1_client() {
+    let server = Server::new(TIMEOUT);
+    let (addr, _finished) = start(&server);
+    // deployed clients accept protocol version 1 only and never send a hello
+    let socket = TcpStream::connect(addr).unwrap();
+    socket.set_read_timeout(Some(TIMEOUT)).unwrap();
+    let mut writer = socket.try_clone().unwrap();
+    let mut lines = BufReader::new(socket).lines().map(Result::unwrap);
+    assert_eq!(lines.next().unwrap(), "RFLOW/1");
+    let greeting: Vec<String> = lines.by_ref().take_while(|l| l != "---").collect();
+    assert!(greeting.iter().any(|l| l.starts_with("Capabilities: ")));
+    writer.write_all(b"hello\n").unwrap();
+    assert_eq!(lines.next().unwrap(), ">>>hello");
+    // no attributes and no blocks without the negotiated capabilities
+    server.send_with_severity(Severity::Warning, "two\nlines");
+    assert_eq!(lines.next().unwrap(), "<<<two");
+    assert_eq!(lines.next().unwrap(), "lines");
+    // continuation lines are not taken for new messages
+    server.send("diff\n>>>added");
+    assert_eq!(lines.next().unwrap(), "<<<diff");
+    assert_eq!(lines.next().unwrap(), " >>>added");
+    // the capabilities are still negotiated by the crate clients
+    let options = ConnectionOptions::new().capabilities([Capability::Severity]);
+    let (client, _rx) = Client::connect_with_options(addr, &options).unwrap();
+    assert!(client.capabilities().contains(&Capability::Severity));
+    assert!(client.capabilities().contains(&Capability::Block));
+    server.shutdown();
+}
+
+#[test]
+fn keepalive_under_broadcast() {
+    let server = Server::new(TIMEOUT);
+    let interval = Duration::from_millis(100);
+    server.set_keepalive(Some(interval)).unwrap();
+    let (addr, _finished) = start(&server);
+    // the client sends nothing but pongs, so it must be pinged while the server is busy
+    let (client, rx) = Client::connect(addr).unwrap();
+    thread::spawn(move || rx.into_iter().count());
+    for i in 0..100 {
+        server.send(i);
+        thread::sleep(interval / 10);
+    }
+    assert!(client.is_connected());
+    assert_eq!(server.clients().len(), 1);
+    server.shutdown();
+}
+
+#[test]
+fn rejected_messages_are_not_echoed() {
+    let server = Server::new(TIMEOUT);
+    server.set_incoming_queue_size(1).unwrap();
+    server.set_incoming_policy(IncomingPolicy::Reject).unwrap();
+    let data_channel = server.take_data_channel().unwrap();
+    let (sender, rx1) = server.connect_local().unwrap();
+    let (_observer, rx2) = server.connect_local().unwrap();
+    sender.try_send("accepted").unwrap();
+    sender.try_send("rejected").unwrap();
+    assert_eq!(
+        rx1.recv().unwrap(),
+        (Direction::ClientToServer, "accepted".to_owned())
+    );
+    assert_eq!(
+        rx1.recv().unwrap(),
+        (Direction::ServerToClient, "server busy".to_owned())
+    );
+    server.send("marker");
+    assert_eq!(rx2.recv().unwrap().1, "accepted");
+    assert_eq!(rx2.recv().unwrap().1, "marker");
+    assert_eq!(data_channel.recv().unwrap().data(), "accepted");
+    for policy in [IncomingPolicy::Reject, IncomingPolicy::Block] {
+        assert_not_echoed_if_closed(policy);
+    }
+}
+
+/// Messages are not echoed if the data channel is closed, the sender is disconnected
+fn assert_not_echoed_if_closed(policy: IncomingPolicy) {
+    let server = Server::new(TIMEOUT);
+    server.set_incoming_policy(policy).unwrap();
+    let _ = server.take_data_channel().unwrap();
+    let (sender, _rx1) = server.connect_local().unwrap();
+    let (_observer, rx2) = server.connect_local().unwrap();
+    sender.try_send("lost").unwrap();
+    let started = std::time::Instant::now();
+    while server.clients().len() > 1 {
+        assert!(
+            started.elapsed() < TIMEOUT,
+            "the sender is not disconnected"
+        );
+        thread::sleep(Duration::from_millis(10));
+    }
+    server.send("marker");
+    assert_eq!(rx2.recv().unwrap().1, "marker");
+}
+
+#[test]
+fn rate_limit() {
+    assert!(matches!(
+        RateLimit::new(0, Duration::from_secs(1)),
+        Err(Error::InvalidData)
+    ));
+    assert!(matches!(
+        RateLimit::new(1, Duration::ZERO),
+        Err(Error::InvalidData)
+    ));
+    let server = Server::new(TIMEOUT);
+    server.set_outgoing_queue_size(4).unwrap();
+    server
+        .set_rate_limit(Some(RateLimit::new(2, Duration::from_secs(60)).unwrap()))
+        .unwrap();
+    let events = server.take_event_channel().unwrap();
+    let (client, rx) = server.connect_local().unwrap();
+    for i in 0..50 {
+        client.try_send(i).unwrap();
+    }
+    // the sender is notified once per the limit period, not per dropped message
+    let frames: Vec<String> = (0..3).map(|_| rx.recv().unwrap().1).collect();
+    assert_eq!(frames, ["0", "1", "rate limit exceeded"]);
+    client.try_send("last").unwrap();
+    server.send("marker");
+    assert_eq!(rx.recv().unwrap().1, "marker");
+    while let Ok(event) = events.try_recv() {
+        assert!(!matches!(event, ServerEvent::QueueOverflow(_)));
+    }
+}
+
+#[test]
+fn call_id_like_broadcast() {
+    const DATA: &str = "@10:00 shift change";
+    let server = Server::new(TIMEOUT);
+    let (addr, _finished) = start(&server);
+    let socket = TcpStream::connect(addr).unwrap();
+    socket.set_read_timeout(Some(TIMEOUT)).unwrap();
+    let mut lines = BufReader::new(socket).lines().map(Result::unwrap);
+    while lines.next().unwrap() != "---" {}
+    let (client, rx) = Client::connect(addr).unwrap();
+    assert!(client.capabilities().contains(&Capability::CallId));
+    server.send(DATA);
+    // no call is pending, so the message is not taken for a reply
+    assert_eq!(lines.next().unwrap(), format!("<<<{}", DATA));
+    assert_eq!(rx.recv().unwrap().1, DATA);
+    server.shutdown();
+    // a server without call ids
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    thread::spawn(move || {
+        let (mut socket, _) = listener.accept().unwrap();
+        write!(socket, "RFLOW/1\n---\n<<<{}\n", DATA).unwrap();
+        thread::sleep(TIMEOUT);
+    });
+    let (client, rx) = Client::connect(addr).unwrap();
+    assert!(client.capabilities().is_empty());
+    assert_eq!(rx.recv().unwrap().1, DATA);
+}
+
+#[test]
+fn shutdown_before_serve() {
+    let server = Server::new(TIMEOUT);
+    server.shutdown();
+    let (addr, finished) = start(&server);
+    finished.recv_timeout(TIMEOUT).unwrap().unwrap();
+    assert!(Client::connect(addr).is_err());
+}
+
+#[test]
+fn targeted_messages() {
+    let server = Server::new(TIMEOUT);
+    server.set_history_size(10).unwrap();
+    let data_channel = server.take_data_channel().unwrap();
+    let s = server.clone();
+    thread::spawn(move || {
+        for frame in data_channel {
+            s.reply(&frame, "reply").unwrap();
+        }
+    });
+    let clients: Vec<_> = (0..3).map(|_| server.connect_local().unwrap()).collect();
+    let ids: Vec<usize> = server.clients().iter().map(ClientInfo::client_id).collect();
+    assert_eq!(ids.len(), 3);
+    server.send_to(ids[1], "to second").unwrap();
+    server.send_except(ids[1], "except second");
+    assert!(matches!(
+        server.send_to(usize::MAX, "nobody"),
+        Err(Error::ClientNotFound(usize::MAX))
+    ));
+    // the request is echoed to all clients, the reply is delivered to the sender only
+    clients[0].0.try_send("request").unwrap();
+    let mut received: Vec<String> = (0..3).map(|_| clients[0].1.recv().unwrap().1).collect();
+    received.sort();
+    assert_eq!(received, ["except second", "reply", "request"]);
+    assert_eq!(clients[1].1.recv().unwrap().1, "to second");
+    assert_eq!(clients[1].1.recv().unwrap().1, "request");
+    assert_eq!(clients[2].1.recv().unwrap().1, "except second");
+    assert_eq!(clients[2].1.recv().unwrap().1, "request");
+    server.send("marker");
+    for (_, rx) in &clients {
+        assert_eq!(rx.recv().unwrap().1, "marker");
+    }
+    // targeted messages are not recorded in the history
+    let (client, rx) = server.connect_local().unwrap();
+    assert_eq!(client.history_len(), 2);
+    assert_eq!(rx.recv().unwrap().1, "request");
+    assert_eq!(rx.recv().unwrap().1, "marker");
+}
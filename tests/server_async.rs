@@ -0,0 +1,88 @@
+#![cfg(feature = "async")]
+
+use std::time::Duration;
+
+use rflow::{
+    AuthMethod, ClientAsync, ClientInfo, ConnectionOptions, Credentials, Error, Identity, Role,
+    ServerAsync,
+};
+use tokio::net::TcpListener;
+
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+#[tokio::test]
+async fn history_after_auth() {
+    let server = ServerAsync::new(TIMEOUT);
+    server.set_history_size(3).unwrap();
+    for data in ["one", "two\nlines", "three", "four"] {
+        server.send(data);
+    }
+    server
+        .set_authenticator(AuthMethod::Token, |_| {
+            Some(Identity::new("operator", Role::Operator))
+        })
+        .unwrap();
+    let options = ConnectionOptions::new().credentials(Credentials::Token("secret".to_owned()));
+    let (client, rx) = server.connect_local_with_options(&options).await.unwrap();
+    server.send("live");
+    assert_eq!(client.headers().get("History").unwrap(), "4");
+    assert_eq!(client.history_len(), 3);
+    for expected in ["two\nlines", "three", "four", "live"] {
+        assert_eq!(rx.recv().await.unwrap().1, expected);
+    }
+}
+
+#[tokio::test]
+async fn keepalive_under_broadcast() {
+    let server = ServerAsync::new(TIMEOUT);
+    let interval = Duration::from_millis(100);
+    server.set_keepalive(Some(interval)).unwrap();
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let s = server.clone();
+    tokio::spawn(async move { s.serve_with_listener(listener).await });
+    // the client sends nothing but pongs, so it must be pinged while the server is busy
+    let (client, rx) = ClientAsync::connect(addr).await.unwrap();
+    tokio::spawn(async move { while rx.recv().await.is_ok() {} });
+    for i in 0..100 {
+        server.send(i);
+        tokio::time::sleep(interval / 10).await;
+    }
+    assert!(client.is_connected());
+    assert_eq!(server.clients().len(), 1);
+    server.shutdown();
+}
+
+#[tokio::test]
+async fn shutdown_before_serve() {
+    let server = ServerAsync::new(TIMEOUT);
+    server.shutdown();
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    tokio::time::timeout(TIMEOUT, server.serve_with_listener(listener))
+        .await
+        .unwrap()
+        .unwrap();
+}
+
+#[tokio::test]
+async fn targeted_messages() {
+    let server = ServerAsync::new(TIMEOUT);
+    let mut clients = Vec::new();
+    for _ in 0..3 {
+        clients.push(server.connect_local().await.unwrap());
+    }
+    let ids: Vec<usize> = server.clients().iter().map(ClientInfo::client_id).collect();
+    assert_eq!(ids.len(), 3);
+    server.send_to(ids[1], "to second").unwrap();
+    server.send_except(ids[1], "except second");
+    assert!(matches!(
+        server.send_to(usize::MAX, "nobody"),
+        Err(Error::ClientNotFound(usize::MAX))
+    ));
+    server.send("marker");
+    for (i, (_, rx)) in clients.iter().enumerate() {
+        let expected = if i == 1 { "to second" } else { "except second" };
+        assert_eq!(rx.recv().await.unwrap().1, expected);
+        assert_eq!(rx.recv().await.unwrap().1, "marker");
+    }
+}
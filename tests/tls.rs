@@ -0,0 +1,191 @@
+#![cfg(feature = "tls")]
+
+use std::{net::TcpListener, sync::Arc, thread, time::Duration};
+
+use rflow::{tls::rustls, Client, ConnectionOptions, Server, ServerEvent};
+
+mod common;
+
+use common::Pki;
+
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+fn start(config: Arc<rustls::ServerConfig>) -> (Server, String) {
+    let server = Server::new(TIMEOUT);
+    server.set_tls_config(config).unwrap();
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    let s = server.clone();
+    thread::spawn(move || s.serve_with_listener(listener));
+    (server, addr)
+}
+
+fn assert_echo(addr: &str, options: &ConnectionOptions) {
+    let (client, rx) = Client::connect_with_options(addr, options).unwrap();
+    client.try_send("hello").unwrap();
+    assert_eq!(rx.recv().unwrap().1, "hello");
+}
+
+fn assert_refused(addr: &str, options: &ConnectionOptions) {
+    assert!(Client::connect_with_options(addr, options).is_err());
+}
+
+#[test]
+fn connect() {
+    let pki = Pki::generate("connect");
+    let (server, addr) = start(pki.server_config());
+    assert_echo(&addr, &pki.client_options("ca", "localhost"));
+    server.shutdown();
+}
+
+#[test]
+fn untrusted_certificate() {
+    let pki = Pki::generate("untrusted");
+    let (server, addr) = start(pki.server_config());
+    assert_refused(&addr, &pki.client_options("other", "localhost"));
+    // the server keeps serving
+    assert_echo(&addr, &pki.client_options("ca", "localhost"));
+    server.shutdown();
+}
+
+#[test]
+fn name_mismatch() {
+    let pki = Pki::generate("mismatch");
+    let (server, addr) = start(pki.server_config());
+    assert_refused(&addr, &pki.client_options("ca", "example.com"));
+    server.shutdown();
+}
+
+#[test]
+fn client_certificate() {
+    let pki = Pki::generate("client-auth");
+    let (server, addr) = start(pki.server_config_with_client_auth());
+    assert_refused(&addr, &pki.client_options("ca", "localhost"));
+    assert_echo(&addr, &pki.client_options_with_auth());
+    server.shutdown();
+}
+
+#[test]
+fn events() {
+    let pki = Pki::generate("events");
+    let server = Server::new(TIMEOUT);
+    let events = server.take_event_channel().unwrap();
+    server.set_tls_config(pki.server_config()).unwrap();
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    let s = server.clone();
+    thread::spawn(move || s.serve_with_listener(listener));
+    // failed handshakes are reported as rejected connections, not as clients
+    assert_refused(&addr, &pki.client_options("other", "localhost"));
+    assert!(matches!(events.recv().unwrap(), ServerEvent::Rejected(..)));
+    let (client, _rx) =
+        Client::connect_with_options(&addr, &pki.client_options("ca", "localhost")).unwrap();
+    let ServerEvent::Connected(client_id, _) = events.recv().unwrap() else {
+        panic!("client connection is not reported");
+    };
+    drop(client);
+    assert!(matches!(
+        events.recv().unwrap(),
+        ServerEvent::Disconnected(id, _) if id == client_id
+    ));
+    server.shutdown();
+}
+
+#[cfg(feature = "async")]
+mod asynchronous {
+    use std::sync::Arc;
+
+    use rflow::{tls::rustls, ClientAsync, ConnectionOptions, ServerAsync, ServerEvent};
+    use tokio::net::TcpListener;
+
+    use super::{Pki, TIMEOUT};
+
+    async fn start(config: Arc<rustls::ServerConfig>) -> (ServerAsync, String) {
+        let server = ServerAsync::new(TIMEOUT);
+        server.set_tls_config(config).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let s = server.clone();
+        tokio::spawn(async move { s.serve_with_listener(listener).await });
+        (server, addr)
+    }
+
+    async fn assert_echo(addr: &str, options: &ConnectionOptions) {
+        let (client, rx) = ClientAsync::connect_with_options(addr, options)
+            .await
+            .unwrap();
+        client.try_send("hello").await.unwrap();
+        assert_eq!(rx.recv().await.unwrap().1, "hello");
+    }
+
+    async fn assert_refused(addr: &str, options: &ConnectionOptions) {
+        assert!(ClientAsync::connect_with_options(addr, options)
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn connect() {
+        let pki = Pki::generate("async-connect");
+        let (server, addr) = start(pki.server_config()).await;
+        assert_echo(&addr, &pki.client_options("ca", "localhost")).await;
+        server.shutdown();
+    }
+
+    #[tokio::test]
+    async fn untrusted_certificate() {
+        let pki = Pki::generate("async-untrusted");
+        let (server, addr) = start(pki.server_config()).await;
+        assert_refused(&addr, &pki.client_options("other", "localhost")).await;
+        assert_echo(&addr, &pki.client_options("ca", "localhost")).await;
+        server.shutdown();
+    }
+
+    #[tokio::test]
+    async fn name_mismatch() {
+        let pki = Pki::generate("async-mismatch");
+        let (server, addr) = start(pki.server_config()).await;
+        assert_refused(&addr, &pki.client_options("ca", "example.com")).await;
+        server.shutdown();
+    }
+
+    #[tokio::test]
+    async fn client_certificate() {
+        let pki = Pki::generate("async-client-auth");
+        let (server, addr) = start(pki.server_config_with_client_auth()).await;
+        assert_refused(&addr, &pki.client_options("ca", "localhost")).await;
+        assert_echo(&addr, &pki.client_options_with_auth()).await;
+        server.shutdown();
+    }
+
+    #[tokio::test]
+    async fn events() {
+        let pki = Pki::generate("async-events");
+        let server = ServerAsync::new(TIMEOUT);
+        let events = server.take_event_channel().unwrap();
+        server.set_tls_config(pki.server_config()).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let s = server.clone();
+        tokio::spawn(async move { s.serve_with_listener(listener).await });
+        // failed handshakes are reported as rejected connections, not as clients
+        assert_refused(&addr, &pki.client_options("other", "localhost")).await;
+        assert!(matches!(
+            events.recv().await.unwrap(),
+            ServerEvent::Rejected(..)
+        ));
+        let (client, _rx) =
+            ClientAsync::connect_with_options(&addr, &pki.client_options("ca", "localhost"))
+                .await
+                .unwrap();
+        let ServerEvent::Connected(client_id, _) = events.recv().await.unwrap() else {
+            panic!("client connection is not reported");
+        };
+        drop(client);
+        assert!(matches!(
+            events.recv().await.unwrap(),
+            ServerEvent::Disconnected(id, _) if id == client_id
+        ));
+        server.shutdown();
+    }
+}
@@ -0,0 +1,95 @@
+#![cfg(unix)]
+
+use std::{
+    fs,
+    os::unix::{fs::PermissionsExt as _, net::UnixStream},
+    path::PathBuf,
+    sync::mpsc,
+    thread,
+    time::Duration,
+};
+
+use rflow::{Client, ConnectionOptions, Error, Server};
+
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+fn socket_dir(test: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("rflow-unix-{}-{}", std::process::id(), test));
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+#[test]
+fn socket_permissions() {
+    let dir = socket_dir("permissions");
+    let path = dir.join("rflow.sock");
+    let server = Server::new(TIMEOUT);
+    server.set_unix_socket_permissions(0o600).unwrap();
+    let (tx, finished) = mpsc::channel();
+    let s = server.clone();
+    let p = path.clone();
+    thread::spawn(move || tx.send(s.serve_unix(p)).ok());
+    for _ in 0..50 {
+        if path.exists() {
+            break;
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    let mode = fs::metadata(&path).unwrap().permissions().mode();
+    assert_eq!(mode & 0o777, 0o600);
+    // the private directory the socket has been bound in is removed
+    assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+    let (client, rx) = Client::connect_unix(&path).unwrap();
+    client.try_send("hello").unwrap();
+    assert_eq!(rx.recv().unwrap().1, "hello");
+    server.shutdown();
+    finished.recv_timeout(TIMEOUT).unwrap().unwrap();
+    assert!(!path.exists());
+    fs::remove_dir_all(&dir).ok();
+}
+
+/// Serves a stream client over a socket pair, the returned channel receives the result of the
+/// serving method
+fn serve_stream(server: &Server) -> (UnixStream, mpsc::Receiver<Result<(), Error>>) {
+    let (server_socket, client_socket) = UnixStream::pair().unwrap();
+    let (tx, rx) = mpsc::channel();
+    let server = server.clone();
+    thread::spawn(move || {
+        let writer = server_socket.try_clone().unwrap();
+        tx.send(server.serve_stream(server_socket, writer)).ok()
+    });
+    (client_socket, rx)
+}
+
+#[test]
+fn stream_client() {
+    let server = Server::new(TIMEOUT);
+    let data_channel = server.take_data_channel().unwrap();
+    let s = server.clone();
+    thread::spawn(move || {
+        for frame in data_channel {
+            s.reply(&frame, frame.data().to_uppercase()).ok();
+        }
+    });
+    let (socket, finished) = serve_stream(&server);
+    let (client, rx) = Client::connect_transport(socket, &ConnectionOptions::new()).unwrap();
+    assert_eq!(client.call("hello", TIMEOUT).unwrap(), "HELLO");
+    assert_eq!(rx.recv().unwrap().1, "hello");
+    server.shutdown();
+    assert_eq!(rx.recv().unwrap().1, "server shutdown");
+    // a blocked read of a generic stream is interrupted by the next incoming data only
+    client.try_send("bye").ok();
+    finished.recv_timeout(TIMEOUT).unwrap().unwrap();
+}
+
+#[test]
+fn stream_client_disconnected() {
+    let server = Server::new(TIMEOUT);
+    let (socket, finished) = serve_stream(&server);
+    let (client, rx) = Client::connect_transport(socket, &ConnectionOptions::new()).unwrap();
+    server.send("hello");
+    assert_eq!(rx.recv().unwrap().1, "hello");
+    drop(client);
+    finished.recv_timeout(TIMEOUT).unwrap().unwrap();
+    assert!(server.clients().is_empty());
+}
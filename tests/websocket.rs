@@ -0,0 +1,167 @@
+#![cfg(feature = "websocket")]
+
+use std::{
+    io::{BufRead as _, BufReader, Write as _},
+    net::{SocketAddr, TcpListener, TcpStream},
+    thread,
+    time::Duration,
+};
+
+use rflow::Server;
+use tungstenite::{client::IntoClientRequest as _, Message, WebSocket};
+
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+fn start(server: &Server) -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server = server.clone();
+    thread::spawn(move || server.serve_with_websocket_listener(listener));
+    addr
+}
+
+/// Returns the HTTP status code if the upgrade is refused
+fn connect(addr: SocketAddr, origin: Option<&str>) -> Result<WebSocket<TcpStream>, u16> {
+    let mut request = format!("ws://{}/", addr).into_client_request().unwrap();
+    if let Some(origin) = origin {
+        request
+            .headers_mut()
+            .insert("Origin", origin.parse().unwrap());
+    }
+    let socket = TcpStream::connect(addr).unwrap();
+    socket.set_read_timeout(Some(TIMEOUT)).unwrap();
+    match tungstenite::client(request, socket) {
+        Ok((ws, _)) => Ok(ws),
+        Err(tungstenite::HandshakeError::Failure(tungstenite::Error::Http(response))) => {
+            Err(response.status().as_u16())
+        }
+        Err(e) => panic!("handshake failed: {}", e),
+    }
+}
+
+/// Reads the next text frame, skipping control frames
+fn read_text(ws: &mut WebSocket<TcpStream>) -> String {
+    loop {
+        if let Message::Text(text) = ws.read().unwrap() {
+            return text;
+        }
+    }
+}
+
+fn skip_greeting(ws: &mut WebSocket<TcpStream>) {
+    while read_text(ws) != "---" {}
+}
+
+#[test]
+fn origin() {
+    let server = Server::new(TIMEOUT);
+    let addr = start(&server);
+    // non-browser clients send no origin
+    assert!(connect(addr, None).is_ok());
+    // pages, served by the server itself
+    assert!(connect(addr, Some(&format!("http://{}", addr))).is_ok());
+    assert_eq!(connect(addr, Some("https://example.com")).err(), Some(403));
+    server
+        .set_websocket_origins(["https://example.com"])
+        .unwrap();
+    assert!(connect(addr, Some("https://example.com")).is_ok());
+    assert_eq!(connect(addr, Some("https://example.org")).err(), Some(403));
+    server.shutdown();
+}
+
+#[test]
+fn origin_of_raw_request() {
+    let server = Server::new(TIMEOUT);
+    let addr = start(&server);
+    let mut socket = TcpStream::connect(addr).unwrap();
+    socket.set_read_timeout(Some(TIMEOUT)).unwrap();
+    write!(
+        socket,
+        "GET / HTTP/1.1\r\nHost: {}\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+        Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\n\
+        Origin: null\r\n\r\n",
+        addr
+    )
+    .unwrap();
+    let mut status = String::new();
+    BufReader::new(socket).read_line(&mut status).unwrap();
+    assert!(status.starts_with("HTTP/1.1 403"));
+    server.shutdown();
+}
+
+#[test]
+fn read_while_writing() {
+    let server = Server::new(TIMEOUT);
+    let addr = start(&server);
+    let mut ws = connect(addr, None).unwrap();
+    skip_greeting(&mut ws);
+    // the server connection reader waits for data, the writer must not be blocked by it
+    server.send("from server");
+    assert_eq!(read_text(&mut ws), "<<<from server");
+    ws.send(Message::Text("hello".to_owned())).unwrap();
+    assert_eq!(read_text(&mut ws), ">>>hello");
+    // pings are replied by the shared connection
+    ws.send(Message::Ping(b"ping".to_vec())).unwrap();
+    loop {
+        match ws.read().unwrap() {
+            Message::Pong(data) => {
+                assert_eq!(data, b"ping");
+                break;
+            }
+            Message::Text(_) => {}
+            message => panic!("unexpected message: {:?}", message),
+        }
+    }
+    for i in 0..100 {
+        server.send(i);
+        ws.send(Message::Text(format!("client {}", i))).unwrap();
+    }
+    let mut received = 0;
+    while received < 200 {
+        let text = read_text(&mut ws);
+        assert!(text.starts_with("<<<") || text.starts_with(">>>client "));
+        received += 1;
+    }
+    server.shutdown();
+    assert!(matches!(
+        ws.read(),
+        Ok(Message::Text(_) | Message::Close(_))
+    ));
+}
+
+#[test]
+fn write_while_reading() {
+    let server = Server::new(TIMEOUT);
+    server.set_outgoing_queue_size(10_000).unwrap();
+    let addr = start(&server);
+    let mut ws = connect(addr, None).unwrap();
+    skip_greeting(&mut ws);
+    // the server fills the socket buffer while the connection reader processes the client
+    // messages, the writer must keep blocking until the client reads instead of failing
+    let large = "x".repeat(16384);
+    let sender = {
+        let server = server.clone();
+        let large = large.clone();
+        thread::spawn(move || {
+            for _ in 0..200 {
+                server.send(&large);
+            }
+        })
+    };
+    for i in 0..100 {
+        ws.send(Message::Text(format!("client {}", i))).unwrap();
+    }
+    sender.join().unwrap();
+    let (mut sent, mut echoed) = (0, 0);
+    while sent < 200 || echoed < 100 {
+        let text = read_text(&mut ws);
+        if text.starts_with("<<<") {
+            assert_eq!(text.len(), large.len() + 3);
+            sent += 1;
+        } else {
+            assert!(text.starts_with(">>>client "));
+            echoed += 1;
+        }
+    }
+    server.shutdown();
+}